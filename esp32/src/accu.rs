@@ -65,6 +65,9 @@ impl<'a, A: ADCPin> Accu<'a, A> {
     pub fn get_critical_volt(&self) -> f32 {
         self.critical_min_volt
     }
+    pub fn set_critical_volt(&mut self, min_volt: f32) {
+        self.critical_min_volt = min_volt;
+    }
     pub fn measure_volt(&mut self) -> f32 {
         let mut adc =
             AdcDriver::new(self.adc1.reborrow(), &Config::new().calibration(true)).unwrap();
@@ -9,29 +9,44 @@ use esp_idf_svc::{
     nvs::{EspDefaultNvsPartition, EspNvsPartition, NvsDefault},
     sys::link_patches,
 };
-use std::{thread::sleep, time::Duration};
+use protocol::{WakeFailure, WateringOutcome, WateringReport, WateringStatus};
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use crate::{
     accu::{single_nimh_cell_volt_to_percent, Accu},
     pumps::{PumpError, Pumps},
-    query::fetch_jobs,
+    query::{fetch_jobs, report_outcomes},
+    settings::Settings,
     status_signaler::StatusSignaler,
+    wake_log::WakeLog,
     wifi_connect::connect_to_wifi_with_timeout,
 };
 
 mod accu;
 mod deepsleep;
+mod ota;
 mod pumps;
 mod query;
+mod settings;
 mod status_signaler;
+mod wake_log;
 mod wifi_connect;
 
+// All durations, voltages and pump parameters below are defaults.
+// The server can override them, see settings.rs.
+
 // Binary on three LEDs
 const SIGNAL_WHILE_WIFI: u8 = 1;
 const SIGNAL_WHILE_FETCH: u8 = 2;
 const SIGNAL_WHILE_WATERING: u8 = 4;
 const ERROR_SLEEP_DURATION: Duration = Duration::from_secs(7200);
 const ERROR_SHOW_RED_LED_DURATION: Duration = Duration::from_secs(15);
+const SUCCESS_SHOW_GREEN_LED_DURATION: Duration = Duration::from_secs(7);
+// Waking up from deep sleep boots a freshly installed firmware.
+const FIRMWARE_UPDATE_SLEEP_DURATION: Duration = Duration::from_secs(1);
 
 const ACCU_NIMH_CELLS_IN_ROW: usize = 8;
 const ACCU_VOLTAGE_R1: f32 = 98.3; // kOhm
@@ -59,6 +74,24 @@ enum RoutineError {
     Pump(PumpError),
 }
 
+impl RoutineError {
+    /// Failures before the server was reached, reported on the next wake.
+    fn wake_failure(&self) -> Option<WakeFailure> {
+        match self {
+            RoutineError::Watchdog => Some(WakeFailure::Watchdog),
+            RoutineError::Wifi(wifi_connect::WifiErr::TimeoutConnect) => {
+                Some(WakeFailure::WifiConnectTimeout)
+            }
+            RoutineError::Wifi(wifi_connect::WifiErr::TimeoutIp) => Some(WakeFailure::IpTimeout),
+            RoutineError::Query(query::QueryError::Connection) => Some(WakeFailure::Connection),
+            RoutineError::Query(query::QueryError::UnexpectedResponse) => {
+                Some(WakeFailure::UnexpectedResponse)
+            }
+            RoutineError::Pump(_) => None,
+        }
+    }
+}
+
 impl From<wifi_connect::WifiErr> for RoutineError {
     fn from(err: wifi_connect::WifiErr) -> Self {
         RoutineError::Wifi(err)
@@ -81,6 +114,8 @@ fn routine(
     peripherals: Peripherals,
     sys_loop: EspEventLoop<System>,
     nvs: EspNvsPartition<NvsDefault>,
+    settings: &mut Settings,
+    wake_log: &WakeLog,
 ) -> Result<Duration, RoutineError> {
     // WATCHDOG
     // The code restarted automatically after a few seconds because of a watchdog.
@@ -117,7 +152,7 @@ fn routine(
         peripherals.adc1.into_ref(),
         accu_measure,
         ACCU_VOLTAGE_FACTOR,
-        ACCU_CRITICAL_VOLTAGE,
+        settings.accu_critical_voltage,
     );
     let accu_volt = accu.measure_volt();
     println!("Accu est: {}V", accu_volt);
@@ -130,42 +165,100 @@ fn routine(
         peripherals.ledc.channel0.into_ref(),
         vec![pump1.into_ref(), pump2.into_ref()],
         accu,
+        *settings,
     );
 
     led_signaler.set_green_number(SIGNAL_WHILE_WIFI);
-    let jobs = {
-        println!("Connect to Wifi");
-        let _wifi =
-            connect_to_wifi_with_timeout(Duration::from_secs(10), peripherals.modem, sys_loop, nvs)
-                .map_err(RoutineError::from)?;
-        led_signaler.set_green_number(SIGNAL_WHILE_FETCH);
-        println!("Fetching ESP todos...");
-        fetch_jobs(accu_percent)?
+    println!("Connect to Wifi");
+    let mut wifi = connect_to_wifi_with_timeout(
+        Duration::from_secs(10),
+        peripherals.modem,
+        sys_loop,
+        nvs.clone(),
+    )
+    .map_err(RoutineError::from)?;
+    led_signaler.set_green_number(SIGNAL_WHILE_FETCH);
+    println!("Fetching ESP todos...");
+    let (jobs, round_trip) = fetch_jobs(accu_percent, &wifi.diagnostics, wake_log)?;
+    WakeLog::checked_in(round_trip.as_millis() as u32).store(nvs.clone());
+    // The server was reached, so this firmware is good enough to keep.
+    ota::mark_running_firmware_valid();
+    let firmware_updated = match jobs.firmware_update.as_ref().map(ota::install_update) {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            println!("Firmware update failed: {:?}", e);
+            false
+        }
+        None => false,
     };
 
+    settings.apply(jobs.device_settings.as_ref(), nvs);
+    pumps.apply_settings(*settings);
+    // The radio would draw from the accu while pumping, it is back on to report
+    wifi.stop();
+
     led_signaler.set_green_number(SIGNAL_WHILE_WATERING);
+    let mut outcomes = Vec::new();
+    let mut accu_critical = false;
     for job in jobs.watering_jobs.iter() {
         if job.amount_ml == 0 {
             continue;
         }
-        match pumps.pump(job.plant_index, job.amount_ml as u32) {
-            Some(Err(PumpError::AccuCriticalVoltage)) => {
-                println!("Warning! Accu below critical voltage.");
-                led_signaler.error_led_on();
-                sleep(ERROR_SHOW_RED_LED_DURATION);
-                return Ok(Duration::from_secs(jobs.sleep_recommendation_seconds));
-            }
-            Some(Ok(())) => {}
-            None => println!("Warning. No pump connected to {}", job.plant_index),
-        }
+        let start = Instant::now();
+        let (status, watered_ml) = match accu_critical {
+            true => (WateringStatus::Skipped, 0.),
+            false => match pumps.pump(job.plant_index, job.amount_ml) {
+                Some(Err(PumpError::AccuCriticalVoltage(watered_ml))) => {
+                    println!("Warning! Accu below critical voltage.");
+                    accu_critical = true;
+                    (WateringStatus::AccuCriticalVoltage, watered_ml)
+                }
+                Some(Ok(watered_ml)) => (WateringStatus::Done, watered_ml),
+                None => {
+                    println!("Warning. No pump connected to {}", job.plant_index);
+                    (WateringStatus::NoPumpConnected, 0.)
+                }
+            },
+        };
+        outcomes.push(WateringOutcome {
+            plant_index: job.plant_index,
+            requested_ml: job.amount_ml,
+            watered_ml: watered_ml as u32,
+            duration_ms: start.elapsed().as_millis() as u32,
+            status,
+        });
     }
 
     // Call destructor to zero all pins, just to be sure
     drop(pumps);
 
+    if !outcomes.is_empty() {
+        led_signaler.set_green_number(SIGNAL_WHILE_WIFI);
+        match wifi.reconnect(Duration::from_secs(10)) {
+            Ok(()) => {
+                if let Err(e) = report_outcomes(&WateringReport { outcomes }) {
+                    println!("Could not report outcomes: {:?}", e);
+                }
+            }
+            Err(e) => println!("Could not reconnect to report outcomes: {:?}", e),
+        }
+    }
+    drop(wifi);
+
+    // Booting an installed update doesn't wait for a better accu
+    let sleep_duration = match firmware_updated {
+        true => FIRMWARE_UPDATE_SLEEP_DURATION,
+        false => Duration::from_secs(jobs.sleep_recommendation_seconds),
+    };
+    if accu_critical {
+        led_signaler.error_led_on();
+        sleep(settings.error_show_red_led_duration);
+        return Ok(sleep_duration);
+    }
+
     led_signaler.set_full_green();
-    sleep(Duration::from_secs(7));
-    Ok(Duration::from_secs(jobs.sleep_recommendation_seconds))
+    sleep(settings.success_show_green_led_duration);
+    Ok(sleep_duration)
 }
 
 fn main() {
@@ -180,13 +273,20 @@ fn main() {
     let sys_loop = EspSystemEventLoop::take().expect("Failed to take event loop");
     let nvs = EspDefaultNvsPartition::take().expect("Failed to take NVS partition");
 
-    let deepsleep_duration = match routine(peripherals, sys_loop, nvs) {
-        Ok(dur) => dur,
-        Err(e) => {
-            log::error!("Routine failed: {:?}", e);
-            ERROR_SLEEP_DURATION
-        }
-    };
+    let mut settings = Settings::load(nvs.clone());
+    let wake_log = WakeLog::load(nvs.clone());
+
+    let deepsleep_duration =
+        match routine(peripherals, sys_loop, nvs.clone(), &mut settings, &wake_log) {
+            Ok(dur) => dur,
+            Err(e) => {
+                log::error!("Routine failed: {:?}", e);
+                if let Some(failure) = e.wake_failure() {
+                    wake_log.failed(failure).store(nvs);
+                }
+                settings.error_sleep_duration
+            }
+        };
 
     println!("Sleep now for {:?}", deepsleep_duration);
     deepsleep::deep_sleep(deepsleep_duration);
@@ -0,0 +1,102 @@
+use embedded_svc::http::client::*;
+use embedded_svc::io::{Read, Write};
+use esp_idf_svc::http::client::*;
+use esp_idf_svc::ota::EspOta;
+use protocol::FirmwareUpdate;
+use sha2::{Digest, Sha256};
+
+use crate::query::{API_SECRET, BASE_URL};
+
+// Flash is written in chunks of this size
+const CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum OtaError {
+    Connection,       // HTTP, TLS
+    UnexpectedStatus, // 401, 404, ...
+    SizeMismatch,
+    ChecksumMismatch,
+    Flash, // Writing the update partition failed
+}
+
+/// The firmware running right now, reported to the server.
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Tell the bootloader the running firmware works,
+/// so it does not roll back on the next boot.
+/// Only call this after the server has been reached.
+pub fn mark_running_firmware_valid() {
+    match EspOta::new() {
+        Ok(mut ota) => {
+            if let Err(e) = ota.mark_running_slot_valid() {
+                println!("Could not mark firmware as valid: {e:?}");
+            }
+        }
+        Err(e) => println!("Could not access OTA partitions: {e:?}"),
+    }
+}
+
+/// Download the advertised firmware into the inactive app partition.
+/// It is booted after the next reset or deep sleep.
+pub fn install_update(update: &FirmwareUpdate) -> Result<(), OtaError> {
+    println!(
+        "Installing firmware {} ({} bytes), running {}",
+        update.version, update.size, FIRMWARE_VERSION
+    );
+    let mut client = Client::wrap(
+        EspHttpConnection::new(&Configuration {
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })
+        .map_err(|_| OtaError::Connection)?,
+    );
+    let url = format!("{}{}?api_secret={}", BASE_URL, update.path, API_SECRET);
+    let request = client.get(&url).map_err(|_| OtaError::Connection)?;
+    let mut response = request.submit().map_err(|_| OtaError::Connection)?;
+    if response.status() != 200 {
+        println!("Firmware download failed with status {}", response.status());
+        return Err(OtaError::UnexpectedStatus);
+    }
+
+    let mut ota = EspOta::new().map_err(|_| OtaError::Flash)?;
+    let mut ota_update = ota.initiate_update().map_err(|_| OtaError::Flash)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0_u8; CHUNK_SIZE];
+    let mut written: u64 = 0;
+    loop {
+        let read = response
+            .read(&mut buffer)
+            .map_err(|_| OtaError::Connection)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        if ota_update.write_all(&buffer[..read]).is_err() {
+            let _ = ota_update.abort();
+            return Err(OtaError::Flash);
+        }
+        written += read as u64;
+    }
+
+    if written != update.size {
+        println!("Expected {} bytes, got {}", update.size, written);
+        let _ = ota_update.abort();
+        return Err(OtaError::SizeMismatch);
+    }
+    let checksum: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if !checksum.eq_ignore_ascii_case(&update.sha256) {
+        println!(
+            "Checksum mismatch: expected {}, got {}",
+            update.sha256, checksum
+        );
+        let _ = ota_update.abort();
+        return Err(OtaError::ChecksumMismatch);
+    }
+    ota_update.complete().map_err(|_| OtaError::Flash)?;
+    println!("Firmware {} installed", update.version);
+    Ok(())
+}
@@ -10,14 +10,12 @@ use esp_idf_svc::hal::{
     units::KiloHertz,
 };
 
-use crate::{
-    accu::Accu, ACCU_CRITICAL_VOLTAGE, PUMP_COOLDOWN_MS, PUMP_MAX_PUMP_DURATION,
-    PUMP_ML_PER_VOLT_SECOND, PUMP_WARMUP_MS,
-};
+use crate::{accu::Accu, settings::Settings};
 
 #[derive(Debug)]
 pub enum PumpError {
-    AccuCriticalVoltage,
+    // Contains the ml watered until the pump was stopped
+    AccuCriticalVoltage(f32),
 }
 
 pub struct Pumps<'a, A: ADCPin> {
@@ -25,6 +23,7 @@ pub struct Pumps<'a, A: ADCPin> {
     channel0: PeripheralRef<'a, CHANNEL0>,
     pumps: Vec<PeripheralRef<'a, AnyOutputPin>>,
     accu: Accu<'a, A>,
+    settings: Settings,
 }
 
 impl<A: ADCPin> Drop for Pumps<'_, A> {
@@ -42,6 +41,7 @@ impl<'a, A: ADCPin> Pumps<'a, A> {
         channel0: PeripheralRef<'a, CHANNEL0>,
         mut pumps: Vec<PeripheralRef<'a, AnyOutputPin>>,
         accu: Accu<'a, A>,
+        settings: Settings,
     ) -> Self {
         // Ensure pins are low
         for p in pumps.iter_mut() {
@@ -53,11 +53,25 @@ impl<'a, A: ADCPin> Pumps<'a, A> {
             channel0,
             pumps,
             accu,
+            settings,
         }
     }
 
-    pub fn pump(&mut self, index: usize, amount_ml: u32) -> Option<Result<(), PumpError>> {
+    pub fn apply_settings(&mut self, settings: Settings) {
+        self.accu.set_critical_volt(settings.accu_critical_voltage);
+        self.settings = settings;
+    }
+
+    /// Returns the estimated ml watered, or `None` if no pump is connected.
+    pub fn pump(&mut self, index: usize, amount_ml: u32) -> Option<Result<f32, PumpError>> {
         let pump = self.pumps.get_mut(index)?;
+        let Settings {
+            pump_ml_per_volt_second,
+            pump_warmup_ms,
+            pump_cooldown_ms,
+            pump_max_pump_duration,
+            ..
+        } = self.settings;
 
         let timer_driver = LedcTimerDriver::new(
             self.timer0.reborrow(),
@@ -71,39 +85,39 @@ impl<'a, A: ADCPin> Pumps<'a, A> {
 
         // Slowly start pump within 100ms
         println!("Slowly starting pump...");
-        for ms in 0..PUMP_WARMUP_MS {
-            driver.set_duty(max_duty * ms / PUMP_WARMUP_MS).unwrap();
+        for ms in 0..pump_warmup_ms {
+            driver.set_duty(max_duty * ms / pump_warmup_ms).unwrap();
             sleep(Duration::from_millis(1));
         }
 
         // linear start/stop => integral 0.5
         let mut ml_watered: f32 = {
             let volt = self.accu.measure_volt();
-            let warmup_cooldown_sec = (PUMP_WARMUP_MS + PUMP_COOLDOWN_MS) as f32 / 1000.;
-            PUMP_ML_PER_VOLT_SECOND * volt * warmup_cooldown_sec * 0.5
+            let warmup_cooldown_sec = (pump_warmup_ms + pump_cooldown_ms) as f32 / 1000.;
+            pump_ml_per_volt_second * volt * warmup_cooldown_sec * 0.5
         };
 
         println!("Max pump now");
         driver.set_duty(max_duty).unwrap();
         let start = Instant::now();
         let mut delta = Instant::now();
-        while start.elapsed() < PUMP_MAX_PUMP_DURATION && (ml_watered as u32) < amount_ml {
+        while start.elapsed() < pump_max_pump_duration && (ml_watered as u32) < amount_ml {
             sleep(Duration::from_millis(5));
             let volt = self.accu.measure_volt();
             if volt < self.accu.get_critical_volt() {
                 driver.set_duty(0).unwrap();
-                return Some(Err(PumpError::AccuCriticalVoltage));
+                return Some(Err(PumpError::AccuCriticalVoltage(ml_watered)));
             }
-            let delta_watered = PUMP_ML_PER_VOLT_SECOND * volt * delta.elapsed().as_secs_f32();
+            let delta_watered = pump_ml_per_volt_second * volt * delta.elapsed().as_secs_f32();
             ml_watered += delta_watered;
             delta = Instant::now();
         }
 
         println!("Slowly stop pump again");
         // Slowly stop again
-        for ms in 0..PUMP_COOLDOWN_MS {
+        for ms in 0..pump_cooldown_ms {
             driver
-                .set_duty(max_duty * (PUMP_COOLDOWN_MS - ms) / PUMP_COOLDOWN_MS)
+                .set_duty(max_duty * (pump_cooldown_ms - ms) / pump_cooldown_ms)
                 .unwrap();
             sleep(Duration::from_millis(1));
         }
@@ -112,6 +126,6 @@ impl<'a, A: ADCPin> Pumps<'a, A> {
             ml_watered,
             start.elapsed().as_millis()
         );
-        Some(Ok(()))
+        Some(Ok(ml_watered))
     }
 }
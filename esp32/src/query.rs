@@ -1,12 +1,25 @@
 use embedded_svc::http::client::*;
-use embedded_svc::utils::io;
+use embedded_svc::io::{Read, Write};
 use esp_idf_svc::http::client::*;
-use serde::Deserialize;
+use protocol::{
+    DequeueJobs, DequeueQuery, ReportQuery, WateringReport, POSTCARD_MEDIA_TYPE, PROTOCOL_VERSION,
+};
+use std::time::{Duration, Instant};
+
+use crate::{ota::FIRMWARE_VERSION, wake_log::WakeLog, wifi_connect::WifiDiagnostics};
 
 // TODO: resistance against trailing slash
 // e.g. https://myserver.dev/evergreen/api, no trailing slash
-const BASE_URL: &str = env!("API_BASE_URL");
-const API_SECRET: &str = env!("API_SECRET");
+pub const BASE_URL: &str = env!("API_BASE_URL");
+pub const API_SECRET: &str = env!("API_SECRET");
+// Names the box in settings, history and diagnostics. One server waters a
+// single box, the plants and the daily schedule are not kept per device.
+const DEVICE_ID: &str = match option_env!("DEVICE_ID") {
+    Some(id) => id,
+    None => "default",
+};
+// Postcard responses are well below 1KiB even with many plants
+const MAX_RESPONSE_BYTES: usize = 16 * 1024;
 
 #[derive(Debug)]
 pub enum QueryError {
@@ -14,49 +27,127 @@ pub enum QueryError {
     UnexpectedResponse, // mal formatted Json, 404, unexpected format
 }
 
-// Copied from server side
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ServerWateringJob {
-    pub plant_index: usize,
-    pub amount_ml: usize,
-}
-
-// Copied from server side
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct DequeueJobs {
-    pub watering_jobs: Vec<ServerWateringJob>,
-    pub sleep_recommendation_seconds: u64,
-}
-
-pub fn fetch_jobs(accu_percentage: f32) -> Result<DequeueJobs, QueryError> {
-    let mut client = Client::wrap(
+fn client() -> Client<EspHttpConnection> {
+    Client::wrap(
         EspHttpConnection::new(&Configuration {
             crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
             ..Default::default()
         })
         .unwrap(),
-    );
+    )
+}
+
+/// Jobs of the server and how long it took until its response started,
+/// which is sent with the next dequeue request.
+pub fn fetch_jobs(
+    accu_percentage: f32,
+    wifi: &WifiDiagnostics,
+    wake_log: &WakeLog,
+) -> Result<(DequeueJobs, Duration), QueryError> {
+    // Includes the TLS handshake
+    let start = Instant::now();
+    let mut client = client();
 
-    // 10KiB make overflow
-    let mut buffer = [0_u8; 128];
+    // 10KiB make overflow, so read in small chunks into the heap
+    let mut chunk = [0_u8; 128];
+    let mut body = Vec::new();
 
     // Get Jobs
+    let query = DequeueQuery {
+        accu_percentage,
+        api_secret: API_SECRET.into(),
+        device_id: Some(DEVICE_ID.into()),
+        firmware_version: Some(FIRMWARE_VERSION.into()),
+        protocol_version: Some(PROTOCOL_VERSION),
+        wifi_connect_ms: Some(wifi.connect_ms),
+        dhcp_ms: Some(wifi.dhcp_ms),
+        rssi_dbm: wifi.rssi_dbm,
+        bssid: wifi.bssid.clone(),
+        wifi_channel: wifi.channel,
+        previous_round_trip_ms: wake_log.previous_round_trip_ms,
+        failed_wakes: Some(wake_log.failed_wakes),
+        last_failure: wake_log.last_failure,
+    };
     let url = format!(
-        "{}/dequeue_jobs?accu_percentage={}&api_secret={}",
-        BASE_URL, accu_percentage, API_SECRET
+        "{}/dequeue_jobs?{}",
+        BASE_URL,
+        serde_urlencoded::to_string(&query).unwrap()
     );
     println!("POST {}", url);
-    let request = client.post(&url, &[]).unwrap();
-    let mut response = request.submit().unwrap();
-    let read = io::try_read_full(&mut response, &mut buffer).map_err(|_| QueryError::Connection)?;
-    println!("Bytes read: {}", read);
-    let body = String::from_utf8_lossy(&buffer[..read]).into_owned();
-    println!("Response: {}", body);
+    let request = client
+        .post(&url, &[("Accept", POSTCARD_MEDIA_TYPE)])
+        .map_err(|_| QueryError::Connection)?;
+    let mut response = request.submit().map_err(|_| QueryError::Connection)?;
+    let round_trip = start.elapsed();
+    println!("Round trip {}ms", round_trip.as_millis());
+    if response.status() != 200 {
+        println!("Unexpected status {}", response.status());
+        return Err(QueryError::UnexpectedResponse);
+    }
+    // Older servers ignore the Accept header and answer with JSON
+    let is_postcard = response
+        .header("Content-Type")
+        .is_some_and(|c| c.starts_with(POSTCARD_MEDIA_TYPE));
+    loop {
+        let read = response
+            .read(&mut chunk)
+            .map_err(|_| QueryError::Connection)?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+        if body.len() > MAX_RESPONSE_BYTES {
+            return Err(QueryError::UnexpectedResponse);
+        }
+    }
+    println!("Bytes read: {}, postcard: {}", body.len(), is_postcard);
+
+    let server_jobs: DequeueJobs = match is_postcard {
+        true => postcard::from_bytes(&body).map_err(|_| QueryError::UnexpectedResponse)?,
+        false => {
+            println!("Response: {}", String::from_utf8_lossy(&body));
+            serde_json::from_slice(&body).map_err(|_| QueryError::UnexpectedResponse)?
+        }
+    };
+    println!("Jobs: {:?}", server_jobs);
+    if server_jobs.protocol_version < PROTOCOL_VERSION {
+        // Older servers leave out what they don't know about
+        println!(
+            "Server speaks protocol version {}, we speak {}",
+            server_jobs.protocol_version, PROTOCOL_VERSION
+        );
+    }
 
-    let server_jobs: DequeueJobs =
-        serde_json::from_str(&body).map_err(|_| QueryError::UnexpectedResponse)?;
+    Ok((server_jobs, round_trip))
+}
 
-    Ok(server_jobs)
+pub fn report_outcomes(report: &WateringReport) -> Result<(), QueryError> {
+    let mut client = client();
+    let query = ReportQuery {
+        api_secret: API_SECRET.into(),
+        device_id: Some(DEVICE_ID.into()),
+    };
+    let url = format!(
+        "{}/report?{}",
+        BASE_URL,
+        serde_urlencoded::to_string(&query).unwrap()
+    );
+    let body = serde_json::to_vec(report).unwrap();
+    let content_length = body.len().to_string();
+    let headers = [
+        ("Content-Type", "application/json"),
+        ("Content-Length", content_length.as_str()),
+    ];
+    println!("POST {}/report", BASE_URL);
+    let mut request = client
+        .post(&url, &headers)
+        .map_err(|_| QueryError::Connection)?;
+    request
+        .write_all(&body)
+        .map_err(|_| QueryError::Connection)?;
+    let response = request.submit().map_err(|_| QueryError::Connection)?;
+    match response.status() {
+        200 => Ok(()),
+        _ => Err(QueryError::UnexpectedResponse),
+    }
 }
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use protocol::DeviceSettings;
+
+use crate::{
+    ACCU_CRITICAL_VOLTAGE, ERROR_SHOW_RED_LED_DURATION, ERROR_SLEEP_DURATION, PUMP_COOLDOWN_MS,
+    PUMP_MAX_PUMP_DURATION, PUMP_ML_PER_VOLT_SECOND, PUMP_WARMUP_MS,
+    SUCCESS_SHOW_GREEN_LED_DURATION,
+};
+
+const NVS_NAMESPACE: &str = "evergreen";
+const NVS_SETTINGS_KEY: &str = "settings";
+// Serialized settings are a few hundred bytes.
+const NVS_BUFFER_SIZE: usize = 512;
+
+/// Settings the firmware runs with.
+/// Starts with the compiled defaults, which are overwritten
+/// by whatever the server pushed last.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub version: Option<u32>,
+    pub pump_ml_per_volt_second: f32,
+    pub pump_warmup_ms: u32,
+    pub pump_cooldown_ms: u32,
+    pub pump_max_pump_duration: Duration,
+    pub accu_critical_voltage: f32,
+    pub error_sleep_duration: Duration,
+    pub error_show_red_led_duration: Duration,
+    pub success_show_green_led_duration: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: None,
+            pump_ml_per_volt_second: PUMP_ML_PER_VOLT_SECOND,
+            pump_warmup_ms: PUMP_WARMUP_MS,
+            pump_cooldown_ms: PUMP_COOLDOWN_MS,
+            pump_max_pump_duration: PUMP_MAX_PUMP_DURATION,
+            accu_critical_voltage: ACCU_CRITICAL_VOLTAGE,
+            error_sleep_duration: ERROR_SLEEP_DURATION,
+            error_show_red_led_duration: ERROR_SHOW_RED_LED_DURATION,
+            success_show_green_led_duration: SUCCESS_SHOW_GREEN_LED_DURATION,
+        }
+    }
+}
+
+impl Settings {
+    fn from_server(server: &DeviceSettings) -> Self {
+        let default = Self::default();
+        Self {
+            version: Some(server.version),
+            pump_ml_per_volt_second: server
+                .pump_ml_per_volt_second
+                .unwrap_or(default.pump_ml_per_volt_second),
+            pump_warmup_ms: server.pump_warmup_ms.unwrap_or(default.pump_warmup_ms),
+            pump_cooldown_ms: server.pump_cooldown_ms.unwrap_or(default.pump_cooldown_ms),
+            pump_max_pump_duration: server
+                .pump_max_pump_duration_ms
+                .map(Duration::from_millis)
+                .unwrap_or(default.pump_max_pump_duration),
+            accu_critical_voltage: server
+                .accu_critical_voltage
+                .unwrap_or(default.accu_critical_voltage),
+            error_sleep_duration: server
+                .error_sleep_duration_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(default.error_sleep_duration),
+            error_show_red_led_duration: server
+                .error_show_red_led_duration_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(default.error_show_red_led_duration),
+            success_show_green_led_duration: server
+                .success_show_green_led_duration_seconds
+                .map(Duration::from_secs)
+                .unwrap_or(default.success_show_green_led_duration),
+        }
+    }
+
+    /// Load the settings last pushed by the server from NVS.
+    /// Falls back to the compiled defaults if nothing is stored
+    /// or the stored settings can not be read.
+    pub fn load(nvs: EspNvsPartition<NvsDefault>) -> Self {
+        let storage = match EspNvs::new(nvs, NVS_NAMESPACE, true) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Could not open NVS, using default settings: {e:?}");
+                return Self::default();
+            }
+        };
+        let mut buffer = [0_u8; NVS_BUFFER_SIZE];
+        let stored = match storage.get_str(NVS_SETTINGS_KEY, &mut buffer) {
+            Ok(Some(s)) => s,
+            Ok(None) => return Self::default(),
+            Err(e) => {
+                println!("Could not read settings from NVS: {e:?}");
+                return Self::default();
+            }
+        };
+        match serde_json::from_str::<DeviceSettings>(stored) {
+            Ok(server) => {
+                println!("Loaded settings version {} from NVS", server.version);
+                Self::from_server(&server)
+            }
+            Err(_) => {
+                println!("Stored settings are malformed, using defaults");
+                Self::default()
+            }
+        }
+    }
+
+    /// Apply the settings block of a dequeue response.
+    /// New versions are persisted, no settings at all
+    /// reset the device to the compiled defaults.
+    pub fn apply(&mut self, server: Option<&DeviceSettings>, nvs: EspNvsPartition<NvsDefault>) {
+        let new_version = server.map(|s| s.version);
+        if new_version == self.version {
+            return;
+        }
+        *self = match server {
+            Some(server) => Self::from_server(server),
+            None => Self::default(),
+        };
+        println!("Applying settings version {:?}", self.version);
+
+        let mut storage = match EspNvs::new(nvs, NVS_NAMESPACE, true) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Could not open NVS, settings are not persisted: {e:?}");
+                return;
+            }
+        };
+        let result = match server {
+            Some(server) => match serde_json::to_string(server) {
+                Ok(serialized) => storage.set_str(NVS_SETTINGS_KEY, &serialized),
+                Err(_) => return,
+            },
+            None => storage.remove(NVS_SETTINGS_KEY).map(|_| ()),
+        };
+        if let Err(e) = result {
+            println!("Could not persist settings: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use protocol::WakeFailure;
+use serde::{Deserialize, Serialize};
+
+const NVS_NAMESPACE: &str = "evergreen";
+const NVS_WAKE_LOG_KEY: &str = "wakelog";
+// Serialized log is well below 100 bytes.
+const NVS_BUFFER_SIZE: usize = 128;
+
+/// What the server can only learn on the next successful wake,
+/// kept in NVS across deep sleep and power loss.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WakeLog {
+    pub previous_round_trip_ms: Option<u32>,
+    pub failed_wakes: u32,
+    pub last_failure: Option<WakeFailure>,
+}
+
+impl WakeLog {
+    /// An unreadable log is started over, it is only diagnostics.
+    pub fn load(nvs: EspNvsPartition<NvsDefault>) -> Self {
+        let storage = match EspNvs::new(nvs, NVS_NAMESPACE, true) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Could not open NVS, starting a new wake log: {e:?}");
+                return Self::default();
+            }
+        };
+        let mut buffer = [0_u8; NVS_BUFFER_SIZE];
+        match storage.get_str(NVS_WAKE_LOG_KEY, &mut buffer) {
+            Ok(Some(stored)) => serde_json::from_str(stored).unwrap_or_default(),
+            Ok(None) => Self::default(),
+            Err(e) => {
+                println!("Could not read wake log from NVS: {e:?}");
+                Self::default()
+            }
+        }
+    }
+
+    /// The server got everything, only the round trip is kept for the next wake.
+    pub fn checked_in(round_trip_ms: u32) -> Self {
+        Self {
+            previous_round_trip_ms: Some(round_trip_ms),
+            failed_wakes: 0,
+            last_failure: None,
+        }
+    }
+
+    pub fn failed(self, failure: WakeFailure) -> Self {
+        Self {
+            failed_wakes: self.failed_wakes.saturating_add(1),
+            last_failure: Some(failure),
+            ..self
+        }
+    }
+
+    pub fn store(&self, nvs: EspNvsPartition<NvsDefault>) {
+        let mut storage = match EspNvs::new(nvs, NVS_NAMESPACE, true) {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Could not open NVS, wake log is not persisted: {e:?}");
+                return;
+            }
+        };
+        let Ok(serialized) = serde_json::to_string(self) else {
+            return;
+        };
+        if let Err(e) = storage.set_str(NVS_WAKE_LOG_KEY, &serialized) {
+            println!("Could not persist wake log: {e:?}");
+        }
+    }
+}
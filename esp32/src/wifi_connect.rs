@@ -9,6 +9,7 @@ use esp_idf_svc::{
     eventloop::{EspEventLoop, System},
     hal::modem::Modem,
     nvs::{EspNvsPartition, NvsDefault},
+    sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t},
     wifi::EspWifi,
 };
 
@@ -18,35 +19,53 @@ pub enum WifiErr {
     TimeoutIp,
 }
 
+/// How the connection went, sent to the server with the dequeue request.
+#[derive(Clone, Debug, Default)]
+pub struct WifiDiagnostics {
+    pub connect_ms: u32,
+    pub dhcp_ms: u32,
+    pub rssi_dbm: Option<i8>,
+    pub bssid: Option<String>,
+    pub channel: Option<u8>,
+}
+
 pub struct WifiConnection<'a> {
     wifi_driver: EspWifi<'a>,
+    pub diagnostics: WifiDiagnostics,
+}
+
+impl WifiConnection<'_> {
+    /// Turns the radio off, e.g. while pumping, so it doesn't drain the accu.
+    pub fn stop(&mut self) {
+        if let Err(e) = self.wifi_driver.disconnect() {
+            println!("Failed to disconnect wifi: {e:?}");
+        }
+        if let Err(e) = self.wifi_driver.stop() {
+            println!("Failed to stop wifi: {e:?}");
+        }
+    }
+
+    /// Connects again after [`Self::stop`], the diagnostics stay those of the first connect.
+    pub fn reconnect(&mut self, timeout: Duration) -> Result<(), WifiErr> {
+        self.wifi_driver.start().unwrap();
+        self.wifi_driver.connect().unwrap();
+        wait_for_ip(&self.wifi_driver, timeout).map(|_| ())
+    }
 }
 
 impl Drop for WifiConnection<'_> {
     fn drop(&mut self) {
+        if !self.wifi_driver.is_connected().unwrap_or(false) {
+            return;
+        }
         if let Err(e) = self.wifi_driver.disconnect() {
             println!("Failed to disconnect wifi: {e:?}");
         }
     }
 }
 
-pub fn connect_to_wifi_with_timeout(
-    timeout: Duration,
-    modem: Modem,
-    sys_loop: EspEventLoop<System>,
-    nvs: EspNvsPartition<NvsDefault>,
-) -> Result<WifiConnection<'static>, WifiErr> {
-    let mut wifi_driver = EspWifi::new(modem, sys_loop, Some(nvs)).unwrap();
-    wifi_driver
-        .set_configuration(&Configuration::Client(WifiClientConfiguration {
-            ssid: env!("WIFI_SSID").into(),
-            password: env!("WIFI_PASS").into(),
-            ..Default::default()
-        }))
-        .unwrap();
-
-    wifi_driver.start().unwrap();
-    wifi_driver.connect().unwrap();
+/// Waits for the association and an IP, returns the milliseconds each took.
+fn wait_for_ip(wifi_driver: &EspWifi, timeout: Duration) -> Result<(u32, u32), WifiErr> {
     let task_start = Instant::now();
     while !wifi_driver.is_connected().unwrap() {
         let config = wifi_driver.get_configuration().unwrap();
@@ -56,7 +75,8 @@ pub fn connect_to_wifi_with_timeout(
         }
         sleep(Duration::from_millis(250));
     }
-    println!("Connected to wifi!");
+    let connect_ms = task_start.elapsed().as_millis() as u32;
+    println!("Connected to wifi after {}ms!", connect_ms);
 
     loop {
         let ip_info = wifi_driver.sta_netif().get_ip_info().unwrap();
@@ -69,6 +89,49 @@ pub fn connect_to_wifi_with_timeout(
         }
         sleep(Duration::from_millis(150));
     }
+    let dhcp_ms = task_start.elapsed().as_millis() as u32 - connect_ms;
+    Ok((connect_ms, dhcp_ms))
+}
+
+pub fn connect_to_wifi_with_timeout(
+    timeout: Duration,
+    modem: Modem,
+    sys_loop: EspEventLoop<System>,
+    nvs: EspNvsPartition<NvsDefault>,
+) -> Result<WifiConnection<'static>, WifiErr> {
+    let mut wifi_driver = EspWifi::new(modem, sys_loop, Some(nvs)).unwrap();
+    wifi_driver
+        .set_configuration(&Configuration::Client(WifiClientConfiguration {
+            ssid: env!("WIFI_SSID").into(),
+            password: env!("WIFI_PASS").into(),
+            ..Default::default()
+        }))
+        .unwrap();
+
+    wifi_driver.start().unwrap();
+    wifi_driver.connect().unwrap();
+    let (connect_ms, dhcp_ms) = wait_for_ip(&wifi_driver, timeout)?;
+
+    let mut diagnostics = WifiDiagnostics {
+        connect_ms,
+        dhcp_ms,
+        ..Default::default()
+    };
+    // Signal of the access point we are associated with
+    let mut ap_info = wifi_ap_record_t::default();
+    match esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }) {
+        Ok(()) => {
+            let bssid = ap_info.bssid.map(|b| format!("{:02x}", b)).join(":");
+            diagnostics.rssi_dbm = Some(ap_info.rssi);
+            diagnostics.bssid = Some(bssid);
+            diagnostics.channel = Some(ap_info.primary);
+        }
+        Err(e) => println!("Could not read access point info: {e:?}"),
+    }
+    println!("Wifi diagnostics: {:?}", diagnostics);
 
-    Ok(WifiConnection { wifi_driver })
+    Ok(WifiConnection {
+        wifi_driver,
+        diagnostics,
+    })
 }
@@ -0,0 +1,105 @@
+// Generates TypeScript types from the server's OpenAPI spec, see `npm run generate:api`.
+// Reads the spec from stdin and prints the types, with `--check <file>` it fails
+// if the file differs instead, e.g. because the server API changed.
+import { readFileSync } from 'node:fs';
+
+const HEADER = `// Generated from the server's OpenAPI spec by \`npm run generate:api\`, don't edit.\n`;
+
+const indent = (text, depth) => text.replace(/\n/g, '\n' + '\t'.repeat(depth));
+
+const docComment = (description, depth) => {
+	if (!description) {
+		return '';
+	}
+	const lines = description.trim().split('\n');
+	const tabs = '\t'.repeat(depth);
+	if (lines.length === 1) {
+		return `${tabs}/** ${lines[0]} */\n`;
+	}
+	return `${tabs}/**\n${lines.map((line) => `${tabs} * ${line}`.trimEnd()).join('\n')}\n${tabs} */\n`;
+};
+
+const propertyName = (name) => (/^[A-Za-z_$][A-Za-z0-9_$]*$/.test(name) ? name : `'${name}'`);
+
+const objectType = (schema) => {
+	const required = new Set(schema.required ?? []);
+	const properties = Object.entries(schema.properties ?? {}).map(([name, property]) => {
+		const optional = required.has(name) ? '' : '?';
+		return (
+			docComment(property.description, 1) +
+			`\t${propertyName(name)}${optional}: ${indent(type(property), 1)};`
+		);
+	});
+	if (schema.additionalProperties) {
+		properties.push(`\t[key: string]: ${type(schema.additionalProperties)};`);
+	}
+	return properties.length > 0 ? `{\n${properties.join('\n')}\n}` : 'Record<string, never>';
+};
+
+const baseType = (schema) => {
+	if (schema.$ref) {
+		return schema.$ref.split('/').pop();
+	}
+	if (schema.allOf?.length === 1) {
+		return type(schema.allOf[0]);
+	}
+	if (schema.oneOf) {
+		return schema.oneOf.map(type).join(' | ');
+	}
+	if (schema.enum) {
+		return schema.enum.map((value) => `'${value}'`).join(' | ');
+	}
+	switch (schema.type) {
+		case 'integer':
+		case 'number':
+			return 'number';
+		case 'string':
+			return 'string';
+		case 'boolean':
+			return 'boolean';
+		case 'array': {
+			const items = type(schema.items);
+			return /^\w+$/.test(items) ? `${items}[]` : `(${items})[]`;
+		}
+		case 'object':
+			return objectType(schema);
+		default:
+			return 'unknown';
+	}
+};
+
+const type = (schema) => {
+	const base = baseType(schema);
+	return schema.nullable ? `${base} | null` : base;
+};
+
+const generate = (spec) => {
+	const schemas = Object.entries(spec.components?.schemas ?? {}).map(([name, schema]) => {
+		const declaration =
+			schema.type === 'object' && !schema.oneOf
+				? `export interface ${name} ${objectType(schema)}`
+				: `export type ${name} = ${type(schema)};`;
+		return docComment(schema.description, 0) + declaration;
+	});
+	const paths = Object.keys(spec.paths ?? {}).map((path) => `\t| '${path}'`);
+	return [
+		HEADER,
+		...schemas.map((schema) => schema + '\n'),
+		`/** Paths of the API, relative to \`api_prefix\`. */\nexport type ApiPath =\n${paths.join(
+			'\n'
+		)};\n`
+	].join('\n');
+};
+
+const spec = JSON.parse(readFileSync(0, 'utf-8'));
+const types = generate(spec);
+const checkIndex = process.argv.indexOf('--check');
+if (checkIndex === -1) {
+	process.stdout.write(types);
+} else {
+	const file = process.argv[checkIndex + 1];
+	if (readFileSync(file, 'utf-8') !== types) {
+		console.error(`${file} doesn't match the server's OpenAPI spec, run npm run generate:api`);
+		process.exit(1);
+	}
+}
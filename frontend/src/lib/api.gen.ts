@@ -0,0 +1,309 @@
+// Generated from the server's OpenAPI spec by `npm run generate:api`, don't edit.
+
+export interface AuditEntry {
+	id: number;
+	timestamp: string;
+	clientIp: string;
+	change: Change;
+	/** ID of the entry this one undid. */
+	reverts?: number | null;
+}
+
+/** A file of the archive, paths are relative to the archive. */
+export interface BackupFile {
+	path: string;
+	size: number;
+	sha256: string;
+}
+
+/** `manifest.json` of a backup archive. */
+export interface BackupManifest {
+	format: number;
+	/** Version of the server which wrote the backup. */
+	serverVersion: string;
+	/** Newest protocol that server spoke with the ESP32. */
+	protocolVersion: number;
+	created: string;
+	files: BackupFile[];
+}
+
+/** A change made through the API. */
+export type Change = {
+	plant: string;
+	old: number;
+	new: number;
+	type: 'plantAmountMl';
+} | {
+	plant: string;
+	type: 'wateringTest';
+} | {
+	snapshot: string;
+	old: PlantConfig[];
+	new: PlantConfig[];
+	/** `plants` section before, with its comments. Missing in older entries. */
+	oldToml?: string | null;
+	type: 'snapshotApplied';
+} | {
+	deviceId: string;
+	oldMl: number;
+	newMl: number;
+	type: 'reservoirRefill';
+} | {
+	created: string;
+	serverVersion: string;
+	files: number;
+	type: 'backupRestored';
+};
+
+export interface ComponentHealth {
+	name: string;
+	status: HealthStatus;
+	message: string;
+}
+
+/** Response of `POST /dequeue_jobs`. */
+export interface DequeueJobs {
+	/**
+	 * Version the server chose, at most the one the device sent.
+	 * Must stay the first field, postcard decoders can only
+	 * tell versions apart by reading it first.
+	 */
+	protocolVersion?: number;
+	wateringJobs: WateringJob[];
+	sleepRecommendationSeconds: number;
+	deviceSettings?: DeviceSettings | null;
+	firmwareUpdate?: FirmwareUpdate | null;
+}
+
+/** Response of `POST /dequeue_jobs` for protocol version 1. */
+export interface DequeueJobsV1 {
+	wateringJobs: WateringJob[];
+	sleepRecommendationSeconds: number;
+}
+
+/** Dequeue response in the format of the negotiated protocol version. */
+export type DequeueResponse = DequeueJobsV1 | DequeueJobs;
+
+export interface DeviceInfo {
+	deviceId: string;
+	lastSeenTimestamp: number;
+	lastBatteryPercentage: number;
+	lastIp: string;
+	firmwareVersion?: string | null;
+	protocolVersion: number;
+}
+
+/**
+ * Settings pushed to the ESP32 with every dequeue response.
+ * Every value is optional, the firmware falls back to its
+ * compiled defaults for everything not set here.
+ * Increase `version` on every change, the ESP32 only
+ * persists settings with a version it has not seen yet.
+ */
+export interface DeviceSettings {
+	version: number;
+	pumpMlPerVoltSecond?: number | null;
+	pumpWarmupMs?: number | null;
+	pumpCooldownMs?: number | null;
+	pumpMaxPumpDurationMs?: number | null;
+	accuCriticalVoltage?: number | null;
+	errorSleepDurationSeconds?: number | null;
+	errorShowRedLedDurationSeconds?: number | null;
+	successShowGreenLedDurationSeconds?: number | null;
+}
+
+/** Body of every error response of the API. */
+export interface ErrorBody {
+	code: ErrorCode;
+	/** For humans, may change any time */
+	message: string;
+	/** Also in the `X-Request-Id` header and the server log */
+	requestId: string;
+}
+
+/** What went wrong, stable across releases. Clients should match on this, not the message. */
+export type ErrorCode = 'bad_request' | 'unauthorized' | 'forbidden' | 'disabled' | 'not_found' | 'method_not_allowed' | 'conflict' | 'gone' | 'payload_too_large' | 'range_not_satisfiable' | 'invalid_config' | 'rate_limited' | 'locked_out' | 'config_error' | 'state_error' | 'internal' | 'shutting_down';
+
+export type ExportKind = 'waterings' | 'check-ins' | 'outcomes';
+
+export interface FirmwareImage {
+	deviceId: string;
+	version: string;
+	sha256: string;
+	size: number;
+	uploaded: string;
+}
+
+export interface FirmwareManifest {
+	images: FirmwareImage[];
+	/**
+	 * Devices rolled back to a fixed version, by device ID.
+	 * Devices not listed here follow the newest image.
+	 */
+	pinned: {
+		[key: string]: string;
+	};
+}
+
+export interface FirmwareUpdate {
+	version: string;
+	sha256: string;
+	size: number;
+	path: string;
+}
+
+export type HealthStatus = 'ok' | 'skipped' | 'warn' | 'fail';
+
+export interface LastSeenResponse {
+	lastSeenTimestamp: number;
+	lastBatteryPercentage: number;
+	lastWateringDate: string;
+}
+
+/** An IP with wrong secrets, locked out or not yet. */
+export interface Lockout {
+	ip: string;
+	/** Wrong secrets in a row. */
+	failures: number;
+	lastFailure: string;
+	/** Missing if the IP may still try. */
+	lockedUntil?: string | null;
+}
+
+/**
+ * Network diagnostics of a device aggregated over a day.
+ * Averages are `None` if no check-in of the day reported the value.
+ */
+export interface NetworkDay {
+	date: string;
+	checkIns: number;
+	/** Wakes without check-in, counted on the day of the next successful one */
+	failedWakes: number;
+	lastFailure?: WakeFailure | null;
+	avgRssiDbm?: number | null;
+	minRssiDbm?: number | null;
+	avgWifiConnectMs?: number | null;
+	maxWifiConnectMs?: number | null;
+	avgDhcpMs?: number | null;
+	avgRoundTripMs?: number | null;
+	/** Access points the device connected to, more than one hints at roaming */
+	bssids: string[];
+	wifiChannels: string;
+}
+
+/** Network diagnostics of a single check-in. */
+export interface NetworkSample {
+	timestamp: number;
+	wifiConnectMs?: number | null;
+	dhcpMs?: number | null;
+	rssiDbm?: number | null;
+	bssid?: string | null;
+	wifiChannel?: number | null;
+	/** Of the previous check-in, the device can't time the one it is sending */
+	previousRoundTripMs?: number | null;
+}
+
+/** Network trend of a device, oldest day first. */
+export interface NetworkTrend {
+	deviceId: string;
+	signal: SignalQuality;
+	medianRssiDbm?: number | null;
+	latest?: NetworkSample | null;
+	days: NetworkDay[];
+}
+
+export interface PlantConfig {
+	amountMl: number;
+	name: string;
+}
+
+/** A plant that differs between the config and a snapshot. */
+export interface PlantDiff {
+	/** Position in `[[plants]]`, which is what the schedule goes by. */
+	index: number;
+	current?: PlantConfig | null;
+	snapshot?: PlantConfig | null;
+}
+
+export interface Readiness {
+	status: HealthStatus;
+	components: ComponentHealth[];
+}
+
+/** Estimated level of the reservoir of a device. */
+export interface ReservoirStatus {
+	deviceId: string;
+	capacityMl: number;
+	levelMl: number;
+	lowLevelMl: number;
+	low: boolean;
+	/** Jobs are skipped while low */
+	skipJobsWhenLow: boolean;
+	/** `None` if never refilled, it is assumed full then */
+	lastRefill?: string | null;
+	lowSince?: string | null;
+}
+
+/** How well the device reaches the router, judged from the median RSSI. */
+export type SignalQuality = 'good' | 'fair' | 'weak' | 'unknown';
+
+export interface SnapshotInfo {
+	name: string;
+	created: string;
+	plants: PlantConfig[];
+}
+
+/** Why a wake of the ESP32 ended without reaching the server. */
+export type WakeFailure = 'wifi_connect_timeout' | 'ip_timeout' | 'connection' | 'unexpected_response' | 'watchdog';
+
+export interface WateringJob {
+	plantIndex: number;
+	amountMl: number;
+}
+
+export interface WateringOutcome {
+	plantIndex: number;
+	requestedMl: number;
+	wateredMl: number;
+	durationMs: number;
+	status: WateringStatus;
+}
+
+/** Body of `POST /report`, sent after the dequeued jobs were worked off. */
+export interface WateringReport {
+	outcomes: WateringOutcome[];
+}
+
+export type WateringStatus = 'done' | 'accuCriticalVoltage' | 'noPumpConnected' | 'skipped';
+
+/** Paths of the API, relative to `api_prefix`. */
+export type ApiPath =
+	| '/admin/backup'
+	| '/admin/firmware'
+	| '/admin/firmware/{device_id}/rollback'
+	| '/admin/firmware/{device_id}/rollback/{version}'
+	| '/admin/firmware/{device_id}/{version}'
+	| '/admin/lockouts'
+	| '/admin/lockouts/{ip}'
+	| '/admin/restore'
+	| '/audit'
+	| '/audit/{id}/revert'
+	| '/calendar.ics'
+	| '/dequeue_jobs'
+	| '/devices'
+	| '/devices/{device_id}/network'
+	| '/devices/{device_id}/reservoir/refill'
+	| '/export/{kind}'
+	| '/firmware/{device_id}/{version}'
+	| '/healthz'
+	| '/lastseen'
+	| '/plants'
+	| '/readyz'
+	| '/report'
+	| '/reservoirs'
+	| '/snapshots'
+	| '/snapshots/{name}'
+	| '/snapshots/{name}/apply'
+	| '/snapshots/{name}/diff'
+	| '/testwatering/{plantname}'
+	| '/updateml/{plantname}';
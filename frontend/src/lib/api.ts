@@ -0,0 +1,61 @@
+// Calls of the server API, see /api/openapi.json or /api/docs.
+// The types come from api.gen.ts, run `npm run generate:api` after API changes.
+import type { ApiPath, ErrorBody, LastSeenResponse, PlantConfig, ReservoirStatus } from './api.gen';
+import { API_PREFIX } from './index';
+
+const url = (
+	path: ApiPath,
+	params: Record<string, string> = {},
+	query: Record<string, string | number> = {}
+): string => {
+	const resolved = path.replace(/\{(\w+)\}/g, (_, name: string) =>
+		encodeURIComponent(params[name])
+	);
+	const search = Object.entries(query).map(([key, value]) => [key, String(value)]);
+	return API_PREFIX + resolved + (search.length > 0 ? '?' + new URLSearchParams(search) : '');
+};
+
+/** Message of an error response, with the request ID to look it up in the server log. */
+export const errorMessage = async (response: Response): Promise<string> => {
+	const body: ErrorBody = await response.json();
+	return `${body.message} (request ${body.requestId})`;
+};
+
+/** GET /plants */
+export const getPlants = async (): Promise<PlantConfig[]> => {
+	const response = await fetch(url('/plants'));
+	if (!response.ok) {
+		throw new Error(await errorMessage(response));
+	}
+	return await response.json();
+};
+
+/** GET /lastseen */
+export const getLastSeen = async (): Promise<LastSeenResponse> => {
+	const response = await fetch(url('/lastseen'));
+	if (!response.ok) {
+		throw new Error(await errorMessage(response));
+	}
+	return await response.json();
+};
+
+/** POST /updateml/{plantname} */
+export const updateAmountMl = (name: string, amountMl: number): Promise<Response> =>
+	fetch(url('/updateml/{plantname}', { plantname: name }, { amountMl }), { method: 'POST' });
+
+/** POST /testwatering/{plantname}, answers once the ESP32 picked up the job. */
+export const testWatering = (name: string): Promise<Response> =>
+	fetch(url('/testwatering/{plantname}', { plantname: name }), { method: 'POST' });
+
+/** GET /reservoirs */
+export const getReservoirs = async (): Promise<ReservoirStatus[]> => {
+	const response = await fetch(url('/reservoirs'));
+	if (!response.ok) {
+		throw new Error(await errorMessage(response));
+	}
+	return await response.json();
+};
+
+/** POST /devices/{deviceId}/reservoir/refill, sets the level to the capacity. */
+export const refillReservoir = (deviceId: string): Promise<Response> =>
+	fetch(url('/devices/{device_id}/reservoir/refill', { device_id: deviceId }), { method: 'POST' });
@@ -1,10 +1,2 @@
-export interface PlantConfig {
-	name: string;
-	amountMl: number;
-}
-
-export interface LastSeenInfo {
-	lastSeenTimestamp: number;
-	lastBatteryPercentage: number;
-	lastWateringDate: string;
-}
+// Must match api_prefix of the server.
+export const API_PREFIX: string = import.meta.env.VITE_API_PREFIX ?? '/api';
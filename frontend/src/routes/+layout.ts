@@ -0,0 +1,2 @@
+// Rendered in the browser only, the server just hands out static files.
+export const ssr = false;
@@ -0,0 +1,362 @@
+//! Messages exchanged between the ESP32 firmware and the server.
+//!
+//! Field names are camelCase on the wire, except for the dequeue
+//! query, which is sent as URL query parameters.
+//!
+//! Responses are JSON, or postcard if the device asks for it with
+//! `Accept: application/x-postcard`. Postcard is not self describing,
+//! so any field change requires a new protocol version.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+/// Protocol version spoken by this crate.
+///
+/// - 1: Watering jobs and sleep recommendation only.
+///   Firmware which does not send a version speaks it.
+/// - 2: Device settings, firmware updates and watering reports.
+pub const PROTOCOL_VERSION: u32 = 2;
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Media type of postcard encoded responses.
+pub const POSTCARD_MEDIA_TYPE: &str = "application/x-postcard";
+
+fn legacy_protocol_version() -> u32 {
+    LEGACY_PROTOCOL_VERSION
+}
+
+/// Query parameters of `POST /dequeue_jobs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::IntoParams),
+    into_params(parameter_in = Query)
+)]
+pub struct DequeueQuery {
+    pub accu_percentage: f32,
+    // Api call defines the allowed IP, so it must be protected.
+    pub api_secret: String,
+    // Older firmware does not send an ID or versions.
+    pub device_id: Option<String>,
+    pub firmware_version: Option<String>,
+    pub protocol_version: Option<u32>,
+    // Network diagnostics, all optional. Older servers ignore unknown
+    // query parameters, so no new protocol version is needed.
+    /// Milliseconds from starting WiFi until associated with the access point.
+    pub wifi_connect_ms: Option<u32>,
+    /// Milliseconds from associated until DHCP handed out an IP.
+    pub dhcp_ms: Option<u32>,
+    pub rssi_dbm: Option<i8>,
+    /// MAC of the access point, e.g. `aa:bb:cc:dd:ee:ff`.
+    pub bssid: Option<String>,
+    pub wifi_channel: Option<u8>,
+    /// HTTP round trip of the previous dequeue request, including TLS.
+    pub previous_round_trip_ms: Option<u32>,
+    /// Wakes which did not reach the server since the last check-in.
+    pub failed_wakes: Option<u32>,
+    /// Why the latest of the failed wakes failed.
+    pub last_failure: Option<WakeFailure>,
+}
+
+/// Why a wake of the ESP32 ended without reaching the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum WakeFailure {
+    /// Not associated with the access point in time.
+    WifiConnectTimeout,
+    /// Associated, but DHCP handed out no IP in time.
+    IpTimeout,
+    /// HTTP or TLS failed.
+    Connection,
+    /// The server answered, but not with jobs.
+    UnexpectedResponse,
+    Watchdog,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct WateringJob {
+    pub plant_index: usize,
+    pub amount_ml: u32,
+}
+
+/// Settings pushed to the ESP32 with every dequeue response.
+/// Every value is optional, the firmware falls back to its
+/// compiled defaults for everything not set here.
+/// Increase `version` on every change, the ESP32 only
+/// persists settings with a version it has not seen yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DeviceSettings {
+    pub version: u32,
+    pub pump_ml_per_volt_second: Option<f32>,
+    pub pump_warmup_ms: Option<u32>,
+    pub pump_cooldown_ms: Option<u32>,
+    pub pump_max_pump_duration_ms: Option<u64>,
+    pub accu_critical_voltage: Option<f32>,
+    pub error_sleep_duration_seconds: Option<u64>,
+    pub error_show_red_led_duration_seconds: Option<u64>,
+    pub success_show_green_led_duration_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct FirmwareUpdate {
+    pub version: String,
+    pub sha256: String,
+    pub size: u64,
+    // Relative to the API base URL
+    pub path: String,
+}
+
+/// Response of `POST /dequeue_jobs`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DequeueJobs {
+    /// Version the server chose, at most the one the device sent.
+    /// Must stay the first field, postcard decoders can only
+    /// tell versions apart by reading it first.
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    pub watering_jobs: Vec<WateringJob>,
+    pub sleep_recommendation_seconds: u64,
+    #[serde(default)]
+    pub device_settings: Option<DeviceSettings>,
+    #[serde(default)]
+    pub firmware_update: Option<FirmwareUpdate>,
+}
+
+/// Response of `POST /dequeue_jobs` for protocol version 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DequeueJobsV1 {
+    pub watering_jobs: Vec<WateringJob>,
+    pub sleep_recommendation_seconds: u64,
+}
+
+impl From<DequeueJobs> for DequeueJobsV1 {
+    fn from(jobs: DequeueJobs) -> Self {
+        Self {
+            watering_jobs: jobs.watering_jobs,
+            sleep_recommendation_seconds: jobs.sleep_recommendation_seconds,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub enum WateringStatus {
+    Done,
+    // Pumping stopped early, the remaining jobs are skipped
+    AccuCriticalVoltage,
+    NoPumpConnected,
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct WateringOutcome {
+    pub plant_index: usize,
+    pub requested_ml: u32,
+    // Estimated from pump runtime and accu voltage
+    pub watered_ml: u32,
+    pub duration_ms: u32,
+    pub status: WateringStatus,
+}
+
+/// Body of `POST /report`, sent after the dequeued jobs were worked off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct WateringReport {
+    pub outcomes: Vec<WateringOutcome>,
+}
+
+/// Query parameters of `POST /report`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "openapi",
+    derive(utoipa::IntoParams),
+    into_params(parameter_in = Query)
+)]
+pub struct ReportQuery {
+    pub api_secret: String,
+    pub device_id: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use serde::de::DeserializeOwned;
+
+    fn roundtrip<T: Serialize + DeserializeOwned + PartialEq + core::fmt::Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        let parsed: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, parsed);
+
+        let bytes = postcard::to_allocvec(&value).unwrap();
+        let parsed: T = postcard::from_bytes(&bytes).unwrap();
+        assert_eq!(value, parsed);
+    }
+
+    fn settings() -> DeviceSettings {
+        DeviceSettings {
+            version: 3,
+            pump_ml_per_volt_second: Some(22.413793),
+            pump_warmup_ms: Some(25),
+            pump_cooldown_ms: None,
+            pump_max_pump_duration_ms: Some(15000),
+            accu_critical_voltage: Some(4.0),
+            error_sleep_duration_seconds: Some(7200),
+            error_show_red_led_duration_seconds: None,
+            success_show_green_led_duration_seconds: Some(7),
+        }
+    }
+
+    #[test]
+    fn dequeue_query_roundtrip() {
+        roundtrip(DequeueQuery {
+            accu_percentage: 87.5,
+            api_secret: "secret".into(),
+            device_id: Some("balcony".into()),
+            firmware_version: Some("0.2.0".into()),
+            protocol_version: Some(PROTOCOL_VERSION),
+            wifi_connect_ms: Some(1830),
+            dhcp_ms: Some(412),
+            rssi_dbm: Some(-71),
+            bssid: Some("aa:bb:cc:dd:ee:ff".into()),
+            wifi_channel: Some(6),
+            previous_round_trip_ms: Some(950),
+            failed_wakes: Some(2),
+            last_failure: Some(WakeFailure::IpTimeout),
+        });
+    }
+
+    #[test]
+    fn dequeue_jobs_roundtrip() {
+        roundtrip(DequeueJobs {
+            protocol_version: PROTOCOL_VERSION,
+            watering_jobs: vec![
+                WateringJob {
+                    plant_index: 0,
+                    amount_ml: 100,
+                },
+                WateringJob {
+                    plant_index: 1,
+                    amount_ml: 500,
+                },
+            ],
+            sleep_recommendation_seconds: 86400,
+            device_settings: Some(settings()),
+            firmware_update: Some(FirmwareUpdate {
+                version: "0.2.0".into(),
+                sha256: "4cbf5bfbe3537db61cbd3f0e6d98eaac3c92abc7119fda9b3274e4e71a09a104".into(),
+                size: 1_048_576,
+                path: "/firmware/default/0.2.0".into(),
+            }),
+        });
+    }
+
+    #[test]
+    fn dequeue_jobs_without_optional_blocks() {
+        // As sent by servers before settings and updates existed
+        let json =
+            r#"{"wateringJobs":[{"plantIndex":2,"amountMl":250}],"sleepRecommendationSeconds":0}"#;
+        let parsed: DequeueJobs = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(parsed.watering_jobs[0].amount_ml, 250);
+        assert_eq!(parsed.device_settings, None);
+        assert_eq!(parsed.firmware_update, None);
+    }
+
+    #[test]
+    fn dequeue_jobs_v1_roundtrip() {
+        let jobs = DequeueJobs {
+            protocol_version: PROTOCOL_VERSION,
+            watering_jobs: vec![WateringJob {
+                plant_index: 0,
+                amount_ml: 100,
+            }],
+            sleep_recommendation_seconds: 3600,
+            device_settings: Some(settings()),
+            firmware_update: None,
+        };
+        let v1 = DequeueJobsV1::from(jobs.clone());
+        let json = serde_json::to_string(&v1).unwrap();
+        assert_eq!(
+            json,
+            r#"{"wateringJobs":[{"plantIndex":0,"amountMl":100}],"sleepRecommendationSeconds":3600}"#
+        );
+        roundtrip(v1);
+
+        // Old firmware ignores everything it does not know
+        let json = serde_json::to_string(&jobs).unwrap();
+        let parsed: DequeueJobsV1 = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.watering_jobs, jobs.watering_jobs);
+    }
+
+    #[test]
+    fn watering_report_roundtrip() {
+        roundtrip(ReportQuery {
+            api_secret: "secret".into(),
+            device_id: None,
+        });
+        roundtrip(WateringReport {
+            outcomes: vec![
+                WateringOutcome {
+                    plant_index: 0,
+                    requested_ml: 100,
+                    watered_ml: 103,
+                    duration_ms: 1250,
+                    status: WateringStatus::Done,
+                },
+                WateringOutcome {
+                    plant_index: 1,
+                    requested_ml: 500,
+                    watered_ml: 12,
+                    duration_ms: 80,
+                    status: WateringStatus::AccuCriticalVoltage,
+                },
+            ],
+        });
+    }
+
+    #[test]
+    fn postcard_stays_small() {
+        let jobs = DequeueJobs {
+            protocol_version: PROTOCOL_VERSION,
+            watering_jobs: (0..16)
+                .map(|plant_index| WateringJob {
+                    plant_index,
+                    amount_ml: 1000,
+                })
+                .collect(),
+            sleep_recommendation_seconds: 86400,
+            device_settings: Some(settings()),
+            firmware_update: None,
+        };
+        let json = serde_json::to_vec(&jobs).unwrap();
+        let bytes = postcard::to_allocvec(&jobs).unwrap();
+        assert!(json.len() > 600);
+        assert!(bytes.len() < 100);
+    }
+
+    #[test]
+    fn settings_use_camel_case() {
+        let json = serde_json::to_string(&settings()).unwrap();
+        assert!(json.contains("\"pumpMlPerVoltSecond\":"));
+        assert!(json.contains("\"pumpCooldownMs\":null"));
+    }
+}
@@ -0,0 +1,50 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use log::warn;
+
+use crate::{
+    client_ip::ClientIp,
+    error::{ApiError, ErrorCode},
+    limits::secret_matches,
+    GlobalState,
+};
+
+/// Extractor guarding the admin API.
+/// Requires the header `Authorization: Bearer <admin_secret>`.
+/// The admin API is disabled if no `admin_secret` is configured.
+/// Repeated wrong secrets lock the IP out for a while.
+pub struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<GlobalState> for AdminAuth {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &GlobalState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(expected_secret) = state.config.get_admin_secret()? else {
+            return Err(ApiError::new(
+                ErrorCode::Disabled,
+                "Admin API is disabled, set admin_secret to enable it",
+            ));
+        };
+        let limits = state.config.get_rate_limits()?;
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+        let provided_secret = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let correct =
+            provided_secret.is_some_and(|provided| secret_matches(&expected_secret, provided));
+        if let Err(rejection) = state.limiter.check_secret(&limits, ip, correct) {
+            warn!("Admin request from {} with missing or wrong secret", ip);
+            return Err(rejection.into_api_error("admin secret"));
+        }
+        Ok(AdminAuth)
+    }
+}
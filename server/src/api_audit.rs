@@ -0,0 +1,140 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use log::info;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    audit::{AuditEntry, Change},
+    client_ip::ClientIp,
+    config::PlantConfig,
+    error::ApiError,
+    limits::FrontendRateLimit,
+    snapshot::plants_toml,
+    GlobalState,
+};
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only the newest entries
+    limit: Option<usize>,
+}
+
+/// Changes made through the API, newest first.
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "frontend",
+    params(AuditQuery),
+    responses(
+        (status = 200, body = Vec<AuditEntry>),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn list_audit(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Query(AuditQuery { limit }): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>, ApiError> {
+    let mut entries = state.audit.list()?;
+    entries.reverse();
+    entries.truncate(limit.unwrap_or(usize::MAX));
+    Ok(Json(entries))
+}
+
+fn revert_amount(
+    state: &GlobalState,
+    id: u64,
+    plant: String,
+    old: u32,
+    new: u32,
+) -> Result<Change, ApiError> {
+    state.config.put_plant_amount_ml_if(&plant, new, old)?;
+    info!(
+        "Reverted change {}, plant {} gets {}ml/day again",
+        id, plant, old
+    );
+    Ok(Change::PlantAmountMl {
+        plant,
+        old: new,
+        new: old,
+    })
+}
+
+fn revert_snapshot(
+    state: &GlobalState,
+    id: u64,
+    snapshot: String,
+    old: Vec<PlantConfig>,
+    new: Vec<PlantConfig>,
+    old_toml: Option<String>,
+) -> Result<Change, ApiError> {
+    // Older entries don't have the comments of the config before the snapshot
+    let toml = match old_toml {
+        Some(toml) => toml,
+        None => plants_toml(&old)?,
+    };
+    let new_toml = state.config.put_plants_toml_if(&new, &toml)?;
+    info!("Reverted change {}, snapshot {} is undone", id, snapshot);
+    Ok(Change::SnapshotApplied {
+        snapshot,
+        old: new,
+        new: old,
+        old_toml: Some(new_toml),
+    })
+}
+
+/// Undoes a single change, unless the values were changed again since.
+/// The undo is logged as a new entry.
+#[utoipa::path(
+    post,
+    path = "/audit/{id}/revert",
+    tag = "frontend",
+    params(
+        ("id" = u64, Path, description = "ID of the audit entry"),
+    ),
+    responses(
+        (status = 200, description = "The entry of the undo", body = AuditEntry),
+        (status = 400, description = "Change can't be reverted", body = ErrorBody),
+        (status = 404, description = "Unknown entry or plant", body = ErrorBody),
+        (status = 409, description = "Value was changed since", body = ErrorBody),
+        (status = 422, description = "Config would be invalid", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn revert_change(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(id): Path<u64>,
+    ClientIp(ip): ClientIp,
+) -> Result<Json<AuditEntry>, ApiError> {
+    let entry = state
+        .audit
+        .get(id)?
+        .ok_or_else(|| ApiError::not_found(format!("Audit entry {} not found", id)))?;
+
+    let change = match entry.change {
+        Change::PlantAmountMl { plant, old, new } => revert_amount(&state, id, plant, old, new)?,
+        Change::SnapshotApplied {
+            snapshot,
+            old,
+            new,
+            old_toml,
+        } => revert_snapshot(&state, id, snapshot, old, new, old_toml)?,
+        Change::WateringTest { .. } => {
+            return Err(ApiError::bad_request("Watering tests can't be reverted"))
+        }
+        Change::ReservoirRefill { .. } => {
+            return Err(ApiError::bad_request("Refills can't be reverted"))
+        }
+        Change::BackupRestored { .. } => {
+            return Err(ApiError::bad_request("Restores can't be reverted"))
+        }
+    };
+    Ok(Json(state.audit.record(ip, change, Some(id))?))
+}
@@ -0,0 +1,146 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderName,
+    },
+    Json,
+};
+use log::{error, info};
+
+use crate::{
+    admin::AdminAuth,
+    api_frontend::record_change,
+    audit::Change,
+    backup::{self, BackupError, BackupManifest, StateStores},
+    client_ip::ClientIp,
+    error::ApiError,
+    GlobalState,
+};
+
+fn stores(state: &GlobalState) -> StateStores {
+    StateStores {
+        json_state: state.json_state.clone(),
+        audit: state.audit.clone(),
+        history: state.history.clone(),
+        snapshots: state.snapshots.clone(),
+        firmware: state.firmware.clone(),
+    }
+}
+
+/// Backups read and write whole files, which must not block the runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, BackupError> + Send + 'static,
+) -> Result<T, ApiError> {
+    let result = tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ApiError::internal(format!("Backup task failed: {}", e)))?;
+    Ok(result?)
+}
+
+/// Archive of config, state, history, audit log, snapshots and firmware images,
+/// with a `manifest.json` listing versions and checksums.
+#[utoipa::path(
+    get,
+    path = "/admin/backup",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    responses(
+        (status = 200, description = "A .tar.gz", content_type = "application/gzip", body = Vec<u8>),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn download_backup(
+    _: AdminAuth,
+    state: State<GlobalState>,
+) -> Result<([(HeaderName, String); 2], Vec<u8>), ApiError> {
+    let (config, state_dir, stores) = (
+        state.config.clone(),
+        state.state_dir.clone(),
+        stores(&state),
+    );
+    let (manifest, archive) =
+        blocking(move || backup::create(&config, &state_dir, &stores)).await?;
+    info!(
+        "Created backup with {} files, {} bytes",
+        manifest.files.len(),
+        archive.len()
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "application/gzip".into()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}\"",
+                    backup::file_name(manifest.created)
+                ),
+            ),
+        ],
+        archive,
+    ))
+}
+
+/// Replaces config and state by those of a backup. The whole archive is checked
+/// before anything is replaced, files not in the backup are deleted.
+/// Restore while the device sleeps, a check-in running at the same time
+/// may write back the state it read before.
+#[utoipa::path(
+    post,
+    path = "/admin/restore",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    request_body(content = Vec<u8>, content_type = "application/gzip"),
+    responses(
+        (status = 200, description = "Manifest of the restored backup", body = BackupManifest),
+        (status = 400, description = "Archive is damaged or doesn't fit this server", body = ErrorBody),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 413, description = "Archive too large", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn restore_backup(
+    _: AdminAuth,
+    state: State<GlobalState>,
+    ClientIp(ip): ClientIp,
+    body: Bytes,
+) -> Result<Json<BackupManifest>, ApiError> {
+    let (config, state_dir, stores) = (
+        state.config.clone(),
+        state.state_dir.clone(),
+        stores(&state),
+    );
+    let manifest = blocking(move || backup::restore(&config, &state_dir, &stores, &body)).await?;
+    info!(
+        "Restored backup from {} with {} files",
+        manifest.created,
+        manifest.files.len()
+    );
+
+    // The test queued here stays if its plant is still in the same place.
+    // One the backed up server stored on shutdown is stale by now.
+    state
+        .pending_watering_test
+        .cancel_if_plant_moved(&state.config)
+        .await;
+    if let Ok(mut json_state) = state.json_state.get() {
+        if let Some(plant) = json_state.pending_watering_test.take() {
+            match state.json_state.set(json_state) {
+                Ok(()) => info!("Dropped watering test of {} stored in the backup", plant),
+                Err(e) => error!("Could not drop watering test stored in the backup: {}", e),
+            }
+        }
+    }
+
+    let change = Change::BackupRestored {
+        created: manifest.created,
+        server_version: manifest.server_version.clone(),
+        files: manifest.files.len(),
+    };
+    record_change(&state, ip, change);
+    Ok(Json(manifest))
+}
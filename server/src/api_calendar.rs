@@ -0,0 +1,84 @@
+use std::io::ErrorKind;
+
+use axum::{
+    extract::{Query, State},
+    http::{header::CONTENT_TYPE, HeaderName},
+};
+use chrono::Local;
+use log::warn;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    calendar::{self, UPCOMING_DAYS},
+    client_ip::ClientIp,
+    error::{ApiError, ErrorCode},
+    history::{OutcomeRecord, WateringRecord},
+    limits::{secret_matches, FrontendRateLimit},
+    simulate::simulate_check_ins,
+    state::{JsonState, StateError},
+    GlobalState,
+};
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CalendarQuery {
+    /// `calendar_secret` of the config, calendar apps can't send headers
+    token: String,
+}
+
+/// iCalendar feed of the past waterings with their outcomes and the planned ones.
+#[utoipa::path(
+    get,
+    path = "/calendar.ics",
+    tag = "frontend",
+    params(CalendarQuery),
+    responses(
+        (status = 200, content_type = "text/calendar"),
+        (status = 401, description = "Wrong token", body = ErrorBody),
+        (status = 403, description = "Feed disabled", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded or locked out after wrong tokens", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn calendar_feed(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Query(CalendarQuery { token }): Query<CalendarQuery>,
+    ClientIp(ip): ClientIp,
+) -> Result<([(HeaderName, &'static str); 1], String), ApiError> {
+    let Some(secret) = state.config.get_calendar_secret()? else {
+        return Err(ApiError::new(
+            ErrorCode::Disabled,
+            "Calendar feed is disabled, set calendar_secret to enable it",
+        ));
+    };
+    let limits = state.config.get_rate_limits()?;
+    if let Err(rejection) = state
+        .limiter
+        .check_secret(&limits, ip, secret_matches(&secret, &token))
+    {
+        warn!("Calendar feed requested from {} with wrong token", ip);
+        return Err(rejection.into_api_error("token"));
+    }
+
+    let plants = state.config.get_plant_config()?;
+    // Only simulated, the planned waterings must not change what the device gets next
+    let json_state = match state.json_state.get() {
+        Ok(json_state) => json_state,
+        Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => JsonState::default(),
+        Err(e) => return Err(e.into()),
+    };
+    let now = Local::now().naive_local();
+    let planned = simulate_check_ins(json_state, &plants, now, UPCOMING_DAYS);
+
+    let since = calendar::past_since(now);
+    let waterings = state.history.read_since::<WateringRecord>(since)?;
+    let outcomes = state.history.read_since::<OutcomeRecord>(since)?;
+    let past = calendar::match_outcomes(waterings, outcomes);
+
+    Ok((
+        [(CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        calendar::feed(&past, &planned),
+    ))
+}
@@ -1,125 +1,351 @@
-use std::time::Duration;
-
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
     Json,
 };
-use axum_client_ip::SecureClientIp;
-use chrono::{Duration as ChronoDuration, Local, NaiveDate};
-use log::{error, warn};
-use serde::Deserialize;
+use chrono::{Local, NaiveDateTime};
+use log::{error, info, warn};
+use protocol::{
+    DequeueJobs, DequeueQuery, FirmwareUpdate, ReportQuery, WateringJob, WateringReport,
+    LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::net::IpAddr;
 
 use crate::{
-    model::{DequeueJobs, WateringJob},
+    client_ip::ClientIp,
+    config::DEFAULT_DEVICE_ID,
+    encoding::{Encoded, Encoding},
+    error::ApiError,
+    history::{CheckInRecord, OutcomeRecord, WateringRecord},
+    limits::{secret_matches, Esp32RateLimit},
+    model::DequeueResponse,
+    reservoir,
+    schedule::{plan_check_in, CheckInPlan},
+    state::{DeviceState, JsonState},
     GlobalState,
 };
 
-fn next_watering_sleep_time(last_date: NaiveDate) -> ChronoDuration {
-    // ChronoDuration can be negative
+/// Repeated wrong secrets lock the IP out for a while.
+pub fn check_api_secret(state: &GlobalState, ip: IpAddr, provided: &str) -> Result<(), ApiError> {
+    let expected_secret = state.config.get_api_secret()?;
+    let limits = state.config.get_rate_limits()?;
+    state
+        .limiter
+        .check_secret(&limits, ip, secret_matches(&expected_secret, provided))
+        .map_err(|rejection| {
+            warn!("Request from {} with wrong API secret", ip);
+            rejection.into_api_error("API secret")
+        })
+}
+
+fn firmware_update(
+    state: &GlobalState,
+    device_id: &str,
+    firmware_version: Option<&str>,
+) -> Option<FirmwareUpdate> {
+    let firmware_version = firmware_version?;
+    let image = match state.firmware.available_update(device_id, firmware_version) {
+        Ok(image) => image?,
+        Err(e) => {
+            // Watering is more important than updating, don't fail the request.
+            warn!("Could not look up firmware update for {}: {}", device_id, e);
+            return None;
+        }
+    };
+    info!(
+        "Offering firmware {} to {} running {}",
+        image.version, device_id, firmware_version
+    );
+    Some(FirmwareUpdate {
+        path: format!("/firmware/{}/{}", device_id, image.version),
+        version: image.version.to_string(),
+        sha256: image.sha256,
+        size: image.size,
+    })
+}
+
+/// Highest protocol version both sides speak.
+/// Devices newer than the server get the server's version.
+fn negotiate_protocol_version(device_version: Option<u32>) -> u32 {
+    device_version
+        .unwrap_or(LEGACY_PROTOCOL_VERSION)
+        .clamp(LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+fn to_response(jobs: DequeueJobs) -> DequeueResponse {
+    match jobs.protocol_version {
+        LEGACY_PROTOCOL_VERSION => DequeueResponse::V1(jobs.into()),
+        _ => DequeueResponse::V2(jobs),
+    }
+}
+
+fn record_check_in(
+    json_state: &mut JsonState,
+    device_id: &str,
+    ip: IpAddr,
+    query: &DequeueQuery,
+    protocol_version: u32,
+) {
     let now = Local::now().naive_local();
-    let last_watering = last_date.and_hms_opt(9, 0, 0).expect("Invalid hms");
-    let h24 = ChronoDuration::from_std(Duration::from_secs(24 * 60 * 60)).unwrap();
-    let next_watering = last_watering + h24;
+    json_state.last_seen = now;
+    json_state.last_ip = ip;
+    json_state.last_accu_percentage = query.accu_percentage;
+    json_state.devices.insert(
+        device_id.into(),
+        DeviceState {
+            last_seen: now,
+            last_ip: ip,
+            last_accu_percentage: query.accu_percentage,
+            firmware_version: query.firmware_version.clone(),
+            protocol_version,
+        },
+    );
+}
 
-    // 9h - 10h on same day => -1h to "wait"
-    next_watering - now
+/// Name of a plant at the time of the check-in, empty if it is gone.
+fn plant_name(state: &GlobalState, plant_index: usize) -> String {
+    state
+        .config
+        .get_plant_config()
+        .ok()
+        .and_then(|plants| plants.get(plant_index).map(|p| p.name.clone()))
+        .unwrap_or_default()
 }
 
-#[derive(Deserialize, Debug)]
-pub struct DequeueQuery {
-    accu_percentage: f32,
-    // Api call defines the allowed IP, so it must be protected.
-    api_secret: String,
+fn check_in_record(
+    now: NaiveDateTime,
+    device_id: &str,
+    ip: IpAddr,
+    query: &DequeueQuery,
+    protocol_version: u32,
+) -> CheckInRecord {
+    CheckInRecord {
+        timestamp: now,
+        device_id: device_id.into(),
+        battery_percentage: query.accu_percentage,
+        ip,
+        firmware_version: query.firmware_version.clone(),
+        protocol_version,
+        wifi_connect_ms: query.wifi_connect_ms,
+        dhcp_ms: query.dhcp_ms,
+        rssi_dbm: query.rssi_dbm,
+        bssid: query.bssid.clone(),
+        wifi_channel: query.wifi_channel,
+        previous_round_trip_ms: query.previous_round_trip_ms,
+        failed_wakes: query.failed_wakes,
+        last_failure: query.last_failure,
+    }
+}
+
+/// Keeps the check-in for exports, failing only costs statistics.
+fn record_history(state: &GlobalState, check_in: CheckInRecord, jobs: &[WateringJob], test: bool) {
+    let waterings: Vec<WateringRecord> = jobs
+        .iter()
+        .map(|job| WateringRecord {
+            timestamp: check_in.timestamp,
+            device_id: check_in.device_id.clone(),
+            plant_index: job.plant_index,
+            plant: plant_name(state, job.plant_index),
+            amount_ml: job.amount_ml,
+            test,
+        })
+        .collect();
+    let result = state
+        .history
+        .append(&[check_in])
+        .and_then(|_| state.history.append(&waterings));
+    if let Err(err) = result {
+        error!("Could not record history: {}", err);
+    }
 }
 
+/// Check-in of the ESP32, answers with the jobs to do now.
+/// Devices sending `Accept: application/x-postcard` get postcard instead of JSON.
+#[utoipa::path(
+    post,
+    path = "/dequeue_jobs",
+    tag = "esp32",
+    params(DequeueQuery),
+    responses(
+        (status = 200, description = "Shape depends on the negotiated protocol version",
+            content(
+                ("application/json" = DequeueResponse),
+                ("application/x-postcard" = DequeueResponse),
+            )
+        ),
+        (status = 401, description = "Wrong API secret", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded or locked out after wrong secrets", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
 pub async fn dequeue_jobs(
+    _: Esp32RateLimit,
     state: State<GlobalState>,
     Query(query): Query<DequeueQuery>,
-    SecureClientIp(ip): SecureClientIp,
-) -> (StatusCode, Result<Json<DequeueJobs>, String>) {
-    let expected_secret = state.config.get_api_secret();
-    if let Err(e) = expected_secret {
-        error!("Could not read API secret: {}", e);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Err("Error reading config".into()),
-        );
-    }
-    let expected_secret = expected_secret.unwrap();
-    if expected_secret != query.api_secret {
-        // I know, one does not log wrong passwords, but
-        // it's not a real password and it's helpful.
-        warn!("Provided API secret \"{}\" was wrong", query.api_secret);
-        return (StatusCode::UNAUTHORIZED, Err("Wrong API secret".into()));
-    }
+    ClientIp(ip): ClientIp,
+    encoding: Encoding,
+) -> Result<Encoded<DequeueResponse>, ApiError> {
+    check_api_secret(&state, ip, &query.api_secret)?;
 
+    let device_id = query.device_id.as_deref().unwrap_or(DEFAULT_DEVICE_ID);
+    let protocol_version = negotiate_protocol_version(query.protocol_version);
     println!(
-        "ESP32 with IP {} reports: Accu: {}",
-        ip, query.accu_percentage
+        "ESP32 {} with IP {} reports: Accu: {}, firmware {:?}, protocol {}",
+        device_id, ip, query.accu_percentage, query.firmware_version, protocol_version
     );
+    // Settings and updates need protocol version 2
+    let (device_settings, firmware_update) = match protocol_version {
+        LEGACY_PROTOCOL_VERSION => (None, None),
+        _ => (
+            state.config.get_device_settings(device_id)?,
+            firmware_update(&state, device_id, query.firmware_version.as_deref()),
+        ),
+    };
+
     // If watering test is pending, do just that one
     if let Some(task) = state.pending_watering_test.pop_pending_task().await {
+        let watering_jobs = vec![task.destruct_and_ack()];
+        let now = Local::now().naive_local();
+        // Tests are asked for explicitly, so they run even with a low reservoir
+        let reservoir = state.config.get_reservoir(device_id).ok().flatten();
+        let alert = state.json_state.update(|json_state| {
+            record_check_in(json_state, device_id, ip, &query, protocol_version);
+            reservoir.as_ref().and_then(|reservoir| {
+                reservoir::draw(json_state, device_id, reservoir, &watering_jobs, now)
+            })
+        });
+        match alert {
+            Ok(Some(alert)) => state.notifier.send(alert),
+            Ok(None) => {}
+            Err(err) => error!("Could not record check-in during watering test: {}", err),
+        }
+        let check_in = check_in_record(now, device_id, ip, &query, protocol_version);
+        record_history(&state, check_in, &watering_jobs, true);
         let test_job = DequeueJobs {
-            watering_jobs: vec![task.destruct_and_ack()],
+            protocol_version,
+            watering_jobs,
             sleep_recommendation_seconds: 0,
+            device_settings,
+            firmware_update,
         };
-        return (StatusCode::OK, Ok(Json(test_job)));
+        return Ok(Encoded(encoding, to_response(test_job)));
     }
 
     // Check if watering should happen now
-    let json_state = state.json_state.ensure_state();
-    if let Err(err) = json_state {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Err(format!("Error reading json state: {}", err)),
-        );
+    let plant_config = state.config.get_plant_config()?;
+    let reservoir = state.config.get_reservoir(device_id)?;
+    let now = Local::now().naive_local();
+    let (watering_jobs, sleep_recommendation_seconds, alert) =
+        state.json_state.update(|json_state| {
+            let CheckInPlan {
+                mut watering_jobs,
+                sleep_recommendation_seconds,
+            } = plan_check_in(json_state, &plant_config, now);
+            let mut alert = None;
+            if let Some(reservoir) = &reservoir {
+                if !watering_jobs.is_empty()
+                    && reservoir::skips_jobs(json_state, device_id, reservoir)
+                {
+                    warn!(
+                        "Skipping {} watering jobs of {}, its reservoir is low",
+                        watering_jobs.len(),
+                        device_id
+                    );
+                    watering_jobs.clear();
+                }
+                alert = reservoir::draw(json_state, device_id, reservoir, &watering_jobs, now);
+            }
+            record_check_in(json_state, device_id, ip, &query, protocol_version);
+            (watering_jobs, sleep_recommendation_seconds, alert)
+        })?;
+    // Only once the state remembers it, so it isn't sent again
+    if let Some(alert) = alert {
+        state.notifier.send(alert);
     }
-    let mut json_state = json_state.unwrap();
-    let plant_config = state.config.get_plant_config();
-    if let Err(err) = plant_config {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Err(format!("Error reading plant config: {}", err)),
+    let check_in = check_in_record(now, device_id, ip, &query, protocol_version);
+    record_history(&state, check_in, &watering_jobs, false);
+
+    let waterig_job = DequeueJobs {
+        protocol_version,
+        watering_jobs,
+        sleep_recommendation_seconds,
+        device_settings,
+        firmware_update,
+    };
+    Ok(Encoded(encoding, to_response(waterig_job)))
+}
+
+/// Outcome of the dequeued watering jobs.
+#[utoipa::path(
+    post,
+    path = "/report",
+    tag = "esp32",
+    params(ReportQuery),
+    request_body = WateringReport,
+    responses(
+        (status = 200, body = String),
+        (status = 401, description = "Wrong API secret", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded or locked out after wrong secrets", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn report_watering(
+    _: Esp32RateLimit,
+    state: State<GlobalState>,
+    Query(query): Query<ReportQuery>,
+    ClientIp(ip): ClientIp,
+    Json(report): Json<WateringReport>,
+) -> Result<&'static str, ApiError> {
+    check_api_secret(&state, ip, &query.api_secret)?;
+    let device_id = query.device_id.as_deref().unwrap_or(DEFAULT_DEVICE_ID);
+    for outcome in report.outcomes.iter() {
+        info!(
+            "ESP32 {} reports plant {}: {:?}, {}ml of {}ml in {}ms",
+            device_id,
+            outcome.plant_index,
+            outcome.status,
+            outcome.watered_ml,
+            outcome.requested_ml,
+            outcome.duration_ms
         );
     }
-    let plant_config = plant_config.unwrap();
     let now = Local::now().naive_local();
-    let watering_sleep_time_sec: i64 =
-        next_watering_sleep_time(json_state.last_planned_watering).num_seconds();
-    let jobs: Vec<WateringJob> = match watering_sleep_time_sec.is_negative() {
-        true => {
-            json_state.last_planned_watering = now.date();
-            plant_config
-                .iter()
-                .enumerate()
-                .map(|(index, conf)| WateringJob {
-                    plant_index: index,
-                    amount_ml: conf.amount_ml,
-                })
-                .collect()
-        }
-        false => Vec::new(),
-    };
-    json_state.last_seen = Local::now().naive_local();
-    json_state.last_ip = ip;
-    json_state.last_accu_percentage = query.accu_percentage;
-    if let Err(err) = state.json_state.set(json_state.clone()) {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Err(format!("Json Store error: {}", err)),
-        );
+    let outcomes: Vec<OutcomeRecord> = report
+        .outcomes
+        .iter()
+        .map(|outcome| OutcomeRecord {
+            timestamp: now,
+            device_id: device_id.into(),
+            plant_index: outcome.plant_index,
+            plant: plant_name(&state, outcome.plant_index),
+            requested_ml: outcome.requested_ml,
+            watered_ml: outcome.watered_ml,
+            duration_ms: outcome.duration_ms,
+            status: outcome.status,
+        })
+        .collect();
+    if let Err(err) = state.history.append(&outcomes) {
+        error!("Could not record outcomes: {}", err);
     }
 
-    let sleep_recommendation = next_watering_sleep_time(json_state.last_planned_watering);
-    let sleep_recommendation_seconds = if sleep_recommendation.num_seconds().is_negative() {
-        0
-    } else {
-        sleep_recommendation.num_seconds() as u64
+    // The requested amounts were drawn when the jobs were handed out
+    let undelivered_ml = report
+        .outcomes
+        .iter()
+        .map(|outcome| outcome.requested_ml.saturating_sub(outcome.watered_ml))
+        .fold(0u32, u32::saturating_add);
+    let reservoir = match undelivered_ml {
+        0 => None,
+        _ => state.config.get_reservoir(device_id)?,
     };
-    let waterig_job = DequeueJobs {
-        watering_jobs: jobs,
-        sleep_recommendation_seconds,
-    };
-    (StatusCode::OK, Ok(Json(waterig_job)))
+    if let Some(reservoir) = reservoir {
+        let result = state.json_state.update(|json_state| {
+            reservoir::give_back(json_state, device_id, &reservoir, undelivered_ml, now)
+        });
+        match result {
+            Ok(Some(alert)) => state.notifier.send(alert),
+            Ok(None) => {}
+            Err(err) => error!("Could not update reservoir of {}: {}", device_id, err),
+        }
+    }
+    Ok("Report received")
 }
@@ -0,0 +1,213 @@
+use std::path::PathBuf;
+
+use axum::{
+    body::{boxed, Body, Bytes},
+    extract::{Path, Query, State},
+    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDate;
+use log::error;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    history::{CheckInRecord, OutcomeRecord, Record, WateringRecord},
+    limits::FrontendRateLimit,
+    GlobalState,
+};
+
+// Bytes collected before they are sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize, Debug, Clone, Copy, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportKind {
+    /// Jobs handed out to the devices
+    Waterings,
+    /// Battery and IP of every dequeue request
+    CheckIns,
+    /// Watering reports of the devices
+    Outcomes,
+}
+
+impl ExportKind {
+    fn name(self) -> &'static str {
+        match self {
+            ExportKind::Waterings => "waterings",
+            ExportKind::CheckIns => "check-ins",
+            ExportKind::Outcomes => "outcomes",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    /// Newline delimited JSON
+    Ndjson,
+}
+
+impl ExportFormat {
+    fn media_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// First day, inclusive
+    from: Option<NaiveDate>,
+    /// Last day, inclusive
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    #[param(inline)]
+    format: ExportFormat,
+}
+
+#[derive(Error, Debug)]
+enum ExportError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parsing error in line {0}: {1}")]
+    Parse(usize, serde_json::Error),
+    #[error("Serializing error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Serializing error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Client went away: {0}")]
+    Send(#[from] hyper::Error),
+}
+
+/// History of a date range, streamed as the file is read.
+#[utoipa::path(
+    get,
+    path = "/export/{kind}",
+    tag = "frontend",
+    params(
+        ("kind" = ExportKind, Path, description = "Which history to export"),
+        ExportQuery,
+    ),
+    responses(
+        (status = 200, description = "One line per record, oldest first",
+            content(
+                ("text/csv" = String),
+                ("application/x-ndjson" = String),
+            )
+        ),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    )
+)]
+pub async fn export(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(kind): Path<ExportKind>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let (sender, body) = Body::channel();
+    let history = &state.history;
+    match kind {
+        ExportKind::Waterings => {
+            spawn_export::<WateringRecord>(history.path::<WateringRecord>(), query, sender)
+        }
+        ExportKind::CheckIns => {
+            spawn_export::<CheckInRecord>(history.path::<CheckInRecord>(), query, sender)
+        }
+        ExportKind::Outcomes => {
+            spawn_export::<OutcomeRecord>(history.path::<OutcomeRecord>(), query, sender)
+        }
+    }
+    let filename = format!("{}.{}", kind.name(), query.format.extension());
+    (
+        [
+            (CONTENT_TYPE, query.format.media_type().to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        boxed(body),
+    )
+        .into_response()
+}
+
+fn spawn_export<R: Record>(path: PathBuf, query: ExportQuery, mut sender: hyper::body::Sender) {
+    tokio::spawn(async move {
+        if let Err(e) = write_export::<R>(&path, &query, &mut sender).await {
+            error!("Export of {} stopped: {}", path.display(), e);
+            // Tells the client the export is incomplete
+            sender.abort();
+        }
+    });
+}
+
+async fn write_export<R: Record>(
+    path: &std::path::Path,
+    query: &ExportQuery,
+    sender: &mut hyper::body::Sender,
+) -> Result<(), ExportError> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        // Nothing recorded yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let mut line_number = 0;
+    let mut first = true;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: R =
+            serde_json::from_str(&line).map_err(|e| ExportError::Parse(line_number, e))?;
+        let day = record.timestamp().date();
+        if query.from.is_some_and(|from| day < from) {
+            continue;
+        }
+        // Records are appended in the server's local time, which can go back,
+        // e.g. at the end of daylight saving time, so later lines may still match
+        if query.to.is_some_and(|to| day > to) {
+            continue;
+        }
+        match query.format {
+            ExportFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(first)
+                    .from_writer(&mut chunk);
+                writer.serialize(&record)?;
+                writer.flush()?;
+            }
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut chunk, &record)?;
+                chunk.push(b'\n');
+            }
+        }
+        first = false;
+        if chunk.len() >= CHUNK_SIZE {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+            sender.send_data(Bytes::from(full)).await?;
+        }
+    }
+    if !chunk.is_empty() {
+        sender.send_data(Bytes::from(chunk)).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,296 @@
+use std::io::Read;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::info;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    admin::AdminAuth,
+    api_esp32::check_api_secret,
+    client_ip::ClientIp,
+    error::{ApiError, ErrorCode},
+    firmware::{FirmwareError, FirmwareImage, FirmwareManifest},
+    GlobalState,
+};
+
+/// Parse a single `bytes=` range into an inclusive start and end.
+/// Returns `None` for unsatisfiable or unsupported ranges.
+fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let range = header.strip_prefix("bytes=")?;
+    if range.contains(',') || size == 0 {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size - 1)
+        }
+        (start, "") => (start.parse().ok()?, size - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size - 1)),
+    };
+    match start <= end && start < size {
+        true => Some((start, end)),
+        false => None,
+    }
+}
+
+fn header_value(value: String) -> Result<HeaderValue, ApiError> {
+    HeaderValue::try_from(value).map_err(|e| ApiError::internal(format!("Invalid header: {}", e)))
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DownloadQuery {
+    api_secret: String,
+}
+
+/// Firmware image for the ESP32, supports resuming with `Range`.
+#[utoipa::path(
+    get,
+    path = "/firmware/{device_id}/{version}",
+    tag = "esp32",
+    params(
+        ("device_id" = String, Path, description = "ID the ESP32 sends"),
+        ("version" = String, Path, description = "Semantic version"),
+        DownloadQuery,
+    ),
+    responses(
+        (status = 200, content_type = "application/octet-stream"),
+        (status = 206, description = "Requested range", content_type = "application/octet-stream"),
+        (status = 401, description = "Wrong API secret", body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 416, description = "Range not satisfiable", body = ErrorBody),
+        (status = 429, description = "Locked out after wrong secrets", body = ErrorBody),
+    )
+)]
+pub async fn download_firmware(
+    state: State<GlobalState>,
+    Path((device_id, version)): Path<(String, String)>,
+    Query(query): Query<DownloadQuery>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    // Not rate limited, downloads are resumed with many range requests
+    check_api_secret(&state, ip, &query.api_secret)?;
+
+    let image = state.firmware.get_image(&device_id, &version)?;
+    let range = headers.get(RANGE).and_then(|r| r.to_str().ok());
+    let (status, start, end) = match range {
+        None => (StatusCode::OK, 0, image.size.saturating_sub(1)),
+        Some(range) => match parse_range(range, image.size) {
+            Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+            None => {
+                let mut response = ApiError::new(
+                    ErrorCode::RangeNotSatisfiable,
+                    format!("Image has {} bytes", image.size),
+                )
+                .into_response();
+                response.headers_mut().insert(
+                    CONTENT_RANGE,
+                    header_value(format!("bytes */{}", image.size))?,
+                );
+                return Ok(response);
+            }
+        },
+    };
+
+    let file = state.firmware.open_image(&image, start)?;
+    let length = if image.size == 0 { 0 } else { end - start + 1 };
+    let mut body = Vec::with_capacity(length as usize);
+    file.take(length)
+        .read_to_end(&mut body)
+        .map_err(FirmwareError::from)?;
+    info!(
+        "Serving firmware {} for {} bytes {}-{}",
+        image.version, device_id, start, end
+    );
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response_headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response_headers.insert(CONTENT_LENGTH, length.into());
+    if status == StatusCode::PARTIAL_CONTENT {
+        response_headers.insert(
+            CONTENT_RANGE,
+            header_value(format!("bytes {}-{}/{}", start, end, image.size))?,
+        );
+    }
+    Ok((status, response_headers, body).into_response())
+}
+
+/// Uploaded images and rollbacks.
+#[utoipa::path(
+    get,
+    path = "/admin/firmware",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    responses(
+        (status = 200, body = FirmwareManifest),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+    )
+)]
+pub async fn list_firmware(
+    _: AdminAuth,
+    state: State<GlobalState>,
+) -> Result<Json<FirmwareManifest>, ApiError> {
+    Ok(Json(state.firmware.get_manifest()?))
+}
+
+/// Adds a firmware image, offered to devices running an older version.
+#[utoipa::path(
+    post,
+    path = "/admin/firmware/{device_id}/{version}",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    params(
+        ("device_id" = String, Path, description = "ID the ESP32 sends"),
+        ("version" = String, Path, description = "Semantic version"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 201, body = FirmwareImage),
+        (status = 400, description = "Invalid device ID or version", body = ErrorBody),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 409, description = "Version already uploaded", body = ErrorBody),
+        (status = 413, description = "Image too large", body = ErrorBody),
+    )
+)]
+pub async fn upload_firmware(
+    _: AdminAuth,
+    state: State<GlobalState>,
+    Path((device_id, version)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<FirmwareImage>), ApiError> {
+    let image = state.firmware.add_image(&device_id, &version, &body)?;
+    info!(
+        "Uploaded firmware {} for {} ({} bytes)",
+        image.version, image.device_id, image.size
+    );
+    Ok((StatusCode::CREATED, Json(image)))
+}
+
+/// Pins a device to an uploaded version.
+#[utoipa::path(
+    post,
+    path = "/admin/firmware/{device_id}/rollback/{version}",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    params(
+        ("device_id" = String, Path, description = "ID the ESP32 sends"),
+        ("version" = String, Path, description = "Semantic version"),
+    ),
+    responses(
+        (status = 200, body = String),
+        (status = 400, description = "Invalid version", body = ErrorBody),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 404, description = "Version not uploaded", body = ErrorBody),
+    )
+)]
+pub async fn rollback_firmware(
+    _: AdminAuth,
+    state: State<GlobalState>,
+    Path((device_id, version)): Path<(String, String)>,
+) -> Result<String, ApiError> {
+    state.firmware.pin_version(&device_id, Some(&version))?;
+    info!("Device {} pinned to firmware {}", device_id, version);
+    Ok(format!(
+        "Device {} will be rolled back to {}",
+        device_id, version
+    ))
+}
+
+/// Lets a pinned device follow the newest image again.
+#[utoipa::path(
+    delete,
+    path = "/admin/firmware/{device_id}/rollback",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    params(
+        ("device_id" = String, Path, description = "ID the ESP32 sends"),
+    ),
+    responses(
+        (status = 200, body = String),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+    )
+)]
+pub async fn clear_rollback(
+    _: AdminAuth,
+    state: State<GlobalState>,
+    Path(device_id): Path<String>,
+) -> Result<String, ApiError> {
+    state.firmware.pin_version(&device_id, None)?;
+    Ok(format!(
+        "Device {} follows the newest firmware again",
+        device_id
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SIZE: u64 = 1000;
+
+    #[test]
+    fn closed_range() {
+        assert_eq!(parse_range("bytes=0-499", SIZE), Some((0, 499)));
+        assert_eq!(parse_range("bytes=500-500", SIZE), Some((500, 500)));
+        assert_eq!(parse_range("bytes= 10 - 20 ", SIZE), Some((10, 20)));
+    }
+
+    #[test]
+    fn open_ended_range() {
+        assert_eq!(parse_range("bytes=400-", SIZE), Some((400, 999)));
+        assert_eq!(parse_range("bytes=999-", SIZE), Some((999, 999)));
+    }
+
+    #[test]
+    fn suffix_range() {
+        assert_eq!(parse_range("bytes=-100", SIZE), Some((900, 999)));
+        // Longer than the image, all of it
+        assert_eq!(parse_range("bytes=-5000", SIZE), Some((0, 999)));
+        assert_eq!(parse_range("bytes=-0", SIZE), None);
+    }
+
+    #[test]
+    fn end_past_the_image_is_cut_off() {
+        assert_eq!(parse_range("bytes=900-5000", SIZE), Some((900, 999)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        // Answered with 416
+        assert_eq!(parse_range("bytes=500-400", SIZE), None);
+        assert_eq!(parse_range("bytes=1000-", SIZE), None);
+        assert_eq!(parse_range("bytes=1000-1100", SIZE), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn unsupported_ranges() {
+        assert_eq!(parse_range("bytes=0-1,5-6", SIZE), None);
+        assert_eq!(parse_range("items=0-10", SIZE), None);
+        assert_eq!(parse_range("bytes=a-10", SIZE), None);
+        assert_eq!(parse_range("bytes=10", SIZE), None);
+        assert_eq!(parse_range("bytes=-", SIZE), None);
+        assert_eq!(parse_range("bytes=-1-5", SIZE), None);
+    }
+}
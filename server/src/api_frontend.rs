@@ -1,51 +1,138 @@
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
     Json,
 };
-use axum_client_ip::SecureClientIp;
 use log::{error, info, warn};
+use protocol::WateringJob;
 use serde::Deserialize;
+use std::net::IpAddr;
+use utoipa::IntoParams;
 
 use crate::{
+    audit::Change,
+    client_ip::ClientIp,
     config::PlantConfig,
-    model::{LastSeenResponse, WateringJob},
+    error::{ApiError, ErrorCode},
+    limits::FrontendRateLimit,
+    model::{DeviceInfo, LastSeenResponse},
+    watering_test::Ack,
     GlobalState, FRONTEND_ML_MAX,
 };
 
-pub async fn last_seen(state: State<GlobalState>) -> Json<Option<LastSeenResponse>> {
-    let state_res = state.json_state.get();
-    if let Err(e) = state_res {
-        log::error!("Error reading state: {:?}", e);
-        return Json(None);
+/// Changes are done already, so a broken audit log doesn't fail the request.
+pub fn record_change(state: &GlobalState, ip: IpAddr, change: Change) {
+    if let Err(e) = state.audit.record(ip, change, None) {
+        error!("Could not write audit log: {}", e);
     }
-    let state = state_res.unwrap();
+}
+
+/// When the ESP32 checked in the last time.
+#[utoipa::path(
+    get,
+    path = "/lastseen",
+    tag = "frontend",
+    responses(
+        (status = 200, body = LastSeenResponse),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "State can't be read", body = ErrorBody),
+    )
+)]
+pub async fn last_seen(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+) -> Result<Json<LastSeenResponse>, ApiError> {
+    let state = state.json_state.get()?;
     info!("Last seen request - ESP32 last seen: {}", state.last_seen);
-    let last_seen_response = LastSeenResponse {
+    Ok(Json(LastSeenResponse {
         last_seen_timestamp: state.last_seen.and_utc().timestamp(),
         last_battery_percentage: state.last_accu_percentage,
         last_watering_date: state.last_planned_watering.to_string(),
-    };
-    Json(Some(last_seen_response))
+    }))
 }
 
-pub async fn get_plant(state: State<GlobalState>) -> Json<Vec<PlantConfig>> {
-    let plants = state.config.get_plant_config().unwrap();
+/// All devices which checked in so far.
+#[utoipa::path(
+    get,
+    path = "/devices",
+    tag = "frontend",
+    responses(
+        (status = 200, body = Vec<DeviceInfo>),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "State can't be read", body = ErrorBody),
+    )
+)]
+pub async fn get_devices(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+) -> Result<Json<Vec<DeviceInfo>>, ApiError> {
+    let state = state.json_state.get()?;
+    let mut devices: Vec<DeviceInfo> = state
+        .devices
+        .into_iter()
+        .map(|(device_id, device)| DeviceInfo {
+            device_id,
+            last_seen_timestamp: device.last_seen.and_utc().timestamp(),
+            last_battery_percentage: device.last_accu_percentage,
+            last_ip: device.last_ip.to_string(),
+            firmware_version: device.firmware_version,
+            protocol_version: device.protocol_version,
+        })
+        .collect();
+    devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+    Ok(Json(devices))
+}
+
+/// Configured plants, the index is the one used in watering jobs.
+#[utoipa::path(
+    get,
+    path = "/plants",
+    tag = "frontend",
+    responses(
+        (status = 200, body = Vec<PlantConfig>),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "Config can't be read", body = ErrorBody),
+    )
+)]
+pub async fn get_plant(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+) -> Result<Json<Vec<PlantConfig>>, ApiError> {
+    let plants = state.config.get_plant_config()?;
     info!("Get plants request - plant count {}", plants.len());
-    Json(plants)
+    Ok(Json(plants))
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, IntoParams)]
 #[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
 pub struct SetAmountMlQuery {
     amount_ml: usize,
 }
 
+/// Changes the daily amount of a plant in the config file.
+#[utoipa::path(
+    post,
+    path = "/updateml/{plantname}",
+    tag = "frontend",
+    params(
+        ("plantname" = String, Path, description = "Name configured in [[plants]]"),
+        SetAmountMlQuery,
+    ),
+    responses(
+        (status = 200, body = String),
+        (status = 400, description = "Amount too high", body = ErrorBody),
+        (status = 404, description = "Unknown plant", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "Config can't be written", body = ErrorBody),
+    )
+)]
 pub async fn set_plant_amount_ml(
+    _: FrontendRateLimit,
     state: State<GlobalState>,
     Path(name): Path<String>,
     Query(SetAmountMlQuery { amount_ml }): Query<SetAmountMlQuery>,
-) -> (StatusCode, String) {
+    ClientIp(ip): ClientIp,
+) -> Result<String, ApiError> {
     info!("Setting plant amount to {}ml", amount_ml);
 
     if amount_ml > FRONTEND_ML_MAX {
@@ -53,94 +140,100 @@ pub async fn set_plant_amount_ml(
             "Request to water {}ml > {}ml received. Declined.",
             amount_ml, FRONTEND_ML_MAX
         );
-        return (
-            StatusCode::BAD_REQUEST,
-            format!("At most {}ml are allowed", FRONTEND_ML_MAX),
-        );
+        return Err(ApiError::bad_request(format!(
+            "At most {}ml are allowed",
+            FRONTEND_ML_MAX
+        )));
     }
 
-    let plants = state.config.get_plant_config();
-    if let Err(err) = plants {
-        error!("Error reading plant config");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Error reading config: {}", err),
-        );
-    }
-    let plants = plants.unwrap();
-    let plant = plants.iter().enumerate().find(|p| p.1.name == name);
-    match plant {
-        Some(plant) => match state.config.put_plant_amount_ml(plant.0, amount_ml as u32) {
-            Ok(_) => (
-                StatusCode::OK,
-                format!("Plant {} now gets {}ml/day", name, amount_ml),
-            ),
-            Err(e) => {
-                error!("Error saving config for amount update: {}", e);
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Error saving config: {}", e),
-                )
-            }
-        },
-        None => (StatusCode::NOT_FOUND, format!("Plant {} not found", name)),
-    }
+    let old = state.config.put_plant_amount_ml(&name, amount_ml as u32)?;
+    let change = Change::PlantAmountMl {
+        plant: name.clone(),
+        old,
+        new: amount_ml as u32,
+    };
+    record_change(&state, ip, change);
+    Ok(format!("Plant {} now gets {}ml/day", name, amount_ml))
 }
 
+/// Waters a plant with the next check-in of the ESP32.
+/// Answers once the ESP32 picked up the job, so the request can take minutes.
+#[utoipa::path(
+    post,
+    path = "/testwatering/{plantname}",
+    tag = "frontend",
+    params(
+        ("plantname" = String, Path, description = "Name configured in [[plants]]"),
+    ),
+    responses(
+        (status = 200, description = "The ESP32 picked up the job", body = String),
+        (status = 400, description = "Unknown plant", body = ErrorBody),
+        (status = 403, description = "Not sent from the network of the ESP32", body = ErrorBody),
+        (status = 410, description = "Replaced by another test or the plant was moved in the config", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+        (status = 503, description = "Server shutting down, the test stays queued", body = ErrorBody),
+    )
+)]
 pub async fn test_watering(
+    _: FrontendRateLimit,
     state: State<GlobalState>,
     plantname: Path<String>,
-    SecureClientIp(ip): SecureClientIp,
-) -> (StatusCode, String) {
+    ClientIp(ip): ClientIp,
+) -> Result<String, ApiError> {
     info!("Starting watering test...");
-    let esp32_ip = state.json_state.ensure_state().map(|s| s.last_ip);
-    if let Err(e) = esp32_ip {
-        error!("Could not read json state: {}", e);
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Could not read json state".into(),
-        );
-    }
-    let esp32_ip = esp32_ip.unwrap();
-
+    let esp32_ip = state.json_state.ensure_state()?.last_ip;
     if ip != esp32_ip {
         warn!("IP mismatch. Frontend: {}, ESP32: {}", ip, esp32_ip);
-        return (
-            StatusCode::FORBIDDEN,
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
             format!("Your IP {} must be the same as ESP32's", ip),
-        );
+        ));
     }
 
-    let plants = state.config.get_plant_config().unwrap();
-    let plant_index = plants
+    let plants = state.config.get_plant_config()?;
+    let Some((plant_index, plant)) = plants
         .iter()
         .enumerate()
-        .find(|(_, c)| c.name == plantname.as_ref());
-    if plant_index.is_none() {
+        .find(|(_, c)| c.name == plantname.as_ref())
+    else {
         info!("Plant {} not found", *plantname);
-        return (StatusCode::BAD_REQUEST, "Plant not found".into());
-    }
-    let plant_index = plant_index.unwrap();
+        return Err(ApiError::bad_request("Plant not found"));
+    };
     let watering_job = WateringJob {
-        plant_index: plant_index.0,
-        amount_ml: plant_index.1.amount_ml,
+        plant_index,
+        amount_ml: plant.amount_ml,
     };
-    let ack = state.pending_watering_test.set_pending_job(watering_job);
+    let ack = state
+        .pending_watering_test
+        .set_pending_job(plantname.0.clone(), watering_job);
+    let change = Change::WateringTest {
+        plant: plantname.0.clone(),
+    };
+    record_change(&state, ip, change);
     info!("Waiting now for the job to be picked up...");
     match ack.await.await {
+        Ok(Ack::ShuttingDown) => {
+            info!("Aborted test - server shutting down");
+            Err(ApiError::new(
+                ErrorCode::ShuttingDown,
+                "Server shutting down, the test stays queued until it is back",
+            ))
+        }
+        Ok(Ack::Refused) => Err(ApiError::new(
+            ErrorCode::ShuttingDown,
+            "Server shutting down, start the test again once it is back",
+        )),
         Err(_) => {
-            info!("Aborted test - another test startet");
-            (
-                StatusCode::GONE,
-                "Another testing job has been started".into(),
-            )
+            info!("Aborted test - another test startet or the plant was moved");
+            Err(ApiError::new(
+                ErrorCode::Gone,
+                "Another testing job has been started or the plant was moved in the config",
+            ))
         }
-        Ok(_) => {
+        Ok(Ack::Done) => {
             info!("ESP32 dequeued the job!");
-            (
-                StatusCode::OK,
-                format!("Plant {} should have been watered", *plantname),
-            )
+            Ok(format!("Plant {} should have been watered", *plantname))
         }
     }
 }
@@ -0,0 +1,273 @@
+use std::io::ErrorKind;
+
+use axum::{extract::State, http::StatusCode, Json};
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use log::warn;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    reservoir,
+    state::{JsonState, StateError},
+    GlobalState,
+};
+
+// Devices sleep until the next watering, which is at most a day away
+const DEVICE_STALE_HOURS: i64 = 26;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum HealthStatus {
+    Ok,
+    /// Not checked, e.g. devices while the state can't be read or no notifier.
+    Skipped,
+    /// Works, but needs attention.
+    Warn,
+    /// Broken, the server isn't ready.
+    Fail,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentHealth {
+    pub name: String,
+    pub status: HealthStatus,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    /// The worst status of all components, skipped ones don't count.
+    pub status: HealthStatus,
+    pub components: Vec<ComponentHealth>,
+}
+
+fn component(name: &str, status: HealthStatus, message: impl Into<String>) -> ComponentHealth {
+    ComponentHealth {
+        name: name.into(),
+        status,
+        message: message.into(),
+    }
+}
+
+/// The file on disk, the server keeps running with the last good config if it broke.
+fn check_config(state: &GlobalState) -> ComponentHealth {
+    match state.config.validate() {
+        Ok(problems) if problems.is_empty() => match state.config.get_plant_config() {
+            Ok(plants) => component(
+                "config",
+                HealthStatus::Ok,
+                format!("{} plants configured", plants.len()),
+            ),
+            Err(e) => component("config", HealthStatus::Fail, e.to_string()),
+        },
+        Ok(problems) => component(
+            "config",
+            HealthStatus::Warn,
+            format!(
+                "{}:{}{}, the last good config is used",
+                state.config.path().display(),
+                problems[0],
+                match problems.len() {
+                    1 => String::new(),
+                    n => format!(" and {} more problems", n - 1),
+                }
+            ),
+        ),
+        Err(e) => component("config", HealthStatus::Fail, e.to_string()),
+    }
+}
+
+fn check_state(state: &GlobalState) -> (ComponentHealth, Option<JsonState>) {
+    let json_state = match state.json_state.get() {
+        Ok(json_state) => Some(json_state),
+        Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            let message = format!("Can't read {}: {}", state.json_state.path().display(), e);
+            return (component("state", HealthStatus::Fail, message), None);
+        }
+    };
+    if let Err(e) = state.json_state.check_writable() {
+        let message = format!(
+            "Can't write next to {}: {}",
+            state.json_state.path().display(),
+            e
+        );
+        return (component("state", HealthStatus::Fail, message), json_state);
+    }
+    let message = match json_state {
+        Some(_) => "Readable and writable",
+        None => "Created on the first check-in",
+    };
+    (component("state", HealthStatus::Ok, message), json_state)
+}
+
+/// The webhook alerts go to, a broken one loses alerts but the server still works.
+/// Judged by the last alert only, probes don't contact the webhook.
+fn check_notifier(state: &GlobalState) -> ComponentHealth {
+    match state.config.get_notifier() {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return component(
+                "notifier",
+                HealthStatus::Skipped,
+                "No webhook configured, alerts are only logged",
+            )
+        }
+        // A broken config is reported by its own component
+        Err(_) => return component("notifier", HealthStatus::Skipped, "Config can't be read"),
+    };
+    match state.notifier.last_delivery() {
+        None => component(
+            "notifier",
+            HealthStatus::Ok,
+            "Webhook configured, no alert sent yet",
+        ),
+        Some(delivery) => match delivery.error {
+            None => component(
+                "notifier",
+                HealthStatus::Ok,
+                format!("Last alert delivered at {}", delivery.at),
+            ),
+            Some(e) => component(
+                "notifier",
+                HealthStatus::Warn,
+                format!("Last alert at {} was not delivered: {}", delivery.at, e),
+            ),
+        },
+    }
+}
+
+fn freshness(name: &str, last_seen: NaiveDateTime, now: NaiveDateTime) -> ComponentHealth {
+    let hours = (now - last_seen).num_hours();
+    match now - last_seen > ChronoDuration::hours(DEVICE_STALE_HOURS) {
+        true => component(
+            name,
+            HealthStatus::Warn,
+            format!(
+                "Last check-in {}h ago at {}, expected at least daily",
+                hours, last_seen
+            ),
+        ),
+        false => component(
+            name,
+            HealthStatus::Ok,
+            format!("Last check-in {}h ago at {}", hours, last_seen),
+        ),
+    }
+}
+
+/// One component per device, a device being offline doesn't make the server unready.
+fn check_devices(json_state: Option<&JsonState>, now: NaiveDateTime) -> Vec<ComponentHealth> {
+    let never = || vec![component("devices", HealthStatus::Warn, "No check-in yet")];
+    let Some(json_state) = json_state else {
+        return never();
+    };
+    if json_state.devices.is_empty() {
+        // Written before devices were tracked
+        return match json_state.last_seen == JsonState::default().last_seen {
+            true => never(),
+            false => vec![freshness("devices", json_state.last_seen, now)],
+        };
+    }
+    let mut devices: Vec<_> = json_state.devices.iter().collect();
+    devices.sort_by_key(|(id, _)| id.as_str());
+    devices
+        .into_iter()
+        .map(|(id, device)| freshness(&format!("device:{}", id), device.last_seen, now))
+        .collect()
+}
+
+/// One component per configured reservoir, low ones need a refill.
+fn check_reservoirs(state: &GlobalState, json_state: &JsonState) -> Vec<ComponentHealth> {
+    // A broken config is reported by its own component
+    let Ok(reservoirs) = state.config.get_reservoirs() else {
+        return Vec::new();
+    };
+    reservoirs
+        .iter()
+        .map(|(id, config)| {
+            let status = reservoir::status(json_state, id, config);
+            let name = format!("reservoir:{}", id);
+            let message = format!(
+                "About {}ml of {}ml left",
+                status.level_ml, status.capacity_ml
+            );
+            match (status.low, status.skip_jobs_when_low) {
+                (false, _) => component(&name, HealthStatus::Ok, message),
+                (true, false) => {
+                    component(&name, HealthStatus::Warn, format!("{}, refill it", message))
+                }
+                (true, true) => component(
+                    &name,
+                    HealthStatus::Warn,
+                    format!("{}, jobs are skipped until it is refilled", message),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Liveness, answers as long as the server handles requests.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    responses(
+        (status = 200, body = String),
+    )
+)]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness with a status and message per component.
+/// Only failing components make it answer 503, warnings are for humans.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, description = "A component failed", body = Readiness),
+    )
+)]
+pub async fn readyz(state: State<GlobalState>) -> (StatusCode, Json<Readiness>) {
+    let now = Local::now().naive_local();
+    let (state_health, json_state) = check_state(&state);
+    let (devices, reservoirs) = match (state_health.status, &json_state) {
+        (HealthStatus::Fail, None) => (
+            vec![component(
+                "devices",
+                HealthStatus::Skipped,
+                "Unknown, the state can't be read",
+            )],
+            Vec::new(),
+        ),
+        _ => (
+            check_devices(json_state.as_ref(), now),
+            check_reservoirs(&state, json_state.as_ref().unwrap_or(&JsonState::default())),
+        ),
+    };
+    let mut components = vec![check_config(&state), state_health, check_notifier(&state)];
+    components.extend(devices);
+    components.extend(reservoirs);
+
+    let status = components
+        .iter()
+        .map(|c| c.status)
+        .filter(|status| *status != HealthStatus::Skipped)
+        .max()
+        .unwrap_or(HealthStatus::Ok);
+    let code = match status {
+        HealthStatus::Fail => {
+            for c in components.iter().filter(|c| c.status == HealthStatus::Fail) {
+                warn!("Not ready, {} failed: {}", c.name, c.message);
+            }
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        _ => StatusCode::OK,
+    };
+    (code, Json(Readiness { status, components }))
+}
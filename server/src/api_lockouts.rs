@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use log::info;
+
+use crate::{admin::AdminAuth, error::ApiError, limits::Lockout, GlobalState};
+
+/// IPs which sent wrong secrets within the last day, locked out ones first.
+#[utoipa::path(
+    get,
+    path = "/admin/lockouts",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    responses(
+        (status = 200, body = Vec<Lockout>),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 429, description = "Locked out after wrong secrets", body = ErrorBody),
+    )
+)]
+pub async fn list_lockouts(_: AdminAuth, state: State<GlobalState>) -> Json<Vec<Lockout>> {
+    Json(state.limiter.lockouts())
+}
+
+/// Lifts the lockout of an IP and forgets its wrong secrets.
+#[utoipa::path(
+    delete,
+    path = "/admin/lockouts/{ip}",
+    tag = "admin",
+    security(("admin_secret" = [])),
+    params(("ip" = String, Path, description = "e.g. 192.168.1.20")),
+    responses(
+        (status = 200, body = String),
+        (status = 400, description = "Invalid IP", body = ErrorBody),
+        (status = 401, description = "Wrong admin secret", body = ErrorBody),
+        (status = 403, description = "Admin API disabled", body = ErrorBody),
+        (status = 404, description = "No wrong secrets from this IP", body = ErrorBody),
+        (status = 429, description = "Locked out after wrong secrets", body = ErrorBody),
+    )
+)]
+pub async fn lift_lockout(
+    _: AdminAuth,
+    state: State<GlobalState>,
+    Path(ip): Path<String>,
+) -> Result<String, ApiError> {
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return Err(ApiError::bad_request(format!("Invalid IP {:?}", ip)));
+    };
+    if !state.limiter.lift_lockout(ip) {
+        return Err(ApiError::not_found(format!("No wrong secrets from {}", ip)));
+    }
+    info!("Lifted lockout of {}", ip);
+    Ok(format!("Lifted lockout of {}", ip))
+}
@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use chrono::{Duration as ChronoDuration, Local};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    error::ApiError,
+    history::CheckInRecord,
+    limits::FrontendRateLimit,
+    network::{self, NetworkTrend, DEFAULT_DAYS, MAX_DAYS},
+    GlobalState,
+};
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct NetworkQuery {
+    /// Days back from today, 30 if missing, at most 365
+    days: Option<u32>,
+}
+
+/// WiFi diagnostics of a device per day, to tell whether it is too far from the router.
+#[utoipa::path(
+    get,
+    path = "/devices/{device_id}/network",
+    tag = "frontend",
+    params(
+        ("device_id" = String, Path, description = "ID the device checks in with"),
+        NetworkQuery,
+    ),
+    responses(
+        (status = 200, body = NetworkTrend),
+        (status = 400, description = "Invalid number of days", body = ErrorBody),
+        (status = 404, description = "Device never checked in", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "State or history can't be read", body = ErrorBody),
+    )
+)]
+pub async fn network_trend(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(device_id): Path<String>,
+    Query(NetworkQuery { days }): Query<NetworkQuery>,
+) -> Result<Json<NetworkTrend>, ApiError> {
+    let days = days.unwrap_or(DEFAULT_DAYS);
+    if days == 0 || days > MAX_DAYS {
+        return Err(ApiError::bad_request(format!(
+            "days must be between 1 and {}",
+            MAX_DAYS
+        )));
+    }
+    if !state.json_state.get()?.devices.contains_key(&device_id) {
+        return Err(ApiError::not_found(format!(
+            "Device {} never checked in",
+            device_id
+        )));
+    }
+
+    // Whole days, the first one shouldn't look like it had fewer check-ins
+    let since = (Local::now().date_naive() - ChronoDuration::days(i64::from(days) - 1))
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default();
+    let check_ins = state.history.read_since::<CheckInRecord>(since)?;
+    Ok(Json(network::trend(&device_id, &check_ins)))
+}
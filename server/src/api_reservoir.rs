@@ -0,0 +1,116 @@
+use std::io::ErrorKind;
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use chrono::Local;
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{
+    api_frontend::record_change,
+    audit::Change,
+    client_ip::ClientIp,
+    error::ApiError,
+    limits::FrontendRateLimit,
+    reservoir::{self, ReservoirStatus},
+    state::{JsonState, StateError},
+    GlobalState,
+};
+
+/// Estimated levels of all configured reservoirs, in config order.
+#[utoipa::path(
+    get,
+    path = "/reservoirs",
+    tag = "frontend",
+    responses(
+        (status = 200, body = Vec<ReservoirStatus>),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "Config or state can't be read", body = ErrorBody),
+    )
+)]
+pub async fn list_reservoirs(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+) -> Result<Json<Vec<ReservoirStatus>>, ApiError> {
+    let reservoirs = state.config.get_reservoirs()?;
+    let json_state = match state.json_state.get() {
+        Ok(json_state) => json_state,
+        Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => JsonState::default(),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Json(
+        reservoirs
+            .iter()
+            .map(|(device_id, config)| reservoir::status(&json_state, device_id, config))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[serde(rename_all = "camelCase")]
+#[into_params(parameter_in = Query)]
+pub struct RefillQuery {
+    /// Level after the refill, the capacity if missing
+    level_ml: Option<u32>,
+}
+
+/// Sets the estimated level after the reservoir of a device was filled up.
+#[utoipa::path(
+    post,
+    path = "/devices/{device_id}/reservoir/refill",
+    tag = "frontend",
+    params(
+        ("device_id" = String, Path, description = "ID the device checks in with"),
+        RefillQuery,
+    ),
+    responses(
+        (status = 200, body = ReservoirStatus),
+        (status = 400, description = "Level above the capacity", body = ErrorBody),
+        (status = 404, description = "No reservoir configured for the device", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, description = "Config or state can't be read or written", body = ErrorBody),
+    )
+)]
+pub async fn refill_reservoir(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(device_id): Path<String>,
+    Query(RefillQuery { level_ml }): Query<RefillQuery>,
+    ClientIp(ip): ClientIp,
+) -> Result<Json<ReservoirStatus>, ApiError> {
+    let Some(config) = state.config.get_reservoir(&device_id)? else {
+        return Err(ApiError::not_found(format!(
+            "No reservoir configured for {}",
+            device_id
+        )));
+    };
+    let level_ml = level_ml.unwrap_or(config.capacity_ml);
+    if level_ml > config.capacity_ml {
+        return Err(ApiError::bad_request(format!(
+            "{}ml are more than the capacity of {}ml",
+            level_ml, config.capacity_ml
+        )));
+    }
+
+    let now = Local::now().naive_local();
+    let (old_ml, alert, status) = state.json_state.update(|json_state| {
+        let (old_ml, alert) = reservoir::refill(json_state, &device_id, &config, level_ml, now);
+        (
+            old_ml,
+            alert,
+            reservoir::status(json_state, &device_id, &config),
+        )
+    })?;
+    if let Some(alert) = alert {
+        state.notifier.send(alert);
+    }
+    let change = Change::ReservoirRefill {
+        device_id,
+        old_ml,
+        new_ml: level_ml,
+    };
+    record_change(&state, ip, change);
+    Ok(Json(status))
+}
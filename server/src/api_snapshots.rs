@@ -0,0 +1,172 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use chrono::NaiveDateTime;
+use log::info;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    api_frontend::record_change, audit::Change, client_ip::ClientIp, config::PlantConfig,
+    error::ApiError, limits::FrontendRateLimit, snapshot::Snapshot, GlobalState,
+};
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created: NaiveDateTime,
+    pub plants: Vec<PlantConfig>,
+}
+
+/// A plant that differs between the config and a snapshot.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlantDiff {
+    /// Position in `[[plants]]`, which is what the schedule goes by.
+    pub index: usize,
+    /// Missing if the snapshot has more plants.
+    pub current: Option<PlantConfig>,
+    /// Missing if the snapshot has fewer plants.
+    pub snapshot: Option<PlantConfig>,
+}
+
+fn info_of(snapshot: Snapshot) -> Result<SnapshotInfo, ApiError> {
+    let plants = snapshot.plants()?;
+    Ok(SnapshotInfo {
+        name: snapshot.name,
+        created: snapshot.created,
+        plants,
+    })
+}
+
+fn get_snapshot(state: &GlobalState, name: &str) -> Result<Snapshot, ApiError> {
+    state
+        .snapshots
+        .get(name)?
+        .ok_or_else(|| ApiError::not_found(format!("Snapshot {} not found", name)))
+}
+
+/// Saved snapshots of the plants, oldest first.
+#[utoipa::path(
+    get,
+    path = "/snapshots",
+    tag = "frontend",
+    responses(
+        (status = 200, body = Vec<SnapshotInfo>),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn list_snapshots(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+) -> Result<Json<Vec<SnapshotInfo>>, ApiError> {
+    let snapshots = state.snapshots.list()?;
+    snapshots
+        .into_iter()
+        .map(info_of)
+        .collect::<Result<_, _>>()
+        .map(Json)
+}
+
+/// Saves the current plants with their comments, replacing a snapshot of the same name.
+#[utoipa::path(
+    post,
+    path = "/snapshots/{name}",
+    tag = "frontend",
+    params(
+        ("name" = String, Path, description = "Name of the snapshot, e.g. \"summer setup\""),
+    ),
+    responses(
+        (status = 200, body = SnapshotInfo),
+        (status = 400, description = "Invalid name", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn save_snapshot(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(name): Path<String>,
+) -> Result<Json<SnapshotInfo>, ApiError> {
+    let plants_toml = state.config.get_plants_toml()?;
+    let snapshot = state.snapshots.save(&name, plants_toml)?;
+    info!("Saved snapshot {}", name);
+    info_of(snapshot).map(Json)
+}
+
+/// Plants that applying the snapshot would change, empty if it matches the config.
+#[utoipa::path(
+    get,
+    path = "/snapshots/{name}/diff",
+    tag = "frontend",
+    params(
+        ("name" = String, Path, description = "Name of the snapshot"),
+    ),
+    responses(
+        (status = 200, body = Vec<PlantDiff>),
+        (status = 404, description = "Unknown snapshot", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn diff_snapshot(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<PlantDiff>>, ApiError> {
+    let snapshot = get_snapshot(&state, &name)?.plants()?;
+    let current = state.config.get_plant_config()?;
+    let diff = (0..current.len().max(snapshot.len()))
+        .map(|index| PlantDiff {
+            index,
+            current: current.get(index).cloned(),
+            snapshot: snapshot.get(index).cloned(),
+        })
+        .filter(|diff| diff.current != diff.snapshot)
+        .collect();
+    Ok(Json(diff))
+}
+
+/// Replaces the plants of the config by those of the snapshot, the rest of the file
+/// stays as it is. The config is validated first and written in one step.
+#[utoipa::path(
+    post,
+    path = "/snapshots/{name}/apply",
+    tag = "frontend",
+    params(
+        ("name" = String, Path, description = "Name of the snapshot"),
+    ),
+    responses(
+        (status = 200, body = String),
+        (status = 404, description = "Unknown snapshot", body = ErrorBody),
+        (status = 422, description = "Config would be invalid, e.g. duplicate plant names", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    )
+)]
+pub async fn apply_snapshot(
+    _: FrontendRateLimit,
+    state: State<GlobalState>,
+    Path(name): Path<String>,
+    ClientIp(ip): ClientIp,
+) -> Result<String, ApiError> {
+    let snapshot = get_snapshot(&state, &name)?;
+    let new = snapshot.plants()?;
+    let (old, old_toml) = state.config.put_plants_toml(&snapshot.plants_toml)?;
+    info!("Applied snapshot {}", name);
+    let count = new.len();
+    record_change(
+        &state,
+        ip,
+        Change::SnapshotApplied {
+            snapshot: name.clone(),
+            old,
+            new,
+            old_toml: Some(old_toml),
+        },
+    );
+    Ok(format!("Applied snapshot {}, {} plants", name, count))
+}
@@ -0,0 +1,63 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Writes a temporary file next to `path` and renames it over `path`,
+/// so readers and a crash in between see either the old or the new content.
+pub fn write(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_temp(path, content)?.persist()
+}
+
+/// A written temporary file, removed on drop unless it replaced its target.
+pub struct TempFile {
+    tmp_path: PathBuf,
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Renames the temporary file over its target.
+    pub fn persist(mut self) -> io::Result<()> {
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Writes the temporary file of [`write`] without replacing `path` yet,
+/// for replacing several files only once all of them are written.
+pub fn write_temp(path: &Path, content: &[u8]) -> io::Result<TempFile> {
+    // Replace the file a symlink points to, not the symlink
+    let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let Some(file_name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a file"));
+    };
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_file = TempFile {
+        tmp_path: path.with_file_name(tmp_name),
+        path,
+        persisted: false,
+    };
+
+    let mut file = File::create(&tmp_file.tmp_path)?;
+    // The config may hold secrets, keep its permissions
+    if let Ok(metadata) = fs::metadata(&tmp_file.path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(tmp_file)
+}
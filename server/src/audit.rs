@@ -0,0 +1,151 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::config::PlantConfig;
+
+pub const AUDIT_FILENAME: &str = "audit.jsonl";
+
+/// A change made through the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Change {
+    #[serde(rename_all = "camelCase")]
+    PlantAmountMl { plant: String, old: u32, new: u32 },
+    /// Queued for the next check-in, can't be reverted.
+    #[serde(rename_all = "camelCase")]
+    WateringTest { plant: String },
+    /// All plants replaced by those of a snapshot.
+    #[serde(rename_all = "camelCase")]
+    SnapshotApplied {
+        snapshot: String,
+        old: Vec<PlantConfig>,
+        new: Vec<PlantConfig>,
+        /// `plants` section before, with its comments. Missing in older entries.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        old_toml: Option<String>,
+    },
+    /// Estimated level set after filling up, can't be reverted.
+    #[serde(rename_all = "camelCase")]
+    ReservoirRefill {
+        device_id: String,
+        old_ml: u32,
+        new_ml: u32,
+    },
+    /// Config and state replaced by a backup, can't be reverted.
+    #[serde(rename_all = "camelCase")]
+    BackupRestored {
+        created: NaiveDateTime,
+        server_version: String,
+        files: usize,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: u64,
+    pub timestamp: NaiveDateTime,
+    // There are no user accounts, the address is all we know
+    #[schema(value_type = String)]
+    pub client_ip: IpAddr,
+    pub change: Change,
+    /// ID of the entry this one undid.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverts: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parsing error in line {0}: {1}")]
+    Parse(usize, serde_json::Error),
+    #[error("Serializing error: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+/// Append-only log of changes in `audit.jsonl` of the state directory,
+/// one JSON entry per line.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(AUDIT_FILENAME),
+            mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Held while a backup or restore handles the log, new entries wait.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read_entries(&self) -> Result<Vec<AuditEntry>, AuditError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut entries = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).map_err(|e| AuditError::Parse(index + 1, e))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// All entries, oldest first.
+    pub fn list(&self) -> Result<Vec<AuditEntry>, AuditError> {
+        let _guard = self.mutex.lock();
+        self.read_entries()
+    }
+
+    pub fn get(&self, id: u64) -> Result<Option<AuditEntry>, AuditError> {
+        Ok(self.list()?.into_iter().find(|e| e.id == id))
+    }
+
+    pub fn record(
+        &self,
+        client_ip: IpAddr,
+        change: Change,
+        reverts: Option<u64>,
+    ) -> Result<AuditEntry, AuditError> {
+        let _guard = self.mutex.lock();
+        let id = self.read_entries()?.last().map_or(1, |e| e.id + 1);
+        let entry = AuditEntry {
+            id,
+            timestamp: Local::now().naive_local(),
+            client_ip: client_ip.to_canonical(),
+            change,
+            reverts,
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        Ok(entry)
+    }
+}
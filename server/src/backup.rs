@@ -0,0 +1,703 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{ErrorKind, Read},
+    path::{Component, Path, PathBuf},
+    process::ExitCode,
+    sync::MutexGuard,
+};
+
+use chrono::{Local, NaiveDateTime};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::{
+    atomic_file,
+    audit::{AuditEntry, AuditLog, AUDIT_FILENAME},
+    cli::{BackupArgs, RestoreArgs},
+    config::{ConfigError, ConfigManager},
+    firmware::{
+        FirmwareManifest, FirmwareStore, FIRMWARE_DIRNAME, MANIFEST_FILENAME as FIRMWARE_MANIFEST,
+    },
+    history::{
+        CheckInRecord, HistoryStore, OutcomeRecord, Record, WateringRecord, HISTORY_DIRNAME,
+    },
+    snapshot::{Snapshot, SnapshotStore, SNAPSHOTS_FILENAME},
+    state::{JsonState, JsonStateManager, STATE_FILENAME},
+    BACKUP_MAX_BYTES,
+};
+
+/// Bumped when a server can no longer restore older backups the same way.
+pub const BACKUP_FORMAT: u32 = 1;
+const MANIFEST_PATH: &str = "manifest.json";
+const CONFIG_PATH: &str = "config/evergreen.toml";
+const STATE_PREFIX: &str = "state/";
+// Files and directories of the state directory that belong to the server,
+// which defaults to the working directory and can hold anything else.
+const STATE_FILES: [&str; 3] = [STATE_FILENAME, AUDIT_FILENAME, SNAPSHOTS_FILENAME];
+const STATE_DIRS: [&str; 2] = [HISTORY_DIRNAME, FIRMWARE_DIRNAME];
+// Archives are unpacked into memory. Most of a backup are firmware images,
+// which don't compress, so this leaves room for history and the rest.
+const MAX_UNPACKED_BYTES: u64 = 2 * BACKUP_MAX_BYTES as u64;
+
+/// A file of the archive, paths are relative to the archive.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// `manifest.json` of a backup archive.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupManifest {
+    pub format: u32,
+    /// Version of the server which wrote the backup.
+    pub server_version: String,
+    /// Newest protocol that server spoke with the ESP32.
+    pub protocol_version: u32,
+    pub created: NaiveDateTime,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Config error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("Serializing error: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Invalid backup: {0}")]
+    Invalid(String),
+}
+
+/// The stores with files in the state directory. Packing and replacing
+/// holds all their locks, so no request reads or writes them in between.
+#[derive(Clone)]
+pub struct StateStores {
+    pub json_state: JsonStateManager,
+    pub audit: AuditLog,
+    pub history: HistoryStore,
+    pub snapshots: SnapshotStore,
+    pub firmware: FirmwareStore,
+}
+
+impl StateStores {
+    /// Stores of their own, for commands running without the server.
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            json_state: JsonStateManager::new(state_dir),
+            audit: AuditLog::new(state_dir),
+            history: HistoryStore::new(state_dir),
+            snapshots: SnapshotStore::new(state_dir),
+            firmware: FirmwareStore::new(state_dir),
+        }
+    }
+
+    // Always in the same order. Requests take one lock at a time, so this can't deadlock
+    fn lock(&self) -> [MutexGuard<'_, ()>; 5] {
+        [
+            self.json_state.lock(),
+            self.audit.lock(),
+            self.history.lock(),
+            self.snapshots.lock(),
+            self.firmware.lock(),
+        ]
+    }
+}
+
+fn invalid(message: impl Into<String>) -> BackupError {
+    BackupError::Invalid(message.into())
+}
+
+pub fn file_name(created: NaiveDateTime) -> String {
+    format!(
+        "evergreen-backup-{}.tar.gz",
+        created.format("%Y%m%d-%H%M%S")
+    )
+}
+
+/// Paths of the server's files in the state directory, relative to it.
+fn state_files(state_dir: &Path) -> std::io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    for name in STATE_FILES {
+        if state_dir.join(name).is_file() {
+            files.push(name.to_string());
+        }
+    }
+    for dir in STATE_DIRS {
+        let entries = match fs::read_dir(state_dir.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            // Hidden files are leftovers of interrupted writes
+            match name.to_str() {
+                Some(name) if !name.starts_with('.') && entry.file_type()?.is_file() => {
+                    files.push(format!("{}/{}", dir, name))
+                }
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// Packs the config and the state directory into a `.tar.gz`.
+pub fn create(
+    config: &ConfigManager,
+    state_dir: &Path,
+    stores: &StateStores,
+) -> Result<(BackupManifest, Vec<u8>), BackupError> {
+    let mut contents = vec![(CONFIG_PATH.to_string(), config.get_raw()?.into_bytes())];
+    let locks = stores.lock();
+    for path in state_files(state_dir)? {
+        let content = fs::read(state_dir.join(&path))?;
+        contents.push((format!("{}{}", STATE_PREFIX, path), content));
+    }
+    drop(locks);
+    let unpacked: u64 = contents.iter().map(|(_, c)| c.len() as u64).sum();
+    if unpacked > MAX_UNPACKED_BYTES {
+        warn!(
+            "Backup unpacks to {} MiB, restoring it needs a server accepting more than {} MiB",
+            unpacked / 1024 / 1024,
+            MAX_UNPACKED_BYTES / 1024 / 1024
+        );
+    }
+    let manifest = BackupManifest {
+        format: BACKUP_FORMAT,
+        server_version: env!("CARGO_PKG_VERSION").into(),
+        protocol_version: protocol::PROTOCOL_VERSION,
+        created: Local::now().naive_local(),
+        files: contents
+            .iter()
+            .map(|(path, content)| BackupFile {
+                path: path.clone(),
+                size: content.len() as u64,
+                sha256: sha256_hex(content),
+            })
+            .collect(),
+    };
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mtime = manifest.created.and_utc().timestamp().max(0) as u64;
+    for (path, content) in std::iter::once((MANIFEST_PATH, &manifest_json)).chain(
+        contents
+            .iter()
+            .map(|(path, content)| (path.as_str(), content)),
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        // The config may hold secrets
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        archive.append_data(&mut header, path, content.as_slice())?;
+    }
+    let archive = archive.into_inner()?.finish()?;
+    Ok((manifest, archive))
+}
+
+/// Unpacks the regular files of a `.tar.gz` into memory.
+fn unpack(archive: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, BackupError> {
+    let not_an_archive = |e: std::io::Error| invalid(format!("Not a .tar.gz archive: {}", e));
+    let mut files = BTreeMap::new();
+    let mut unpacked = 0;
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    for entry in archive.entries().map_err(not_an_archive)? {
+        let mut entry = entry.map_err(not_an_archive)?;
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+        let path = entry.path().map_err(not_an_archive)?.into_owned();
+        let name = path
+            .to_str()
+            .filter(|_| path.components().all(|c| matches!(c, Component::Normal(_))))
+            .ok_or_else(|| invalid(format!("Invalid path {}", path.display())))?
+            .to_string();
+        if !entry_type.is_file() {
+            return Err(invalid(format!("{} is not a regular file", name)));
+        }
+        unpacked += entry.size();
+        if unpacked > MAX_UNPACKED_BYTES {
+            return Err(invalid(format!(
+                "Archive unpacks to more than {} MiB",
+                MAX_UNPACKED_BYTES / 1024 / 1024
+            )));
+        }
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).map_err(not_an_archive)?;
+        if files.insert(name.clone(), content).is_some() {
+            return Err(invalid(format!("{} is in the archive twice", name)));
+        }
+    }
+    Ok(files)
+}
+
+fn check_json<T: DeserializeOwned>(content: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(content).map_err(|e| e.to_string())
+}
+
+fn check_json_lines<T: DeserializeOwned>(content: &[u8]) -> Result<(), String> {
+    let text = std::str::from_utf8(content).map_err(|e| e.to_string())?;
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        serde_json::from_str::<T>(line).map_err(|e| format!("line {}: {}", index + 1, e))?;
+    }
+    Ok(())
+}
+
+fn check_history<R: Record>(name: &str, content: &[u8]) -> Option<Result<(), String>> {
+    (name == R::FILENAME).then(|| check_json_lines::<R>(content))
+}
+
+/// Checks that a file of the state directory is one the server would read and that it parses.
+fn check_state_file(path: &str, content: &[u8]) -> Result<(), String> {
+    match path.split_once('/') {
+        None if path == STATE_FILENAME => check_json::<JsonState>(content).map(|_| ()),
+        None if path == AUDIT_FILENAME => check_json_lines::<AuditEntry>(content),
+        None if path == SNAPSHOTS_FILENAME => {
+            for snapshot in check_json::<Vec<Snapshot>>(content)? {
+                snapshot.plants().map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+        Some((HISTORY_DIRNAME, name)) => check_history::<CheckInRecord>(name, content)
+            .or_else(|| check_history::<WateringRecord>(name, content))
+            .or_else(|| check_history::<OutcomeRecord>(name, content))
+            .unwrap_or_else(|| Err("Unknown history file".into())),
+        Some((FIRMWARE_DIRNAME, FIRMWARE_MANIFEST)) => {
+            check_json::<FirmwareManifest>(content).map(|_| ())
+        }
+        Some((FIRMWARE_DIRNAME, name)) if name.ends_with(".bin") && !name.starts_with('.') => {
+            Ok(())
+        }
+        _ => Err("Not part of the server's state".into()),
+    }
+}
+
+/// Content of an archive that passed all checks.
+struct CheckedBackup {
+    manifest: BackupManifest,
+    config: String,
+    /// By path relative to the state directory.
+    state: BTreeMap<String, Vec<u8>>,
+}
+
+/// Checks the whole archive, so nothing is replaced by a broken backup.
+fn check(config: &ConfigManager, archive: &[u8]) -> Result<CheckedBackup, BackupError> {
+    let mut files = unpack(archive)?;
+    let manifest: BackupManifest = check_json(
+        &files
+            .remove(MANIFEST_PATH)
+            .ok_or_else(|| invalid("No manifest.json"))?,
+    )
+    .map_err(|e| invalid(format!("manifest.json: {}", e)))?;
+    if manifest.format > BACKUP_FORMAT {
+        return Err(invalid(format!(
+            "Written by server {} in format {}, this server reads up to format {}",
+            manifest.server_version, manifest.format, BACKUP_FORMAT
+        )));
+    }
+
+    for file in &manifest.files {
+        let content = files
+            .get(&file.path)
+            .ok_or_else(|| invalid(format!("{} is missing", file.path)))?;
+        if content.len() as u64 != file.size || sha256_hex(content) != file.sha256 {
+            return Err(invalid(format!("{} is damaged", file.path)));
+        }
+    }
+    if let Some(path) = files
+        .keys()
+        .find(|path| !manifest.files.iter().any(|f| &f.path == *path))
+    {
+        return Err(invalid(format!("{} is not in the manifest", path)));
+    }
+
+    let raw_config = String::from_utf8(
+        files
+            .remove(CONFIG_PATH)
+            .ok_or_else(|| invalid(format!("{} is missing", CONFIG_PATH)))?,
+    )
+    .map_err(|e| invalid(format!("{}: {}", CONFIG_PATH, e)))?;
+    let problems = config.validate_raw(&raw_config);
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems
+            .iter()
+            .map(|p| format!("{}:{}", CONFIG_PATH, p))
+            .collect();
+        return Err(invalid(format!(
+            "Config is invalid here:\n{}",
+            problems.join("\n")
+        )));
+    }
+
+    let mut state = BTreeMap::new();
+    for (path, content) in files {
+        let relative = path
+            .strip_prefix(STATE_PREFIX)
+            .ok_or_else(|| invalid(format!("{} is not part of a backup", path)))?;
+        check_state_file(relative, &content).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        state.insert(relative.to_string(), content);
+    }
+    Ok(CheckedBackup {
+        manifest,
+        config: raw_config,
+        state,
+    })
+}
+
+/// Replaces config and state by those of the archive, after checking all of it.
+/// Files of the server that are not in the backup are deleted.
+///
+/// Requests wait while the files are replaced. A request that read
+/// `state.json` before and writes it after still overwrites the restored one,
+/// so a running server should restore while the device sleeps.
+pub fn restore(
+    config: &ConfigManager,
+    state_dir: &Path,
+    stores: &StateStores,
+    archive: &[u8],
+) -> Result<BackupManifest, BackupError> {
+    let CheckedBackup {
+        manifest,
+        config: raw_config,
+        state,
+    } = check(config, archive)?;
+
+    let _locks = stores.lock();
+    // All files are written before the first is replaced, a full disk leaves everything
+    // as it was. Temporary files are removed on errors.
+    let mut tmp_files = Vec::with_capacity(state.len());
+    for (path, content) in &state {
+        let path = state_dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        tmp_files.push(atomic_file::write_temp(&path, content)?);
+    }
+    config.put_raw(&raw_config)?;
+    for tmp_file in tmp_files {
+        tmp_file.persist()?;
+    }
+    for path in state_files(state_dir)? {
+        if !state.contains_key(&path) {
+            fs::remove_file(state_dir.join(path))?;
+        }
+    }
+    Ok(manifest)
+}
+
+pub fn backup_command(
+    configmanager: ConfigManager,
+    state_dir: &Path,
+    args: BackupArgs,
+) -> ExitCode {
+    let stores = StateStores::new(state_dir);
+    let (manifest, archive) = match create(&configmanager, state_dir, &stores) {
+        Ok(backup) => backup,
+        Err(err) => {
+            eprintln!("Could not create backup.\n{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let output = args
+        .output
+        .unwrap_or_else(|| PathBuf::from(file_name(manifest.created)));
+    if let Err(err) = fs::write(&output, archive) {
+        eprintln!("Could not write {}.\n{}", output.display(), err);
+        return ExitCode::FAILURE;
+    }
+    println!(
+        "Wrote {} files to {}.",
+        manifest.files.len(),
+        output.display()
+    );
+    ExitCode::SUCCESS
+}
+
+pub fn restore_command(
+    configmanager: ConfigManager,
+    state_dir: &Path,
+    args: RestoreArgs,
+) -> ExitCode {
+    let archive = match fs::read(&args.archive) {
+        Ok(archive) => archive,
+        Err(err) => {
+            eprintln!("Could not read {}.\n{}", args.archive.display(), err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let stores = StateStores::new(state_dir);
+    match restore(&configmanager, state_dir, &stores, &archive) {
+        Ok(manifest) => {
+            println!(
+                "Restored {} files of the backup from {} (server {}).",
+                manifest.files.len(),
+                manifest.created,
+                manifest.server_version
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Could not restore {}.\n{}", args.archive.display(), err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::config::EnvOverrides;
+
+    use super::*;
+
+    const CONFIG: &str = r#"api_secret = "secret"
+
+[[plants]]
+name = "Karsten"
+amountMl = 100
+"#;
+
+    /// Config and state directory of their own, removed on drop.
+    struct TempServer {
+        dir: PathBuf,
+        config: ConfigManager,
+    }
+
+    impl TempServer {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "evergreen-backup-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(dir.join("state")).unwrap();
+            let path = dir.join("evergreen.toml");
+            fs::write(&path, "api_secret = \"old\"\n").unwrap();
+            let config = ConfigManager::new(path, None, EnvOverrides::default());
+            Self { dir, config }
+        }
+
+        fn state_dir(&self) -> PathBuf {
+            self.dir.join("state")
+        }
+
+        fn rejected(&self, archive: &[u8]) -> String {
+            match check(&self.config, archive) {
+                Err(BackupError::Invalid(message)) => message,
+                Err(e) => panic!("Unexpected error: {}", e),
+                Ok(_) => panic!("Archive was accepted"),
+            }
+        }
+    }
+
+    impl Drop for TempServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Paths are written as they are, `tar::Builder` would refuse `..`.
+    fn pack(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o600);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            archive.append(&header, *content).unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn manifest(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let manifest = BackupManifest {
+            format: BACKUP_FORMAT,
+            server_version: env!("CARGO_PKG_VERSION").into(),
+            protocol_version: protocol::PROTOCOL_VERSION,
+            created: Local::now().naive_local(),
+            files: files
+                .iter()
+                .map(|(path, content)| BackupFile {
+                    path: path.to_string(),
+                    size: content.len() as u64,
+                    sha256: sha256_hex(content),
+                })
+                .collect(),
+        };
+        serde_json::to_vec(&manifest).unwrap()
+    }
+
+    /// A backup with a matching manifest.
+    fn backup(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let manifest = manifest(files);
+        let mut entries = vec![(MANIFEST_PATH, manifest.as_slice())];
+        entries.extend_from_slice(files);
+        pack(&entries)
+    }
+
+    #[test]
+    fn restore_replaces_all_state() {
+        let server = TempServer::new("restore");
+        let state_dir = server.state_dir();
+        fs::write(state_dir.join(AUDIT_FILENAME), "").unwrap();
+        fs::write(state_dir.join(STATE_FILENAME), "{}").unwrap();
+        let state = serde_json::to_vec(&JsonState::default()).unwrap();
+        let archive = backup(&[
+            (CONFIG_PATH, CONFIG.as_bytes()),
+            ("state/state.json", &state),
+            ("state/history/waterings.jsonl", b""),
+        ]);
+
+        let stores = StateStores::new(&state_dir);
+        restore(&server.config, &state_dir, &stores, &archive).unwrap();
+        assert_eq!(server.config.get_raw().unwrap(), CONFIG);
+        assert_eq!(fs::read(state_dir.join(STATE_FILENAME)).unwrap(), state);
+        assert_eq!(
+            state_files(&state_dir).unwrap(),
+            ["history/waterings.jsonl", STATE_FILENAME]
+        );
+        let leftovers: Vec<_> = fs::read_dir(&state_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with('.'))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn broken_backups_change_nothing() {
+        let server = TempServer::new("broken");
+        let state_dir = server.state_dir();
+        fs::write(state_dir.join(STATE_FILENAME), "{}").unwrap();
+        let archive = backup(&[
+            (CONFIG_PATH, CONFIG.as_bytes()),
+            ("state/state.json", b"not json"),
+        ]);
+
+        let stores = StateStores::new(&state_dir);
+        assert!(restore(&server.config, &state_dir, &stores, &archive).is_err());
+        assert_eq!(server.config.get_raw().unwrap(), "api_secret = \"old\"\n");
+        assert_eq!(fs::read(state_dir.join(STATE_FILENAME)).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn paths_leaving_the_archive_are_rejected() {
+        let server = TempServer::new("paths");
+        for path in ["../evergreen.toml", "/etc/passwd", "state/../state.json"] {
+            let archive = backup(&[(CONFIG_PATH, CONFIG.as_bytes()), (path, b"{}")]);
+            assert_eq!(server.rejected(&archive), format!("Invalid path {}", path));
+        }
+    }
+
+    #[test]
+    fn duplicate_entries_are_rejected() {
+        let server = TempServer::new("duplicate");
+        let archive = backup(&[
+            (CONFIG_PATH, CONFIG.as_bytes()),
+            (CONFIG_PATH, CONFIG.as_bytes()),
+        ]);
+        assert_eq!(
+            server.rejected(&archive),
+            format!("{} is in the archive twice", CONFIG_PATH)
+        );
+    }
+
+    #[test]
+    fn files_not_matching_the_manifest_are_rejected() {
+        let server = TempServer::new("manifest");
+        let damaged = format!("{} is damaged", CONFIG_PATH);
+        // Same size, other content
+        let manifest_json = manifest(&[(CONFIG_PATH, CONFIG.to_uppercase().as_bytes())]);
+        let archive = pack(&[
+            (MANIFEST_PATH, manifest_json.as_slice()),
+            (CONFIG_PATH, CONFIG.as_bytes()),
+        ]);
+        assert_eq!(server.rejected(&archive), damaged);
+
+        let truncated = &CONFIG.as_bytes()[..10];
+        let manifest_json = manifest(&[(CONFIG_PATH, CONFIG.as_bytes())]);
+        let archive = pack(&[
+            (MANIFEST_PATH, manifest_json.as_slice()),
+            (CONFIG_PATH, truncated),
+        ]);
+        assert_eq!(server.rejected(&archive), damaged);
+
+        let manifest_json = manifest(&[(CONFIG_PATH, CONFIG.as_bytes())]);
+        let archive = pack(&[
+            (MANIFEST_PATH, manifest_json.as_slice()),
+            (CONFIG_PATH, CONFIG.as_bytes()),
+            ("state/state.json", b"{}"),
+        ]);
+        assert_eq!(
+            server.rejected(&archive),
+            "state/state.json is not in the manifest"
+        );
+    }
+
+    #[test]
+    fn unknown_state_files_are_rejected() {
+        let server = TempServer::new("unknown");
+        for (path, error) in [
+            ("state/notes.txt", "Not part of the server's state"),
+            ("state/history/notes.jsonl", "Unknown history file"),
+            (
+                "state/firmware/.image.bin",
+                "Not part of the server's state",
+            ),
+        ] {
+            let archive = backup(&[(CONFIG_PATH, CONFIG.as_bytes()), (path, b"")]);
+            assert_eq!(server.rejected(&archive), format!("{}: {}", path, error));
+        }
+        let archive = backup(&[(CONFIG_PATH, CONFIG.as_bytes()), ("notes.txt", b"")]);
+        assert_eq!(
+            server.rejected(&archive),
+            "notes.txt is not part of a backup"
+        );
+    }
+
+    #[test]
+    fn archives_unpacking_too_large_are_rejected() {
+        let server = TempServer::new("too-large");
+        // Only the header claims the size, the cap applies before reading the content
+        let mut header = tar::Header::new_gnu();
+        header.set_path("state/firmware/image.bin").unwrap();
+        header.set_size(MAX_UNPACKED_BYTES + 1);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        archive.append(&header, io::empty()).unwrap();
+        let archive = archive.into_inner().unwrap().finish().unwrap();
+        assert_eq!(
+            server.rejected(&archive),
+            format!(
+                "Archive unpacks to more than {} MiB",
+                MAX_UNPACKED_BYTES / 1024 / 1024
+            )
+        );
+    }
+}
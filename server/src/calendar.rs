@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use chrono::{Duration as ChronoDuration, NaiveDateTime, Utc};
+use protocol::WateringStatus;
+
+use crate::{
+    history::{OutcomeRecord, WateringRecord},
+    simulate::SimulatedCheckIn,
+};
+
+pub const UPCOMING_DAYS: u32 = 30;
+pub const PAST_DAYS: i64 = 90;
+// Waterings take seconds, but calendars hide shorter events
+const EVENT_MINUTES: u32 = 15;
+// RFC 5545 limit, longer lines are folded
+const MAX_LINE_OCTETS: usize = 75;
+
+/// A handed out watering job and what the device reported about it.
+pub struct PastWatering {
+    pub watering: WateringRecord,
+    pub outcome: Option<OutcomeRecord>,
+}
+
+/// Pairs every outcome with the latest job for the same device and plant before it.
+/// Jobs without a report, e.g. because of legacy firmware, keep `None`.
+pub fn match_outcomes(
+    waterings: Vec<WateringRecord>,
+    outcomes: Vec<OutcomeRecord>,
+) -> Vec<PastWatering> {
+    let mut past: Vec<PastWatering> = Vec::with_capacity(waterings.len());
+    let mut open: HashMap<(String, usize), usize> = HashMap::new();
+    let mut outcomes = outcomes.into_iter().peekable();
+    for watering in waterings {
+        while let Some(outcome) = outcomes.next_if(|o| o.timestamp < watering.timestamp) {
+            attach(&mut past, &mut open, outcome);
+        }
+        open.insert(
+            (watering.device_id.clone(), watering.plant_index),
+            past.len(),
+        );
+        past.push(PastWatering {
+            watering,
+            outcome: None,
+        });
+    }
+    for outcome in outcomes {
+        attach(&mut past, &mut open, outcome);
+    }
+    past
+}
+
+fn attach(
+    past: &mut [PastWatering],
+    open: &mut HashMap<(String, usize), usize>,
+    outcome: OutcomeRecord,
+) {
+    if let Some(index) = open.remove(&(outcome.device_id.clone(), outcome.plant_index)) {
+        past[index].outcome = Some(outcome);
+    }
+}
+
+/// Escapes TEXT values, RFC 5545 section 3.3.11. Line breaks of any kind become
+/// `\n`, a raw CR would end the content line.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\n")
+        .replace(['\r', '\n'], "\\n")
+}
+
+/// Floating local time, calendars show it in their own time zone.
+fn format_local(time: NaiveDateTime) -> String {
+    time.format("%Y%m%dT%H%M%S").to_string()
+}
+
+/// Adds a content line, folded after 75 octets without splitting characters.
+fn push_line(ics: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            ics.push_str("\r\n ");
+            // The leading space counts
+            octets = 1;
+        }
+        ics.push(c);
+        octets += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+struct Event {
+    uid: String,
+    start: NaiveDateTime,
+    summary: String,
+    description: String,
+    confirmed: bool,
+}
+
+fn push_event(ics: &mut String, dtstamp: &str, event: &Event) {
+    push_line(ics, "BEGIN:VEVENT");
+    push_line(ics, &format!("UID:{}", event.uid));
+    push_line(ics, &format!("DTSTAMP:{}", dtstamp));
+    push_line(ics, &format!("DTSTART:{}", format_local(event.start)));
+    push_line(ics, &format!("DURATION:PT{}M", EVENT_MINUTES));
+    push_line(ics, &format!("SUMMARY:{}", escape(&event.summary)));
+    push_line(ics, &format!("DESCRIPTION:{}", escape(&event.description)));
+    push_line(
+        ics,
+        match event.confirmed {
+            true => "STATUS:CONFIRMED",
+            false => "STATUS:TENTATIVE",
+        },
+    );
+    push_line(ics, "END:VEVENT");
+}
+
+fn past_event(past: &PastWatering) -> Event {
+    let watering = &past.watering;
+    let test = if watering.test { " (test)" } else { "" };
+    let (summary, description) = match &past.outcome {
+        None => (
+            format!("Water {}{}", watering.plant, test),
+            format!(
+                "{}ml handed out to {}, no report received.",
+                watering.amount_ml, watering.device_id
+            ),
+        ),
+        Some(outcome) if outcome.status == WateringStatus::Done => (
+            format!("Watered {}{}", watering.plant, test),
+            format!(
+                "{}ml of {}ml in {:.1}s by {}.",
+                outcome.watered_ml,
+                outcome.requested_ml,
+                outcome.duration_ms as f64 / 1000.0,
+                watering.device_id
+            ),
+        ),
+        Some(outcome) => (
+            format!("Watering {} failed{}", watering.plant, test),
+            format!(
+                "{:?}, {}ml of {}ml by {}.",
+                outcome.status, outcome.watered_ml, outcome.requested_ml, watering.device_id
+            ),
+        ),
+    };
+    Event {
+        uid: format!(
+            "watering-{}-{}-{}@evergreen",
+            format_local(watering.timestamp),
+            watering.device_id,
+            watering.plant_index
+        ),
+        start: watering.timestamp,
+        summary,
+        description,
+        confirmed: true,
+    }
+}
+
+/// Past waterings and the ones the schedule will hand out, one event per plant.
+pub fn feed(past: &[PastWatering], planned: &[SimulatedCheckIn]) -> String {
+    let dtstamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut ics = String::new();
+    push_line(&mut ics, "BEGIN:VCALENDAR");
+    push_line(&mut ics, "VERSION:2.0");
+    push_line(&mut ics, "PRODID:-//Evergreen 5000//Watering//EN");
+    push_line(&mut ics, "CALSCALE:GREGORIAN");
+    push_line(&mut ics, "X-WR-CALNAME:Evergreen 5000");
+
+    for past in past {
+        push_event(&mut ics, &dtstamp, &past_event(past));
+    }
+    for check_in in planned {
+        for job in &check_in.watering_jobs {
+            let event = Event {
+                // Stable across refreshes, so calendars update instead of duplicating
+                uid: format!(
+                    "planned-{}-{}@evergreen",
+                    check_in.time.format("%Y%m%d"),
+                    job.plant_index
+                ),
+                start: check_in.time,
+                summary: format!("Water {} ({}ml)", job.plant_name, job.amount_ml),
+                description: "Planned, the device waters on its first check-in after this time."
+                    .into(),
+                confirmed: false,
+            };
+            push_event(&mut ics, &dtstamp, &event);
+        }
+    }
+    push_line(&mut ics, "END:VCALENDAR");
+    ics
+}
+
+/// Start of the past shown in the feed.
+pub fn past_since(now: NaiveDateTime) -> NaiveDateTime {
+    now - ChronoDuration::days(PAST_DAYS)
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn watering(time: NaiveDateTime, device_id: &str, plant_index: usize) -> WateringRecord {
+        WateringRecord {
+            timestamp: time,
+            device_id: device_id.into(),
+            plant_index,
+            plant: format!("Plant {}", plant_index),
+            amount_ml: 100,
+            test: false,
+        }
+    }
+
+    fn outcome(time: NaiveDateTime, device_id: &str, plant_index: usize) -> OutcomeRecord {
+        OutcomeRecord {
+            timestamp: time,
+            device_id: device_id.into(),
+            plant_index,
+            plant: format!("Plant {}", plant_index),
+            requested_ml: 100,
+            watered_ml: 100,
+            duration_ms: 5000,
+            status: WateringStatus::Done,
+        }
+    }
+
+    fn outcome_times(past: &[PastWatering]) -> Vec<Option<NaiveDateTime>> {
+        past.iter()
+            .map(|p| p.outcome.as_ref().map(|o| o.timestamp))
+            .collect()
+    }
+
+    #[test]
+    fn escape_text() {
+        assert_eq!(escape("a\\b;c,d"), "a\\\\b\\;c\\,d");
+        assert_eq!(
+            escape("one\ntwo\r\nthree\rfour"),
+            "one\\ntwo\\nthree\\nfour"
+        );
+    }
+
+    #[test]
+    fn short_lines_are_not_folded() {
+        let mut ics = String::new();
+        push_line(&mut ics, "SUMMARY:Water Karsten");
+        assert_eq!(ics, "SUMMARY:Water Karsten\r\n");
+    }
+
+    #[test]
+    fn long_lines_are_folded_after_75_octets() {
+        let mut ics = String::new();
+        push_line(&mut ics, &"a".repeat(75 + 74 + 1));
+        assert_eq!(
+            ics,
+            format!("{}\r\n {}\r\n {}\r\n", "a".repeat(75), "a".repeat(74), "a")
+        );
+    }
+
+    #[test]
+    fn folding_does_not_split_characters() {
+        let mut ics = String::new();
+        // 74 octets, the next ü would end at 76
+        push_line(&mut ics, &format!("{}üü", "a".repeat(74)));
+        assert_eq!(ics, format!("{}\r\n üü\r\n", "a".repeat(74)));
+        for line in ics.split("\r\n") {
+            assert!(line.len() <= MAX_LINE_OCTETS);
+        }
+    }
+
+    #[test]
+    fn outcomes_pair_with_the_latest_job_before_them() {
+        let waterings = vec![
+            watering(at(9, 0), "balcony", 0),
+            watering(at(9, 0), "balcony", 1),
+            watering(at(9, 0), "kitchen", 0),
+            watering(at(10, 0), "balcony", 0),
+        ];
+        let outcomes = vec![
+            outcome(at(9, 1), "balcony", 1),
+            outcome(at(9, 2), "kitchen", 0),
+            outcome(at(10, 1), "balcony", 0),
+        ];
+        let past = match_outcomes(waterings, outcomes);
+        assert_eq!(
+            outcome_times(&past),
+            [None, Some(at(9, 1)), Some(at(9, 2)), Some(at(10, 1))]
+        );
+    }
+
+    #[test]
+    fn unmatched_outcomes_are_dropped() {
+        let waterings = vec![watering(at(9, 0), "balcony", 0)];
+        let outcomes = vec![
+            // Before any job, e.g. the job is older than the feed
+            outcome(at(8, 0), "balcony", 0),
+            outcome(at(9, 1), "balcony", 0),
+            // A second report doesn't replace the first
+            outcome(at(9, 2), "balcony", 0),
+            outcome(at(9, 3), "kitchen", 0),
+        ];
+        let past = match_outcomes(waterings, outcomes);
+        assert_eq!(outcome_times(&past), [Some(at(9, 1))]);
+    }
+}
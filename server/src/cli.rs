@@ -0,0 +1,93 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::config::DEFAULT_CONFIG_PATH;
+
+/// Server of the Evergreen 5000 watering system.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path of the TOML configuration.
+    #[arg(long, global = true, default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    /// Directory containing state.json and uploaded firmware.
+    #[arg(long, global = true, default_value = ".")]
+    pub state_dir: PathBuf,
+
+    /// Address to listen on, overrides host and port of the configuration.
+    #[arg(long, global = true)]
+    pub bind: Option<SocketAddr>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run the HTTP server (default).
+    Serve,
+    /// Check configuration and state, then exit.
+    Validate,
+    /// Print the configuration with all defaults and overrides applied.
+    PrintConfig,
+    /// Replay device check-ins without touching the state and print the schedule.
+    Simulate(SimulateArgs),
+    /// Print the OpenAPI specification, e.g. to generate an API client.
+    Openapi,
+    /// Write config, state, history and audit log into one archive.
+    Backup(BackupArgs),
+    /// Replace config and state by those of a backup, after checking all of it.
+    Restore(RestoreArgs),
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct BackupArgs {
+    /// File to write, defaults to evergreen-backup-<time>.tar.gz in the working directory.
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct RestoreArgs {
+    /// Archive written by `backup` or downloaded from /admin/backup.
+    pub archive: PathBuf,
+}
+
+#[derive(Args, Debug, Clone, Copy, PartialEq)]
+pub struct SimulateArgs {
+    /// First check-in, e.g. 2024-05-01 or 2024-05-01T08:30. Defaults to now.
+    #[arg(long, value_parser = parse_start)]
+    pub start: Option<NaiveDateTime>,
+
+    /// Number of days to simulate.
+    #[arg(long, default_value_t = 7)]
+    pub days: u32,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// Accepts a date, which starts at midnight, or a date and time.
+fn parse_start(s: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).expect("Invalid hms"));
+    }
+    [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+    .ok_or_else(|| format!("{:?} is neither YYYY-MM-DD nor YYYY-MM-DDTHH:MM[:SS]", s))
+}
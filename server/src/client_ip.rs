@@ -0,0 +1,299 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+
+/// Where the address of the client is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpSource {
+    /// Address of the connection, without a proxy in front.
+    Peer,
+    /// Set by Nginx via `proxy_set_header X-Real-IP $remote_addr`.
+    #[default]
+    XRealIp,
+    /// Rightmost address not belonging to a trusted proxy.
+    XForwardedFor,
+    /// RFC 7239, rightmost `for=` not belonging to a trusted proxy.
+    Forwarded,
+}
+
+/// Added to the router as extension, used by [`ClientIp`].
+#[derive(Debug, Clone)]
+pub struct ClientIpConfig {
+    pub source: ClientIpSource,
+    // Headers are only believed if the peer is one of these
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+impl ClientIpConfig {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client's address, the peer's if the headers can't be trusted.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        // IPv4 peers of dual stack sockets look like ::ffff:1.2.3.4
+        let peer = peer.to_canonical();
+        if self.source == ClientIpSource::Peer || !self.is_trusted(peer) {
+            return peer;
+        }
+        let from_header = match self.source {
+            ClientIpSource::Peer => None,
+            ClientIpSource::XRealIp => header_values(headers, "x-real-ip")
+                .last()
+                .and_then(parse_ip),
+            ClientIpSource::XForwardedFor => {
+                let hops: Vec<&str> = header_values(headers, "x-forwarded-for")
+                    .flat_map(|v| v.split(','))
+                    .collect();
+                self.rightmost_untrusted(&hops)
+            }
+            ClientIpSource::Forwarded => {
+                let hops: Vec<&str> = header_values(headers, "forwarded")
+                    .flat_map(|v| v.split(','))
+                    .filter_map(forwarded_for)
+                    .collect();
+                self.rightmost_untrusted(&hops)
+            }
+        };
+        from_header.unwrap_or(peer)
+    }
+
+    /// Walks from the proxy next to us towards the client and stops at
+    /// the first hop we don't trust, anything left of it could be made up.
+    fn rightmost_untrusted(&self, hops: &[&str]) -> Option<IpAddr> {
+        let mut client = None;
+        for hop in hops.iter().rev() {
+            // Obfuscated or broken entries end the chain
+            let Some(ip) = parse_ip(hop) else {
+                break;
+            };
+            client = Some(ip);
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn header_values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name).iter().filter_map(|v| v.to_str().ok())
+}
+
+/// Value of the `for` parameter of a Forwarded element.
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("for")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `::1` and `[::1]:80`.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+/// Client address according to the configured [`ClientIpSource`].
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(config) = parts.extensions.get::<Arc<ClientIpConfig>>() else {
+            return Err(ApiError::internal("Client IP source not configured"));
+        };
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Err(ApiError::internal("Peer address not available"));
+        };
+        Ok(ClientIp(config.client_ip(peer.ip(), &parts.headers)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    const PROXY: &str = "127.0.0.1";
+    const UNTRUSTED_PEER: &str = "198.51.100.7";
+
+    fn config(source: ClientIpSource) -> ClientIpConfig {
+        ClientIpConfig {
+            source,
+            trusted_proxies: vec![
+                "127.0.0.1/32".parse().unwrap(),
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+        }
+    }
+
+    fn client_ip(source: ClientIpSource, peer: &str, headers: &[(&'static str, &str)]) -> IpAddr {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        config(source).client_ip(peer.parse().unwrap(), &map)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn x_forwarded_for_skips_trusted_hops() {
+        let xff = "203.0.113.9, 192.0.2.1, 10.1.2.3";
+        assert_eq!(
+            client_ip(
+                ClientIpSource::XForwardedFor,
+                PROXY,
+                &[("x-forwarded-for", xff)]
+            ),
+            ip("192.0.2.1")
+        );
+        // Spread over several headers
+        assert_eq!(
+            client_ip(
+                ClientIpSource::XForwardedFor,
+                PROXY,
+                &[
+                    ("x-forwarded-for", "192.0.2.1"),
+                    ("x-forwarded-for", "10.1.2.3")
+                ]
+            ),
+            ip("192.0.2.1")
+        );
+        // Only trusted hops, the leftmost is the client
+        assert_eq!(
+            client_ip(
+                ClientIpSource::XForwardedFor,
+                PROXY,
+                &[("x-forwarded-for", "10.9.9.9, 10.1.2.3")]
+            ),
+            ip("10.9.9.9")
+        );
+    }
+
+    #[test]
+    fn forwarded_skips_trusted_hops() {
+        let forwarded = r#"for=203.0.113.9;proto=https, For="192.0.2.1:4711", for=10.1.2.3"#;
+        assert_eq!(
+            client_ip(
+                ClientIpSource::Forwarded,
+                PROXY,
+                &[("forwarded", forwarded)]
+            ),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn ipv6_with_brackets_and_port() {
+        let forwarded = r#"for="[2001:db9::1]:4711", for="[2001:db8::2]""#;
+        assert_eq!(
+            client_ip(
+                ClientIpSource::Forwarded,
+                PROXY,
+                &[("forwarded", forwarded)]
+            ),
+            ip("2001:db9::1")
+        );
+        assert_eq!(
+            client_ip(
+                ClientIpSource::XForwardedFor,
+                PROXY,
+                &[("x-forwarded-for", "[2001:db9::1]:4711, 2001:db8::2")]
+            ),
+            ip("2001:db9::1")
+        );
+        assert_eq!(parse_ip("[::1]"), Some(ip("::1")));
+        assert_eq!(parse_ip(" 1.2.3.4:80 "), Some(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn unknown_and_obfuscated_end_the_chain() {
+        // Nothing right of them is untrusted, so the last trusted hop is the client
+        for hop in ["for=unknown", "for=_hidden", r#"for="_gazonk""#] {
+            let forwarded = format!("for=192.0.2.1, {}, for=10.1.2.3", hop);
+            assert_eq!(
+                client_ip(
+                    ClientIpSource::Forwarded,
+                    PROXY,
+                    &[("forwarded", &forwarded)]
+                ),
+                ip("10.1.2.3"),
+                "{}",
+                hop
+            );
+        }
+        // Nothing usable at all, the peer is the client
+        assert_eq!(
+            client_ip(
+                ClientIpSource::Forwarded,
+                PROXY,
+                &[("forwarded", "for=unknown")]
+            ),
+            ip(PROXY)
+        );
+        assert_eq!(forwarded_for("proto=https;by=10.0.0.1"), None);
+    }
+
+    #[test]
+    fn headers_only_count_from_trusted_peers() {
+        for (source, name) in [
+            (ClientIpSource::XRealIp, "x-real-ip"),
+            (ClientIpSource::XForwardedFor, "x-forwarded-for"),
+            (ClientIpSource::Forwarded, "forwarded"),
+        ] {
+            let value = match source {
+                ClientIpSource::Forwarded => "for=192.0.2.1",
+                _ => "192.0.2.1",
+            };
+            assert_eq!(client_ip(source, PROXY, &[(name, value)]), ip("192.0.2.1"));
+            assert_eq!(
+                client_ip(source, UNTRUSTED_PEER, &[(name, value)]),
+                ip(UNTRUSTED_PEER)
+            );
+        }
+    }
+
+    #[test]
+    fn peer_source_ignores_headers() {
+        assert_eq!(
+            client_ip(ClientIpSource::Peer, PROXY, &[("x-real-ip", "192.0.2.1")]),
+            ip(PROXY)
+        );
+        // IPv4 peers of dual stack sockets
+        assert_eq!(
+            client_ip(ClientIpSource::Peer, "::ffff:192.0.2.1", &[]),
+            ip("192.0.2.1")
+        );
+    }
+}
@@ -1,75 +1,424 @@
-use log::debug;
+use log::{debug, info, warn};
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-    net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Mutex},
+    fmt::Display,
+    fs::{self, File},
+    io::Read,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 use thiserror::Error;
+use tokio::sync::broadcast;
 
+use crate::{
+    atomic_file,
+    client_ip::{ClientIpConfig, ClientIpSource},
+    limits::RateLimits,
+    validation::{self, Problem},
+    watch,
+};
+
+use ipnet::IpNet;
+use protocol::DeviceSettings;
 use serde::{Deserialize, Serialize};
 use toml_edit::{value, DocumentMut, TomlError};
+use utoipa::ToSchema;
 
-const CONFIG_FILENAME: &str = "evergreen.toml";
+pub const DEFAULT_CONFIG_PATH: &str = "evergreen.toml";
 const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_HTTPS_PORT: u16 = 8443;
+// The frontend fetches from here
+const DEFAULT_API_PREFIX: &str = "/api";
+pub const DEFAULT_DEVICE_ID: &str = "default";
+const ENV_HOST: &str = "EVERGREEN_HOST";
+const ENV_PORT: &str = "EVERGREEN_PORT";
+const ENV_API_SECRET: &str = "EVERGREEN_API_SECRET";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PlantConfig {
     pub amount_ml: u32,
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub id: String,
+    pub settings: Option<DeviceSettings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reservoir: Option<ReservoirConfig>,
+}
+
+/// Tank the pumps of a device draw from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReservoirConfig {
+    pub capacity_ml: u32,
+    // A fifth of the capacity if not set
+    pub low_level_ml: Option<u32>,
+    // Hand out no jobs while low, instead of letting the pumps run dry
+    #[serde(default)]
+    pub skip_jobs_when_low: bool,
+}
+
+impl ReservoirConfig {
+    pub fn low_level_ml(&self) -> u32 {
+        self.low_level_ml.unwrap_or(self.capacity_ml / 5)
+    }
+}
+
+/// HTTPS listener next to the plain HTTP one.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    // PEM files, relative to the config file
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub port: Option<u16>,
+}
+
+/// Where alerts go besides the log.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifierConfig {
+    // Gets a JSON POST per alert
+    pub webhook_url: String,
+}
+
+/// Per-IP limits, unset values use the defaults of [`RateLimits`].
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    // Requests per minute, 0 disables the limit
+    pub esp32_per_minute: Option<u32>,
+    pub frontend_per_minute: Option<u32>,
+    // Wrong secrets in a row before an IP is locked out, 0 disables lockouts
+    pub lockout_after_failures: Option<u32>,
+    // Doubled with every further wrong secret, up to the maximum
+    pub lockout_seconds: Option<u64>,
+    pub max_lockout_seconds: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
     host: Option<IpAddr>,
     port: Option<u16>,
+    // Missing if given by api_secret_file or EVERGREEN_API_SECRET
+    #[serde(default)]
     api_secret: String,
+    #[serde(alias = "apiSecretFile", skip_serializing_if = "Option::is_none")]
+    api_secret_file: Option<PathBuf>,
+    // Admin API is disabled if not set.
+    admin_secret: Option<String>,
+    // Token of the calendar feed, disabled if not set.
+    calendar_secret: Option<String>,
+    // How long in-flight requests may take after SIGTERM
+    shutdown_timeout_seconds: Option<u64>,
+    client_ip_source: Option<ClientIpSource>,
+    // CIDRs of proxies allowed to set client IP headers
+    trusted_proxies: Option<Vec<IpNet>>,
+    // Built frontend, relative to the config file
+    frontend_dir: Option<PathBuf>,
+    api_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls: Option<TlsConfig>,
+    rate_limits: Option<RateLimitConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notifier: Option<NotifierConfig>,
     plants: Vec<PlantConfig>,
+    #[serde(default)]
+    devices: Vec<DeviceConfig>,
+}
+
+impl Config {
+    /// Replace secrets, so the config can be printed.
+    pub fn redacted(mut self) -> Self {
+        self.api_secret = "<redacted>".into();
+        if self.admin_secret.is_some() {
+            self.admin_secret = Some("<redacted>".into());
+        }
+        if self.calendar_secret.is_some() {
+            self.calendar_secret = Some("<redacted>".into());
+        }
+        // Webhook URLs usually carry a token
+        if let Some(notifier) = &mut self.notifier {
+            notifier.webhook_url = "<redacted>".into();
+        }
+        self
+    }
+}
+
+/// Values from `EVERGREEN_*` environment variables, read once on startup.
+/// They take precedence over the config file.
+#[derive(Clone, Debug, Default)]
+pub struct EnvOverrides {
+    host: Option<IpAddr>,
+    port: Option<u16>,
+    api_secret: Option<String>,
+}
+
+impl EnvOverrides {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Ok(Self {
+            host: parse_env_var(ENV_HOST)?,
+            port: parse_env_var(ENV_PORT)?,
+            api_secret: env_var(ENV_API_SECRET),
+        })
+    }
+
+    pub fn has_api_secret(&self) -> bool {
+        self.api_secret.is_some()
+    }
+}
+
+/// Empty variables count as unset.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn parse_env_var<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    env_var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| ConfigError::InvalidEnv { name, value })
+        })
+        .transpose()
+}
+
+/// Where the effective value of a setting comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueSource {
+    CommandLine,
+    Env(&'static str),
+    SecretFile(PathBuf),
+    ConfigFile,
+    Default,
+}
+
+impl Display for ValueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueSource::CommandLine => write!(f, "--bind"),
+            ValueSource::Env(name) => write!(f, "{}", name),
+            ValueSource::SecretFile(path) => write!(f, "file {}", path.display()),
+            ValueSource::ConfigFile => write!(f, "config file"),
+            ValueSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Sent to subscribers whenever the config file changed.
+#[derive(Clone, Debug)]
+pub enum ConfigEvent {
+    /// The new config is in use.
+    Reloaded,
+    /// The new config is invalid, the last good one stays in use.
+    Rejected(Vec<String>),
+}
+
+struct CachedConfig {
+    // File content, to skip reloads without changes
+    raw: String,
+    config: Arc<Config>,
 }
 
 #[derive(Clone)]
 pub struct ConfigManager {
+    path: PathBuf,
+    // Set via command line, takes precedence over host and port
+    bind: Option<SocketAddr>,
+    env: EnvOverrides,
     mutex: Arc<Mutex<()>>,
+    // Last good config, loaded on first use
+    cache: Arc<RwLock<Option<CachedConfig>>>,
+    events: broadcast::Sender<ConfigEvent>,
 }
 
 #[derive(Error, Debug)]
 pub enum ConfigError {
-    #[error("Config file {} not found in working directory", CONFIG_FILENAME)]
-    NotFound,
+    #[error("Config file {0} not found")]
+    NotFound(PathBuf),
     #[error("Error while parsing: {0}")]
     ParseError(#[from] toml_edit::de::Error),
     #[error("Error while parsing: {0}")]
     ParseError2(#[from] TomlError),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Could not watch config: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("Environment variable {name} has invalid value {value:?}")]
+    InvalidEnv { name: &'static str, value: String },
+    #[error("Could not read API secret from {0}: {1}")]
+    SecretFile(PathBuf, std::io::Error),
+    #[error("Change would make the config invalid:\n{}", .0.join("\n"))]
+    Invalid(Vec<String>),
+    #[error("No plants in {0}")]
+    NoPlants(String),
+    #[error("Plant {0} not found")]
+    UnknownPlant(String),
+    #[error("Plant {plant} was changed since, it gets {current_ml}ml/day now")]
+    AmountChanged { plant: String, current_ml: u32 },
+    #[error("Plants were changed since")]
+    PlantsChanged,
 }
 
 impl ConfigManager {
-    pub fn new() -> Self {
+    pub fn new(path: PathBuf, bind: Option<SocketAddr>, env: EnvOverrides) -> Self {
+        let (events, _) = broadcast::channel(16);
         Self {
+            path,
+            bind,
+            env,
             mutex: Arc::new(Mutex::new(())),
+            cache: Arc::new(RwLock::new(None)),
+            events,
         }
     }
 
-    fn get_raw(&self) -> Result<String, ConfigError> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get_raw(&self) -> Result<String, ConfigError> {
         let _guard = self.mutex.lock();
-        let mut file = File::open(CONFIG_FILENAME)?;
-        let mut buffer = String::new();
-        file.read_to_string(&mut buffer).map_err(|e| {
+        self.read_file()
+    }
+
+    /// Callers hold the mutex.
+    fn read_file(&self) -> Result<String, ConfigError> {
+        let mut file = File::open(&self.path).map_err(|e| {
             if e.kind() == std::io::ErrorKind::NotFound {
-                ConfigError::NotFound
+                ConfigError::NotFound(self.path.clone())
             } else {
                 e.into()
             }
         })?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)?;
         Ok(buffer)
     }
 
-    fn get(&self) -> Result<Config, ConfigError> {
+    /// Relative paths in the config are relative to its directory.
+    fn config_dir(&self) -> &Path {
+        watch::parent_dir(&self.path)
+    }
+
+    /// Problems of the config file, see validation.rs.
+    pub fn validate(&self) -> Result<Vec<Problem>, ConfigError> {
+        Ok(self.validate_raw(&self.get_raw()?))
+    }
+
+    /// Problems the content would have as config file, e.g. of a backup.
+    pub fn validate_raw(&self, raw: &str) -> Vec<Problem> {
+        validation::validate(raw, self.config_dir(), &self.env)
+    }
+
+    /// Parses the config and resolves the API secret.
+    fn load(&self, buffer: &str) -> Result<Config, ConfigError> {
+        let mut config: Config = toml_edit::de::from_str(buffer)?;
+        if let Some(secret) = &self.env.api_secret {
+            config.api_secret = secret.clone();
+        } else if let Some(file) = &config.api_secret_file {
+            let path = self.config_dir().join(file);
+            config.api_secret =
+                read_secret_file(&path).map_err(|e| ConfigError::SecretFile(path, e))?;
+        }
+        Ok(config)
+    }
+
+    fn get(&self) -> Result<Arc<Config>, ConfigError> {
+        if let Some(cached) = self.cache.read().unwrap().as_ref() {
+            return Ok(cached.config.clone());
+        }
         let buffer = self.get_raw()?;
-        Ok(toml_edit::de::from_str(buffer.as_str())?)
+        let config = Arc::new(self.load(&buffer)?);
+        *self.cache.write().unwrap() = Some(CachedConfig {
+            raw: buffer,
+            config: config.clone(),
+        });
+        Ok(config)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ConfigEvent> {
+        self.events.subscribe()
+    }
+
+    /// Reads the file again and uses it if it validates.
+    /// Otherwise the last good config is kept.
+    /// Returns None if the content did not change.
+    pub fn reload(&self) -> Result<Option<ConfigEvent>, ConfigError> {
+        let buffer = self.get_raw()?;
+        if let Some(cached) = self.cache.read().unwrap().as_ref() {
+            if cached.raw == buffer {
+                return Ok(None);
+            }
+        }
+        let problems = validation::validate(&buffer, self.config_dir(), &self.env);
+        let event = match problems.is_empty() {
+            true => {
+                let config = Arc::new(self.load(&buffer)?);
+                *self.cache.write().unwrap() = Some(CachedConfig {
+                    raw: buffer,
+                    config,
+                });
+                info!("Reloaded config {}", self.path.display());
+                ConfigEvent::Reloaded
+            }
+            false => {
+                let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                warn!(
+                    "Keeping the last good config, {} is invalid:\n{}",
+                    self.path.display(),
+                    problems.join("\n")
+                );
+                ConfigEvent::Rejected(problems)
+            }
+        };
+        // Nobody listening is fine
+        let _ = self.events.send(event.clone());
+        Ok(Some(event))
+    }
+
+    /// Reloads the config whenever the file changes.
+    pub fn watch(&self) -> Result<(), ConfigError> {
+        let manager = self.clone();
+        watch::on_file_change(std::slice::from_ref(&self.path), move || {
+            let manager = manager.clone();
+            async move {
+                if let Err(e) = manager.reload() {
+                    warn!("Could not reload config: {}", e);
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    /// The config with all defaults and overrides applied.
+    pub fn get_effective(&self) -> Result<Config, ConfigError> {
+        let mut config = Config::clone(&*self.get()?);
+        config.host = Some(self.get_host()?);
+        config.port = Some(self.get_port()?);
+        config.shutdown_timeout_seconds = Some(self.get_shutdown_timeout()?.as_secs());
+        config.tls = self.get_tls()?;
+        config.frontend_dir = self.get_frontend_dir()?;
+        config.api_prefix = Some(self.get_api_prefix()?);
+        let client_ip = self.get_client_ip_config()?;
+        config.client_ip_source = Some(client_ip.source);
+        config.trusted_proxies = Some(client_ip.trusted_proxies);
+        let rate_limits = self.get_rate_limits()?;
+        config.rate_limits = Some(RateLimitConfig {
+            esp32_per_minute: Some(rate_limits.esp32_per_minute),
+            frontend_per_minute: Some(rate_limits.frontend_per_minute),
+            lockout_after_failures: Some(rate_limits.lockout_after_failures),
+            lockout_seconds: Some(rate_limits.lockout.as_secs()),
+            max_lockout_seconds: Some(rate_limits.max_lockout.as_secs()),
+        });
+        Ok(config)
     }
 
     fn get_document(&self) -> Result<DocumentMut, ConfigError> {
@@ -77,36 +426,534 @@ impl ConfigManager {
         Ok(buffer.parse()?)
     }
 
+    /// Precedence: --bind, EVERGREEN_HOST, config file, default.
     pub fn get_host(&self) -> Result<IpAddr, ConfigError> {
+        if let Some(bind) = self.bind {
+            return Ok(bind.ip());
+        }
+        if let Some(host) = self.env.host {
+            return Ok(host);
+        }
         Ok(self.get()?.host.unwrap_or(DEFAULT_HOST))
     }
 
+    /// Precedence: --bind, EVERGREEN_PORT, config file, default.
     pub fn get_port(&self) -> Result<u16, ConfigError> {
+        if let Some(bind) = self.bind {
+            return Ok(bind.port());
+        }
+        if let Some(port) = self.env.port {
+            return Ok(port);
+        }
         Ok(self.get()?.port.unwrap_or(DEFAULT_PORT))
     }
 
+    /// Where host, port and api_secret come from, for print-config.
+    pub fn get_sources(&self) -> Result<Vec<(&'static str, ValueSource)>, ConfigError> {
+        let config = self.get()?;
+        let host = if self.bind.is_some() {
+            ValueSource::CommandLine
+        } else if self.env.host.is_some() {
+            ValueSource::Env(ENV_HOST)
+        } else if config.host.is_some() {
+            ValueSource::ConfigFile
+        } else {
+            ValueSource::Default
+        };
+        let port = if self.bind.is_some() {
+            ValueSource::CommandLine
+        } else if self.env.port.is_some() {
+            ValueSource::Env(ENV_PORT)
+        } else if config.port.is_some() {
+            ValueSource::ConfigFile
+        } else {
+            ValueSource::Default
+        };
+        let api_secret = match (&self.env.api_secret, &config.api_secret_file) {
+            (Some(_), _) => ValueSource::Env(ENV_API_SECRET),
+            (None, Some(file)) => ValueSource::SecretFile(self.config_dir().join(file)),
+            (None, None) => ValueSource::ConfigFile,
+        };
+        Ok(vec![
+            ("host", host),
+            ("port", port),
+            ("api_secret", api_secret),
+        ])
+    }
+
+    pub fn get_shutdown_timeout(&self) -> Result<Duration, ConfigError> {
+        Ok(Duration::from_secs(
+            self.get()?
+                .shutdown_timeout_seconds
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS),
+        ))
+    }
+
+    /// TLS settings with paths resolved and the default port applied.
+    /// Changes need a restart, only the certificate files are reloaded.
+    pub fn get_tls(&self) -> Result<Option<TlsConfig>, ConfigError> {
+        Ok(self.get()?.tls.as_ref().map(|tls| TlsConfig {
+            cert_path: self.config_dir().join(&tls.cert_path),
+            key_path: self.config_dir().join(&tls.key_path),
+            port: Some(tls.port.unwrap_or(DEFAULT_HTTPS_PORT)),
+        }))
+    }
+
+    pub fn get_notifier(&self) -> Result<Option<NotifierConfig>, ConfigError> {
+        Ok(self.get()?.notifier.clone())
+    }
+
+    pub fn get_rate_limits(&self) -> Result<RateLimits, ConfigError> {
+        let config = self.get()?.rate_limits.clone().unwrap_or_default();
+        let defaults = RateLimits::default();
+        Ok(RateLimits {
+            esp32_per_minute: config.esp32_per_minute.unwrap_or(defaults.esp32_per_minute),
+            frontend_per_minute: config
+                .frontend_per_minute
+                .unwrap_or(defaults.frontend_per_minute),
+            lockout_after_failures: config
+                .lockout_after_failures
+                .unwrap_or(defaults.lockout_after_failures),
+            lockout: config
+                .lockout_seconds
+                .map_or(defaults.lockout, Duration::from_secs),
+            max_lockout: config
+                .max_lockout_seconds
+                .map_or(defaults.max_lockout, Duration::from_secs),
+        })
+    }
+
+    /// Changes need a restart.
+    pub fn get_client_ip_config(&self) -> Result<ClientIpConfig, ConfigError> {
+        let config = self.get()?;
+        Ok(ClientIpConfig {
+            source: config.client_ip_source.unwrap_or_default(),
+            // A proxy on the same machine, like Nginx
+            trusted_proxies: config.trusted_proxies.clone().unwrap_or_else(|| {
+                vec![
+                    IpNet::from(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                    IpNet::from(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+                ]
+            }),
+        })
+    }
+
+    pub fn get_frontend_dir(&self) -> Result<Option<PathBuf>, ConfigError> {
+        Ok(self
+            .get()?
+            .frontend_dir
+            .as_ref()
+            .map(|dir| self.config_dir().join(dir)))
+    }
+
+    /// Defaults to /api if a frontend is served, as it expects, otherwise to the root.
+    pub fn get_api_prefix(&self) -> Result<String, ConfigError> {
+        let config = self.get()?;
+        let serves_frontend = config.frontend_dir.is_some() || cfg!(feature = "embed-frontend");
+        Ok(match (&config.api_prefix, serves_frontend) {
+            (Some(prefix), _) => prefix.clone(),
+            (None, true) => DEFAULT_API_PREFIX.into(),
+            (None, false) => String::new(),
+        })
+    }
+
     pub fn get_plant_config(&self) -> Result<Vec<PlantConfig>, ConfigError> {
-        Ok(self.get()?.plants)
+        Ok(self.get()?.plants.clone())
+    }
+
+    /// Sets the amount of the plant and returns the amount it got before.
+    pub fn put_plant_amount_ml(&self, plant: &str, amount_ml: u32) -> Result<u32, ConfigError> {
+        let old_ml = self.edit_document(|config| {
+            let (index, current_ml) = find_plant_amount_ml(config, plant)?;
+            config["plants"][index]["amountMl"] = value(amount_ml as i64);
+            Ok(current_ml)
+        })?;
+        debug!("Successful: Plant {} get {}ml/day now", plant, amount_ml);
+        Ok(old_ml)
     }
 
-    pub fn put_plant_amount_ml(&self, index: usize, amount_ml: u32) -> Result<(), ConfigError> {
-        let mut config = self.get_document()?;
-        config["plants"][index]["amountMl"] = value(amount_ml as i64);
-        let mut file = OpenOptions::new().write(true).open(CONFIG_FILENAME)?;
-        file.write_all(config.to_string().as_bytes())?;
-        debug!("Successful: Plant {} get {}ml/day now", index, amount_ml);
+    /// Sets the amount only if the plant still gets `expected_ml`. Checked and written
+    /// under the lock, so a change in between is never overwritten.
+    pub fn put_plant_amount_ml_if(
+        &self,
+        plant: &str,
+        expected_ml: u32,
+        amount_ml: u32,
+    ) -> Result<(), ConfigError> {
+        self.edit_document(|config| {
+            let (index, current_ml) = find_plant_amount_ml(config, plant)?;
+            if current_ml != expected_ml {
+                return Err(ConfigError::AmountChanged {
+                    plant: plant.into(),
+                    current_ml,
+                });
+            }
+            config["plants"][index]["amountMl"] = value(amount_ml as i64);
+            Ok(())
+        })?;
+        debug!("Successful: Plant {} get {}ml/day now", plant, amount_ml);
         Ok(())
     }
 
     pub fn put_plant_name(&self, index: usize, name: String) -> Result<(), ConfigError> {
-        let mut config = self.get_document()?;
-        config["plants"][index]["name"] = value(name);
-        let mut file = OpenOptions::new().write(true).open(CONFIG_FILENAME)?;
-        file.write_all(config.to_string().as_bytes())?;
+        self.edit_document(|config| {
+            config["plants"][index]["name"] = value(name);
+            Ok(())
+        })
+    }
+
+    /// The `plants` section as TOML, including its comments.
+    pub fn get_plants_toml(&self) -> Result<String, ConfigError> {
+        Ok(plants_section(&self.get_document()?))
+    }
+
+    /// Replaces the `plants` section, the rest of the file stays as it is.
+    /// Returns the plants and `plants` section it replaced.
+    pub fn put_plants_toml(
+        &self,
+        plants_toml: &str,
+    ) -> Result<(Vec<PlantConfig>, String), ConfigError> {
+        self.replace_plants(plants_toml, None)
+    }
+
+    /// Replaces the `plants` section only if the plants are still `expected`. Checked
+    /// and written under the lock, so a change in between is never overwritten.
+    /// Returns the `plants` section it replaced.
+    pub fn put_plants_toml_if(
+        &self,
+        expected: &[PlantConfig],
+        plants_toml: &str,
+    ) -> Result<String, ConfigError> {
+        let (_, old_toml) = self.replace_plants(plants_toml, Some(expected))?;
+        Ok(old_toml)
+    }
+
+    fn replace_plants(
+        &self,
+        plants_toml: &str,
+        expected: Option<&[PlantConfig]>,
+    ) -> Result<(Vec<PlantConfig>, String), ConfigError> {
+        let mut plants = plants_toml
+            .parse::<DocumentMut>()?
+            .remove("plants")
+            .ok_or_else(|| ConfigError::NoPlants(plants_toml.into()))?;
+        self.edit_document(|config| {
+            let old = toml_edit::de::from_str::<Config>(&config.to_string())?.plants;
+            if expected.is_some_and(|expected| expected != old) {
+                return Err(ConfigError::PlantsChanged);
+            }
+            let old_toml = plants_section(config);
+            let first = config
+                .get("plants")
+                .and_then(|item| item.as_array_of_tables())
+                .and_then(|tables| tables.get(0));
+            let position = first.and_then(|table| table.position());
+            let prefix = first.and_then(|table| table.decor().prefix()).cloned();
+            if let Some(tables) = plants.as_array_of_tables_mut() {
+                for table in tables.iter_mut() {
+                    // Tables are ordered by position, keep the plants where they were
+                    if let Some(position) = position {
+                        table.set_position(position);
+                    }
+                }
+                if let (Some(first), Some(prefix)) = (tables.get_mut(0), prefix) {
+                    first.decor_mut().set_prefix(prefix);
+                }
+            }
+            config["plants"] = plants;
+            Ok((old, old_toml))
+        })
+    }
+
+    /// Reads, edits and writes the file under the lock, so concurrent edits don't
+    /// overwrite each other. Reloads right away like [`Self::put_raw`].
+    fn edit_document<T>(
+        &self,
+        edit: impl FnOnce(&mut DocumentMut) -> Result<T, ConfigError>,
+    ) -> Result<T, ConfigError> {
+        let guard = self.mutex.lock();
+        let mut config: DocumentMut = self.read_file()?.parse()?;
+        let result = edit(&mut config)?;
+        self.write_file(&config.to_string())?;
+        drop(guard);
+        self.reload()?;
+        Ok(result)
+    }
+
+    /// Replaces the file if the new content validates and reloads right away,
+    /// so the change is visible before the file watcher notices it.
+    pub fn put_raw(&self, raw: &str) -> Result<(), ConfigError> {
+        let guard = self.mutex.lock();
+        self.write_file(raw)?;
+        drop(guard);
+        self.reload()?;
         Ok(())
     }
 
+    /// Callers hold the mutex.
+    fn write_file(&self, raw: &str) -> Result<(), ConfigError> {
+        let problems = self.validate_raw(raw);
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(
+                problems.iter().map(|p| p.to_string()).collect(),
+            ));
+        }
+        atomic_file::write(&self.path, raw.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn get_device_settings(
+        &self,
+        device_id: &str,
+    ) -> Result<Option<DeviceSettings>, ConfigError> {
+        Ok(self
+            .get()?
+            .devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .and_then(|d| d.settings.clone()))
+    }
+
+    pub fn get_reservoir(&self, device_id: &str) -> Result<Option<ReservoirConfig>, ConfigError> {
+        Ok(self
+            .get()?
+            .devices
+            .iter()
+            .find(|d| d.id == device_id)
+            .and_then(|d| d.reservoir.clone()))
+    }
+
+    /// Devices with a reservoir, in config order.
+    pub fn get_reservoirs(&self) -> Result<Vec<(String, ReservoirConfig)>, ConfigError> {
+        Ok(self
+            .get()?
+            .devices
+            .iter()
+            .filter_map(|d| Some((d.id.clone(), d.reservoir.clone()?)))
+            .collect())
+    }
+
     pub fn get_api_secret(&self) -> Result<String, ConfigError> {
-        self.get().map(|c| c.api_secret)
+        self.get().map(|c| c.api_secret.clone())
+    }
+
+    pub fn get_admin_secret(&self) -> Result<Option<String>, ConfigError> {
+        self.get().map(|c| c.admin_secret.clone())
+    }
+
+    pub fn get_calendar_secret(&self) -> Result<Option<String>, ConfigError> {
+        self.get().map(|c| c.calendar_secret.clone())
+    }
+}
+
+/// Reads a secret, ignoring surrounding whitespace like a trailing newline.
+pub fn read_secret_file(path: &Path) -> std::io::Result<String> {
+    Ok(fs::read_to_string(path)?.trim().to_string())
+}
+
+/// The `plants` section of the document as TOML, including its comments.
+fn plants_section(config: &DocumentMut) -> String {
+    let mut plants = DocumentMut::new();
+    if let Some(item) = config.get("plants") {
+        let mut item = item.clone();
+        // Comments above the first plant belong to the rest of the file
+        if let Some(first) = item.as_array_of_tables_mut().and_then(|t| t.get_mut(0)) {
+            first.decor_mut().set_prefix("");
+        }
+        plants.insert("plants", item);
+    }
+    plants.to_string()
+}
+
+/// Index and amount of the plant in the document being edited.
+fn find_plant_amount_ml(config: &DocumentMut, plant: &str) -> Result<(usize, u32), ConfigError> {
+    toml_edit::de::from_str::<Config>(&config.to_string())?
+        .plants
+        .iter()
+        .enumerate()
+        .find(|(_, p)| p.name == plant)
+        .map(|(index, p)| (index, p.amount_ml))
+        .ok_or_else(|| ConfigError::UnknownPlant(plant.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = r#"# Server settings
+api_secret = "secret"
+
+# The plants, in pin order
+[[plants]]
+name = "Karsten"
+amountMl = 100 # likes it wet
+
+# Basil on the window sill
+[[plants]]
+name = "Bazil"
+amountMl = 500
+
+# The only box
+[[devices]]
+id = "default"
+
+[devices.settings]
+version = 1 # bump on every change
+"#;
+
+    /// A config file in its own directory, removed on drop.
+    struct TempConfig {
+        dir: PathBuf,
+        manager: ConfigManager,
+    }
+
+    impl TempConfig {
+        fn new(name: &str, content: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "evergreen-config-{}-{}",
+                name,
+                std::process::id()
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(DEFAULT_CONFIG_PATH);
+            fs::write(&path, content).unwrap();
+            let manager = ConfigManager::new(path, None, EnvOverrides::default());
+            Self { dir, manager }
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn plants_toml_round_trip_keeps_the_file() {
+        let config = TempConfig::new("round-trip", CONFIG);
+        let plants = config.manager.get_plants_toml().unwrap();
+        assert_eq!(
+            plants,
+            r#"[[plants]]
+name = "Karsten"
+amountMl = 100 # likes it wet
+
+# Basil on the window sill
+[[plants]]
+name = "Bazil"
+amountMl = 500
+"#
+        );
+        config.manager.put_plants_toml(&plants).unwrap();
+        assert_eq!(config.manager.get_raw().unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn put_plants_toml_changes_only_the_plants() {
+        let config = TempConfig::new("put-plants", CONFIG);
+        config
+            .manager
+            .put_plants_toml(
+                r#"[[plants]]
+name = "Karsten"
+amountMl = 150 # drier now
+
+[[plants]]
+name = "Ficus"
+amountMl = 200
+"#,
+            )
+            .unwrap();
+        assert_eq!(
+            config.manager.get_raw().unwrap(),
+            r#"# Server settings
+api_secret = "secret"
+
+# The plants, in pin order
+[[plants]]
+name = "Karsten"
+amountMl = 150 # drier now
+
+[[plants]]
+name = "Ficus"
+amountMl = 200
+
+# The only box
+[[devices]]
+id = "default"
+
+[devices.settings]
+version = 1 # bump on every change
+"#
+        );
+        let names: Vec<String> = config
+            .manager
+            .get_plant_config()
+            .unwrap()
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        assert_eq!(names, ["Karsten", "Ficus"]);
+    }
+
+    #[test]
+    fn put_plants_toml_if_restores_the_comments() {
+        let config = TempConfig::new("plants-if", CONFIG);
+        let snapshot = "[[plants]]\nname = \"Ficus\"\namountMl = 200\n";
+        let (old, old_toml) = config.manager.put_plants_toml(snapshot).unwrap();
+        let applied = config.manager.get_plant_config().unwrap();
+        assert!(matches!(
+            config.manager.put_plants_toml_if(&old, &old_toml),
+            Err(ConfigError::PlantsChanged)
+        ));
+        let replaced = config
+            .manager
+            .put_plants_toml_if(&applied, &old_toml)
+            .unwrap();
+        assert_eq!(replaced, snapshot);
+        assert_eq!(config.manager.get_raw().unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn put_plants_toml_rejects_invalid_plants() {
+        let config = TempConfig::new("invalid-plants", CONFIG);
+        let duplicate =
+            "[[plants]]\nname = \"A\"\namountMl = 1\n\n[[plants]]\nname = \"A\"\namountMl = 2\n";
+        assert!(matches!(
+            config.manager.put_plants_toml(duplicate),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            config.manager.put_plants_toml("[other]\n"),
+            Err(ConfigError::NoPlants(_))
+        ));
+        assert_eq!(config.manager.get_raw().unwrap(), CONFIG);
+    }
+
+    #[test]
+    fn concurrent_edits_are_all_kept() {
+        let config = TempConfig::new("concurrent", CONFIG);
+        let writers: Vec<_> = [("Karsten", 100), ("Bazil", 500)]
+            .into_iter()
+            .map(|(plant, base_ml)| {
+                let manager = config.manager.clone();
+                std::thread::spawn(move || {
+                    for ml in 1..=20 {
+                        manager.put_plant_amount_ml(plant, base_ml + ml).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let amounts: Vec<_> = config
+            .manager
+            .get_plant_config()
+            .unwrap()
+            .iter()
+            .map(|p| p.amount_ml)
+            .collect();
+        assert_eq!(amounts, vec![120, 520]);
     }
 }
@@ -0,0 +1,61 @@
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE},
+        request::Parts,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use protocol::POSTCARD_MEDIA_TYPE;
+use serde::Serialize;
+
+use crate::error::ApiError;
+
+/// Response encoding requested by the ESP32 via the `Accept` header.
+/// JSON unless postcard is asked for explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Postcard,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Encoding {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let accepts_postcard = parts
+            .headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|media_type| {
+                media_type.split(';').next().map(str::trim) == Some(POSTCARD_MEDIA_TYPE)
+            });
+        match accepts_postcard {
+            true => Ok(Encoding::Postcard),
+            false => Ok(Encoding::Json),
+        }
+    }
+}
+
+/// Serializes the value with the requested encoding.
+pub struct Encoded<T>(pub Encoding, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        match self.0 {
+            Encoding::Json => Json(self.1).into_response(),
+            Encoding::Postcard => match postcard::to_allocvec(&self.1) {
+                Ok(bytes) => ([(CONTENT_TYPE, POSTCARD_MEDIA_TYPE)], bytes).into_response(),
+                Err(e) => ApiError::internal(format!("Could not encode postcard response: {}", e))
+                    .into_response(),
+            },
+        }
+    }
+}
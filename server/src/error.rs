@@ -0,0 +1,312 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use axum::{
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+        HeaderName, HeaderValue, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Local;
+use log::{error, warn};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    audit::AuditError, backup::BackupError, config::ConfigError, firmware::FirmwareError,
+    history::HistoryError, snapshot::SnapshotError, state::StateError,
+};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+// Longer IDs from proxies are replaced, they end up in every log line
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// What went wrong, stable across releases. Clients should match on this, not the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Invalid path, query or body
+    BadRequest,
+    /// Wrong or missing secret
+    Unauthorized,
+    /// Not allowed from this client
+    Forbidden,
+    /// Feature is off in the config
+    Disabled,
+    NotFound,
+    MethodNotAllowed,
+    /// Changed since, e.g. by someone else
+    Conflict,
+    /// Replaced before it completed
+    Gone,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    /// Change would make the config invalid
+    InvalidConfig,
+    RateLimited,
+    /// Too many wrong secrets
+    LockedOut,
+    /// Config can't be read or written
+    ConfigError,
+    /// State can't be read or written
+    StateError,
+    Internal,
+    ShuttingDown,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::Disabled => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::Gone => StatusCode::GONE,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            ErrorCode::InvalidConfig => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateLimited | ErrorCode::LockedOut => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ConfigError | ErrorCode::StateError | ErrorCode::Internal => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            ErrorCode::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Code for error responses not made by [`ApiError`], e.g. rejections of axum's extractors.
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::GONE => ErrorCode::Gone,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::RANGE_NOT_SATISFIABLE => ErrorCode::RangeNotSatisfiable,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::ShuttingDown,
+            status if status.is_server_error() => ErrorCode::Internal,
+            _ => ErrorCode::BadRequest,
+        }
+    }
+}
+
+/// Body of every error response of the API.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    /// For humans, may change any time
+    pub message: String,
+    /// Also in the `X-Request-Id` header and the server log
+    pub request_id: String,
+}
+
+/// Error of an API handler, answered as [`ErrorBody`].
+/// Internal errors are logged with the request ID, so reports can be matched with the log.
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.code.status();
+        let request_id = current_request_id();
+        if status == StatusCode::INTERNAL_SERVER_ERROR {
+            error!("[{}] {:?}: {}", request_id, self.code, self.message);
+        }
+        // Also set by the middleware, but not every fallback runs behind it
+        let header = HeaderValue::from_str(&request_id).ok();
+        let body = ErrorBody {
+            code: self.code,
+            message: self.message,
+            request_id,
+        };
+        let mut response = (status, Json(body)).into_response();
+        if let Some(header) = header {
+            response.headers_mut().insert(REQUEST_ID_HEADER, header);
+        }
+        if let Some(retry_after) = self.retry_after {
+            // Rounded up, clients retrying early would be rejected again
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+impl From<ConfigError> for ApiError {
+    /// Rejected changes are the client's fault, the rest is ours.
+    fn from(err: ConfigError) -> Self {
+        match err {
+            ConfigError::Invalid(_) => ApiError::new(ErrorCode::InvalidConfig, err.to_string()),
+            ConfigError::UnknownPlant(_) => ApiError::not_found(err.to_string()),
+            ConfigError::AmountChanged { .. } | ConfigError::PlantsChanged => {
+                ApiError::new(ErrorCode::Conflict, err.to_string())
+            }
+            _ => ApiError::new(ErrorCode::ConfigError, format!("Config error: {}", err)),
+        }
+    }
+}
+
+impl From<StateError> for ApiError {
+    fn from(err: StateError) -> Self {
+        ApiError::new(ErrorCode::StateError, format!("State error: {}", err))
+    }
+}
+
+impl From<AuditError> for ApiError {
+    fn from(err: AuditError) -> Self {
+        ApiError::internal(format!("Audit log error: {}", err))
+    }
+}
+
+impl From<HistoryError> for ApiError {
+    fn from(err: HistoryError) -> Self {
+        ApiError::internal(format!("History error: {}", err))
+    }
+}
+
+impl From<SnapshotError> for ApiError {
+    fn from(err: SnapshotError) -> Self {
+        match err {
+            SnapshotError::InvalidName(_) => ApiError::bad_request(err.to_string()),
+            _ => ApiError::internal(format!("Snapshot error: {}", err)),
+        }
+    }
+}
+
+impl From<FirmwareError> for ApiError {
+    fn from(err: FirmwareError) -> Self {
+        let code = match err {
+            FirmwareError::NotFound(..) => ErrorCode::NotFound,
+            FirmwareError::AlreadyExists(..) => ErrorCode::Conflict,
+            FirmwareError::InvalidVersion(_) | FirmwareError::InvalidDeviceId(_) => {
+                ErrorCode::BadRequest
+            }
+            FirmwareError::Io(_) | FirmwareError::Parse(_) => ErrorCode::Internal,
+        };
+        ApiError::new(code, err.to_string())
+    }
+}
+
+impl From<BackupError> for ApiError {
+    fn from(err: BackupError) -> Self {
+        match err {
+            BackupError::Invalid(_) => ApiError::bad_request(err.to_string()),
+            _ => ApiError::internal(err.to_string()),
+        }
+    }
+}
+
+fn next_request_id() -> String {
+    static PREFIX: OnceLock<String> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // Start time keeps IDs unique across restarts
+    let prefix = PREFIX.get_or_init(|| format!("{:x}", Local::now().timestamp()));
+    format!("{}-{}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// ID of the request being handled, a fresh one outside of [`request_id`].
+pub fn current_request_id() -> String {
+    REQUEST_ID
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| next_request_id())
+}
+
+fn is_json(response: &Response) -> bool {
+    response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"))
+}
+
+/// Middleware giving every request an ID, taken from `X-Request-Id` if a proxy set one.
+/// Error responses which aren't JSON yet, e.g. rejections of axum's extractors,
+/// are turned into an [`ErrorBody`] with the original status.
+pub async fn request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(next_request_id);
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .await;
+    let status = response.status();
+    if (status.is_client_error() || status.is_server_error()) && !is_json(&response) {
+        let (mut parts, body) = response.into_parts();
+        let message = match hyper::body::to_bytes(body).await {
+            Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+            Ok(_) => status.canonical_reason().unwrap_or_default().to_string(),
+            Err(e) => {
+                warn!("[{}] Could not read error response: {}", request_id, e);
+                status.canonical_reason().unwrap_or_default().to_string()
+            }
+        };
+        let body = ErrorBody {
+            code: ErrorCode::from_status(status),
+            message,
+            request_id: request_id.clone(),
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        response = Response::from_parts(parts, Json(body).into_response().into_body());
+    }
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
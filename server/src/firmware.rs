@@ -0,0 +1,223 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::{Local, NaiveDateTime};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use utoipa::ToSchema;
+
+pub const FIRMWARE_DIRNAME: &str = "firmware";
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareImage {
+    pub device_id: String,
+    #[schema(value_type = String, example = "1.2.0")]
+    pub version: Version,
+    pub sha256: String,
+    pub size: u64,
+    pub uploaded: NaiveDateTime,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareManifest {
+    pub images: Vec<FirmwareImage>,
+    /// Devices rolled back to a fixed version, by device ID.
+    /// Devices not listed here follow the newest image.
+    #[schema(value_type = HashMap<String, String>)]
+    pub pinned: HashMap<String, Version>,
+}
+
+#[derive(Error, Debug)]
+pub enum FirmwareError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parsing error: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(#[from] semver::Error),
+    #[error("Invalid device ID {0}, only ASCII letters, digits, - and _ are allowed")]
+    InvalidDeviceId(String),
+    #[error("Firmware {1} for device {0} already exists")]
+    AlreadyExists(String, Version),
+    #[error("Firmware {1} for device {0} not found")]
+    NotFound(String, Version),
+}
+
+/// Stores firmware images in the `firmware` directory of the state directory,
+/// described by a JSON manifest next to them.
+#[derive(Clone)]
+pub struct FirmwareStore {
+    dir: PathBuf,
+    mutex: Arc<Mutex<()>>,
+}
+
+fn validate_device_id(device_id: &str) -> Result<(), FirmwareError> {
+    let valid = !device_id.is_empty()
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(FirmwareError::InvalidDeviceId(device_id.into())),
+    }
+}
+
+impl FirmwareStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            dir: state_dir.join(FIRMWARE_DIRNAME),
+            mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Held while a backup or restore handles images and manifest.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn image_path(&self, device_id: &str, version: &Version) -> PathBuf {
+        self.dir.join(format!("{}-{}.bin", device_id, version))
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILENAME)
+    }
+
+    fn read_manifest(&self) -> Result<FirmwareManifest, FirmwareError> {
+        let mut file = match File::open(self.manifest_path()) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(FirmwareManifest::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)?;
+        Ok(serde_json::from_str(buffer.as_str())?)
+    }
+
+    fn write_manifest(&self, manifest: &FirmwareManifest) -> Result<(), FirmwareError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.manifest_path())?;
+        file.write_all(serde_json::to_string_pretty(manifest)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn get_manifest(&self) -> Result<FirmwareManifest, FirmwareError> {
+        let _guard = self.mutex.lock();
+        self.read_manifest()
+    }
+
+    pub fn add_image(
+        &self,
+        device_id: &str,
+        version: &str,
+        binary: &[u8],
+    ) -> Result<FirmwareImage, FirmwareError> {
+        validate_device_id(device_id)?;
+        let version = Version::parse(version)?;
+        let _guard = self.mutex.lock();
+        let mut manifest = self.read_manifest()?;
+        if manifest
+            .images
+            .iter()
+            .any(|i| i.device_id == device_id && i.version == version)
+        {
+            return Err(FirmwareError::AlreadyExists(device_id.into(), version));
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        let mut file = File::create(self.image_path(device_id, &version))?;
+        file.write_all(binary)?;
+        file.sync_all()?;
+
+        let image = FirmwareImage {
+            device_id: device_id.into(),
+            version,
+            sha256: hex::encode(Sha256::digest(binary)),
+            size: binary.len() as u64,
+            uploaded: Local::now().naive_local(),
+        };
+        manifest.images.push(image.clone());
+        self.write_manifest(&manifest)?;
+        Ok(image)
+    }
+
+    /// Pin a device to an uploaded version, usually an older one.
+    /// `None` removes the pin, so the device follows the newest image again.
+    pub fn pin_version(&self, device_id: &str, version: Option<&str>) -> Result<(), FirmwareError> {
+        let _guard = self.mutex.lock();
+        let mut manifest = self.read_manifest()?;
+        match version {
+            Some(version) => {
+                let version = Version::parse(version)?;
+                if !manifest
+                    .images
+                    .iter()
+                    .any(|i| i.device_id == device_id && i.version == version)
+                {
+                    return Err(FirmwareError::NotFound(device_id.into(), version));
+                }
+                manifest.pinned.insert(device_id.into(), version);
+            }
+            None => {
+                manifest.pinned.remove(device_id);
+            }
+        }
+        self.write_manifest(&manifest)
+    }
+
+    /// Image the device should run, if it differs from the reported version.
+    /// Without a pin only newer versions are offered.
+    pub fn available_update(
+        &self,
+        device_id: &str,
+        reported_version: &str,
+    ) -> Result<Option<FirmwareImage>, FirmwareError> {
+        let reported_version = Version::parse(reported_version)?;
+        let manifest = self.get_manifest()?;
+        let mut images = manifest
+            .images
+            .into_iter()
+            .filter(|i| i.device_id == device_id);
+        let update = match manifest.pinned.get(device_id) {
+            Some(pinned) => images.find(|i| &i.version == pinned),
+            None => images
+                .max_by(|a, b| a.version.cmp(&b.version))
+                .filter(|i| i.version > reported_version),
+        };
+        Ok(update.filter(|i| i.version != reported_version))
+    }
+
+    pub fn get_image(
+        &self,
+        device_id: &str,
+        version: &str,
+    ) -> Result<FirmwareImage, FirmwareError> {
+        let version = Version::parse(version)?;
+        self.get_manifest()?
+            .images
+            .into_iter()
+            .find(|i| i.device_id == device_id && i.version == version)
+            .ok_or_else(|| FirmwareError::NotFound(device_id.into(), version))
+    }
+
+    /// Open the binary of an image and seek to `offset`.
+    pub fn open_image(&self, image: &FirmwareImage, offset: u64) -> Result<File, FirmwareError> {
+        let mut file = File::open(self.image_path(&image.device_id, &image.version))?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    }
+}
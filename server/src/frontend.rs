@@ -0,0 +1,110 @@
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf},
+};
+
+use axum::{
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        Method, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+};
+use log::error;
+
+const INDEX: &str = "index.html";
+// Hashed file names, a new build gets new names
+const IMMUTABLE_PREFIX: &str = "_app/immutable/";
+
+#[cfg(feature = "embed-frontend")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "../frontend/build"]
+struct EmbeddedFrontend;
+
+/// Where the built SvelteKit frontend comes from.
+#[derive(Debug, Clone)]
+pub enum Frontend {
+    Dir(PathBuf),
+    #[cfg(feature = "embed-frontend")]
+    Embedded,
+}
+
+impl Frontend {
+    /// A configured directory wins over the embedded build.
+    pub fn new(dir: Option<PathBuf>) -> Option<Self> {
+        match dir {
+            Some(dir) => Some(Frontend::Dir(dir)),
+            #[cfg(feature = "embed-frontend")]
+            None => Some(Frontend::Embedded),
+            #[cfg(not(feature = "embed-frontend"))]
+            None => None,
+        }
+    }
+
+    async fn read(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match self {
+            Frontend::Dir(dir) => {
+                // Only plain file names, nothing like `..`
+                let relative = Path::new(path);
+                if !relative
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_)))
+                {
+                    return None;
+                }
+                let file = dir.join(relative);
+                match tokio::fs::read(&file).await {
+                    Ok(content) => Some(Cow::Owned(content)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    // Directories end up here as well
+                    Err(e) => {
+                        error!("Could not read {}: {}", file.display(), e);
+                        None
+                    }
+                }
+            }
+            #[cfg(feature = "embed-frontend")]
+            Frontend::Embedded => EmbeddedFrontend::get(path).map(|f| f.data),
+        }
+    }
+
+    /// Serves a file of the build, unknown pages get index.html,
+    /// so the frontend can render them itself.
+    pub async fn serve(&self, method: Method, uri: Uri) -> Response {
+        if method != Method::GET && method != Method::HEAD {
+            return (StatusCode::NOT_FOUND, "Path, query or body mismatch.").into_response();
+        }
+        let path = match uri.path().trim_start_matches('/') {
+            "" => INDEX,
+            path => path,
+        };
+        if let Some(content) = self.read(path).await {
+            return file_response(path, content);
+        }
+        // Missing assets are errors, not pages
+        let is_asset = path.starts_with("_app/") || Path::new(path).extension().is_some();
+        if !is_asset {
+            if let Some(content) = self.read(INDEX).await {
+                return file_response(INDEX, content);
+            }
+        }
+        (StatusCode::NOT_FOUND, "Not found").into_response()
+    }
+}
+
+fn file_response(path: &str, content: Cow<'static, [u8]>) -> Response {
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let cache_control = match path.starts_with(IMMUTABLE_PREFIX) {
+        true => "public, max-age=31536000, immutable",
+        // Revalidate, so a new build shows up right away
+        false => "no-cache",
+    };
+    (
+        [
+            (CONTENT_TYPE, content_type.as_ref()),
+            (CACHE_CONTROL, cache_control),
+        ],
+        content.into_owned(),
+    )
+        .into_response()
+}
@@ -0,0 +1,173 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::NaiveDateTime;
+use protocol::{WakeFailure, WateringStatus};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+pub const HISTORY_DIRNAME: &str = "history";
+
+/// A line of one of the history files, appended in chronological order.
+pub trait Record: Serialize + DeserializeOwned + Send + 'static {
+    const FILENAME: &'static str;
+
+    fn timestamp(&self) -> NaiveDateTime;
+}
+
+/// A dequeue request of a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckInRecord {
+    pub timestamp: NaiveDateTime,
+    pub device_id: String,
+    pub battery_percentage: f32,
+    pub ip: IpAddr,
+    pub firmware_version: Option<String>,
+    pub protocol_version: u32,
+    // Network diagnostics, missing for older firmware and older lines
+    pub wifi_connect_ms: Option<u32>,
+    pub dhcp_ms: Option<u32>,
+    pub rssi_dbm: Option<i8>,
+    pub bssid: Option<String>,
+    pub wifi_channel: Option<u8>,
+    pub previous_round_trip_ms: Option<u32>,
+    pub failed_wakes: Option<u32>,
+    pub last_failure: Option<WakeFailure>,
+}
+
+impl Record for CheckInRecord {
+    const FILENAME: &'static str = "check_ins.jsonl";
+
+    fn timestamp(&self) -> NaiveDateTime {
+        self.timestamp
+    }
+}
+
+/// A watering job handed out to a device.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WateringRecord {
+    pub timestamp: NaiveDateTime,
+    pub device_id: String,
+    pub plant_index: usize,
+    pub plant: String,
+    pub amount_ml: u32,
+    /// Started from the frontend instead of the schedule.
+    pub test: bool,
+}
+
+impl Record for WateringRecord {
+    const FILENAME: &'static str = "waterings.jsonl";
+
+    fn timestamp(&self) -> NaiveDateTime {
+        self.timestamp
+    }
+}
+
+/// What a device reported after watering a plant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutcomeRecord {
+    pub timestamp: NaiveDateTime,
+    pub device_id: String,
+    pub plant_index: usize,
+    // Empty if the plant is gone from the config
+    pub plant: String,
+    pub requested_ml: u32,
+    pub watered_ml: u32,
+    pub duration_ms: u32,
+    pub status: WateringStatus,
+}
+
+impl Record for OutcomeRecord {
+    const FILENAME: &'static str = "outcomes.jsonl";
+
+    fn timestamp(&self) -> NaiveDateTime {
+        self.timestamp
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parsing error in {0} line {1}: {2}")]
+    Parse(&'static str, usize, serde_json::Error),
+    #[error("Serializing error: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+/// Append-only JSON lines files in the `history` directory of the state directory,
+/// one per record type.
+#[derive(Clone)]
+pub struct HistoryStore {
+    dir: PathBuf,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl HistoryStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            dir: state_dir.join(HISTORY_DIRNAME),
+            mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Held while a backup or restore handles the history files.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn path<R: Record>(&self) -> PathBuf {
+        self.dir.join(R::FILENAME)
+    }
+
+    /// Records from `since` on, for small ranges only, exports stream instead.
+    pub fn read_since<R: Record>(&self, since: NaiveDateTime) -> Result<Vec<R>, HistoryError> {
+        let _guard = self.mutex.lock();
+        let file = match File::open(self.path::<R>()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: R = serde_json::from_str(&line)
+                .map_err(|e| HistoryError::Parse(R::FILENAME, index + 1, e))?;
+            if record.timestamp() >= since {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn append<R: Record>(&self, records: &[R]) -> Result<(), HistoryError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut lines, record)?;
+            lines.push(b'\n');
+        }
+        let _guard = self.mutex.lock();
+        fs::create_dir_all(&self.dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path::<R>())?;
+        file.write_all(&lines)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use ipnet::IpNet;
+use log::warn;
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use utoipa::ToSchema;
+
+use crate::{
+    client_ip::ClientIp,
+    error::{ApiError, ErrorCode},
+    GlobalState,
+};
+
+// Wrong secrets are forgotten after a day without another one
+const FORGET_FAILURES_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+// Idle buckets are full again after a minute, no need to keep them
+const BUCKET_IDLE: Duration = Duration::from_secs(60);
+const PRUNE_ABOVE_ENTRIES: usize = 1024;
+
+/// Per-IP limits with defaults applied, see `[rate_limits]` of the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Requests per minute, 0 disables the limit.
+    pub esp32_per_minute: u32,
+    pub frontend_per_minute: u32,
+    /// Wrong secrets in a row before an IP is locked out, 0 disables lockouts.
+    pub lockout_after_failures: u32,
+    /// First lockout, doubled with every further wrong secret.
+    pub lockout: Duration,
+    pub max_lockout: Duration,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            // Devices check in every few hours, more is a loop or an attack
+            esp32_per_minute: 12,
+            frontend_per_minute: 120,
+            lockout_after_failures: 5,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitGroup {
+    Esp32,
+    Frontend,
+}
+
+/// Token bucket, refilled continuously up to the per-minute limit.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    last_at: NaiveDateTime,
+    locked_until: Option<Instant>,
+}
+
+impl Failures {
+    fn locked_for(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    buckets: HashMap<(LimitGroup, IpAddr), Bucket>,
+    failures: HashMap<IpAddr, Failures>,
+}
+
+/// An IP with wrong secrets, locked out or not yet.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockout {
+    #[schema(value_type = String)]
+    pub ip: IpAddr,
+    /// Wrong secrets in a row.
+    pub failures: u32,
+    pub last_failure: NaiveDateTime,
+    /// Missing if the IP may still try.
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+/// Compares secrets in constant time, so response times don't reveal how much of a
+/// guess was right. Only the length can be told apart.
+pub fn secret_matches(expected: &str, provided: &str) -> bool {
+    expected.as_bytes().ct_eq(provided.as_bytes()).into()
+}
+
+pub enum SecretRejection {
+    Wrong,
+    LockedOut(Duration),
+}
+
+impl SecretRejection {
+    /// `what` names the secret, e.g. "API secret".
+    pub fn into_api_error(self, what: &str) -> ApiError {
+        match self {
+            SecretRejection::Wrong => {
+                ApiError::new(ErrorCode::Unauthorized, format!("Wrong {}", what))
+            }
+            SecretRejection::LockedOut(duration) => ApiError::new(
+                ErrorCode::LockedOut,
+                format!(
+                    "Too many wrong secrets, try again in {}s",
+                    duration.as_secs() + 1
+                ),
+            )
+            .with_retry_after(duration),
+        }
+    }
+}
+
+/// Request rates and wrong secrets per IP. Kept in memory, a restart lifts all limits.
+#[derive(Clone)]
+pub struct Limiter {
+    state: Arc<Mutex<LimiterState>>,
+    // Wrong secrets from these aren't counted, see check_secret
+    trusted_proxies: Arc<Vec<IpNet>>,
+}
+
+impl Limiter {
+    pub fn new(trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            state: Arc::default(),
+            trusted_proxies: Arc::new(trusted_proxies),
+        }
+    }
+
+    /// Whether this is the address of a trusted proxy rather than a client.
+    /// It is, if the proxy doesn't pass on client addresses or the source is `peer`.
+    /// Locking it out would lock out every client behind it.
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    // Nothing panics while holding the lock, the state stays consistent
+    fn state(&self) -> MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes a token from the IP's bucket, or tells how long until the next one.
+    pub fn check_rate(
+        &self,
+        group: LimitGroup,
+        per_minute: u32,
+        ip: IpAddr,
+    ) -> Result<(), Duration> {
+        self.check_rate_at(group, per_minute, ip, Instant::now())
+    }
+
+    fn check_rate_at(
+        &self,
+        group: LimitGroup,
+        per_minute: u32,
+        ip: IpAddr,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+        let mut state = self.state();
+        if state.buckets.len() > PRUNE_ABOVE_ENTRIES {
+            state
+                .buckets
+                .retain(|_, bucket| now - bucket.updated < BUCKET_IDLE);
+        }
+        let bucket = state.buckets.entry((group, ip)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let refill = (now - bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+    }
+
+    /// Takes whether the secret was right. A locked out IP is rejected either way,
+    /// so guessing during a lockout reveals nothing. Trusted proxies are never locked out.
+    pub fn check_secret(
+        &self,
+        limits: &RateLimits,
+        ip: IpAddr,
+        correct: bool,
+    ) -> Result<(), SecretRejection> {
+        self.check_secret_at(limits, ip, correct, Instant::now())
+    }
+
+    fn check_secret_at(
+        &self,
+        limits: &RateLimits,
+        ip: IpAddr,
+        correct: bool,
+        now: Instant,
+    ) -> Result<(), SecretRejection> {
+        if self.is_trusted_proxy(ip) {
+            return match correct {
+                true => Ok(()),
+                false => Err(SecretRejection::Wrong),
+            };
+        }
+        let mut state = self.state();
+        if let Some(duration) = state.failures.get(&ip).and_then(|f| f.locked_for(now)) {
+            return Err(SecretRejection::LockedOut(duration));
+        }
+        if correct {
+            state.failures.remove(&ip);
+            return Ok(());
+        }
+
+        state.failures.retain(|_, failures| {
+            now - failures.last < FORGET_FAILURES_AFTER || failures.locked_for(now).is_some()
+        });
+        let failures = state.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            last: now,
+            last_at: Local::now().naive_local(),
+            locked_until: None,
+        });
+        failures.count += 1;
+        failures.last = now;
+        failures.last_at = Local::now().naive_local();
+        if limits.lockout_after_failures > 0 && failures.count >= limits.lockout_after_failures {
+            let doublings = failures.count - limits.lockout_after_failures;
+            let duration = limits
+                .lockout
+                .saturating_mul(2u32.saturating_pow(doublings))
+                .min(limits.max_lockout);
+            failures.locked_until = Some(now + duration);
+            warn!(
+                "Locked out {} for {}s after {} wrong secrets",
+                ip,
+                duration.as_secs(),
+                failures.count
+            );
+        }
+        Err(SecretRejection::Wrong)
+    }
+
+    /// IPs with wrong secrets, locked out ones first.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        let now_local = Local::now().naive_local();
+        let state = self.state();
+        let mut lockouts: Vec<Lockout> = state
+            .failures
+            .iter()
+            .filter(|(_, failures)| now - failures.last < FORGET_FAILURES_AFTER)
+            .map(|(ip, failures)| Lockout {
+                ip: *ip,
+                failures: failures.count,
+                last_failure: failures.last_at,
+                locked_until: failures.locked_for(now).map(|duration| {
+                    now_local + ChronoDuration::from_std(duration).unwrap_or_default()
+                }),
+            })
+            .collect();
+        lockouts.sort_by_key(|l| (l.locked_until.is_none(), l.ip));
+        lockouts
+    }
+
+    /// Forgets the wrong secrets of an IP, returns false if there were none.
+    pub fn lift_lockout(&self, ip: IpAddr) -> bool {
+        self.state().failures.remove(&ip).is_some()
+    }
+}
+
+async fn check_rate_limit(
+    parts: &mut Parts,
+    state: &GlobalState,
+    group: LimitGroup,
+) -> Result<(), ApiError> {
+    let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+    let limits = state.config.get_rate_limits()?;
+    let per_minute = match group {
+        LimitGroup::Esp32 => limits.esp32_per_minute,
+        LimitGroup::Frontend => limits.frontend_per_minute,
+    };
+    state
+        .limiter
+        .check_rate(group, per_minute, ip)
+        .map_err(|retry_after| {
+            warn!("Rate limit of {:?} endpoints exceeded by {}", group, ip);
+            ApiError::new(
+                ErrorCode::RateLimited,
+                format!(
+                    "Too many requests, try again in {}s",
+                    retry_after.as_secs() + 1
+                ),
+            )
+            .with_retry_after(retry_after)
+        })
+}
+
+/// Extractor applying `esp32_per_minute` to the client IP.
+pub struct Esp32RateLimit;
+
+#[async_trait]
+impl FromRequestParts<GlobalState> for Esp32RateLimit {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &GlobalState,
+    ) -> Result<Self, Self::Rejection> {
+        check_rate_limit(parts, state, LimitGroup::Esp32).await?;
+        Ok(Esp32RateLimit)
+    }
+}
+
+/// Extractor applying `frontend_per_minute` to the client IP.
+pub struct FrontendRateLimit;
+
+#[async_trait]
+impl FromRequestParts<GlobalState> for FrontendRateLimit {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &GlobalState,
+    ) -> Result<Self, Self::Rejection> {
+        check_rate_limit(parts, state, LimitGroup::Frontend).await?;
+        Ok(FrontendRateLimit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT: &str = "198.51.100.7";
+    const PROXY: &str = "10.0.0.1";
+
+    fn limiter() -> Limiter {
+        Limiter::new(vec!["10.0.0.0/8".parse().unwrap()])
+    }
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            lockout_after_failures: 3,
+            lockout: Duration::from_secs(60),
+            max_lockout: Duration::from_secs(300),
+            ..RateLimits::default()
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// `Some` with the remaining lockout, `None` if the secret was just wrong.
+    fn wrong_secret(limiter: &Limiter, ip: IpAddr, now: Instant) -> Option<Duration> {
+        match limiter.check_secret_at(&limits(), ip, false, now) {
+            Ok(()) => panic!("Wrong secret accepted"),
+            Err(SecretRejection::Wrong) => None,
+            Err(SecretRejection::LockedOut(duration)) => Some(duration),
+        }
+    }
+
+    fn locked_for(limiter: &Limiter, ip: IpAddr, now: Instant) -> Option<Duration> {
+        limiter
+            .state()
+            .failures
+            .get(&ip)
+            .and_then(|f| f.locked_for(now))
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_max() {
+        let limiter = limiter();
+        let client = ip(CLIENT);
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(wrong_secret(&limiter, client, now), None);
+            assert_eq!(locked_for(&limiter, client, now), None);
+        }
+        for lockout in [60, 120, 240, 300, 300] {
+            assert_eq!(wrong_secret(&limiter, client, now), None);
+            assert_eq!(locked_for(&limiter, client, now), Some(secs(lockout)));
+            // Even the right secret is rejected until the lockout ends
+            now += secs(lockout - 1);
+            assert!(matches!(
+                limiter.check_secret_at(&limits(), client, true, now),
+                Err(SecretRejection::LockedOut(remaining)) if remaining == secs(1)
+            ));
+            now += secs(1);
+        }
+        assert!(limiter
+            .check_secret_at(&limits(), client, true, now)
+            .is_ok());
+        assert!(limiter.state().failures.is_empty());
+    }
+
+    #[test]
+    fn trusted_proxies_are_never_locked_out() {
+        let limiter = limiter();
+        let proxy = ip(PROXY);
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(wrong_secret(&limiter, proxy, now), None);
+        }
+        assert!(limiter.check_secret_at(&limits(), proxy, true, now).is_ok());
+        assert!(limiter.state().failures.is_empty());
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_a_day() {
+        let limiter = limiter();
+        let client = ip(CLIENT);
+        let start = Instant::now();
+        wrong_secret(&limiter, client, start);
+        wrong_secret(&limiter, client, start);
+
+        let next_day = start + FORGET_FAILURES_AFTER;
+        assert_eq!(wrong_secret(&limiter, client, next_day), None);
+        assert_eq!(limiter.state().failures[&client].count, 1);
+        assert_eq!(locked_for(&limiter, client, next_day), None);
+
+        // Within the day they add up
+        wrong_secret(&limiter, client, next_day + secs(60 * 60));
+        wrong_secret(&limiter, client, next_day + secs(2 * 60 * 60));
+        assert_eq!(
+            locked_for(&limiter, client, next_day + secs(2 * 60 * 60)),
+            Some(secs(60))
+        );
+    }
+
+    #[test]
+    fn token_bucket_refills_up_to_the_limit() {
+        let limiter = limiter();
+        let client = ip(CLIENT);
+        let start = Instant::now();
+        let check = |now| limiter.check_rate_at(LimitGroup::Frontend, 60, client, now);
+        for _ in 0..60 {
+            assert_eq!(check(start), Ok(()));
+        }
+        assert_eq!(check(start), Err(secs(1)));
+        assert_eq!(
+            check(start + Duration::from_millis(500)),
+            Err(Duration::from_millis(500))
+        );
+        assert_eq!(check(start + secs(1)), Ok(()));
+
+        // A long pause doesn't allow more than a minute's worth
+        let later = start + secs(10 * 60);
+        for _ in 0..60 {
+            assert_eq!(check(later), Ok(()));
+        }
+        assert!(check(later).is_err());
+
+        // Groups have buckets of their own
+        assert_eq!(
+            limiter.check_rate_at(LimitGroup::Esp32, 12, client, later),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn zero_disables_the_rate_limit() {
+        let limiter = limiter();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(
+                limiter.check_rate_at(LimitGroup::Esp32, 0, ip(CLIENT), now),
+                Ok(())
+            );
+        }
+    }
+}
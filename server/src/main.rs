@@ -1,60 +1,255 @@
-use std::net::SocketAddr;
+use std::{fs, io::ErrorKind, net::SocketAddr, path::PathBuf, process::ExitCode, sync::Arc};
 
-use api_frontend::last_seen;
+use audit::AuditLog;
 use axum::{
-    http::{StatusCode, Uri},
-    routing::{get, post},
-    Router,
+    http::{Method, Uri},
+    middleware, Extension, Router,
 };
 
-use axum_client_ip::SecureClientIpSource;
-use config::ConfigManager;
+use clap::Parser;
+use cli::{Cli, Command};
+use client_ip::{ClientIpConfig, ClientIpSource};
+use config::{ConfigManager, EnvOverrides};
+use error::ApiError;
+use firmware::FirmwareStore;
+use frontend::Frontend;
+use history::HistoryStore;
+use limits::Limiter;
 use log::info;
-use state::JsonStateManager;
+use notifier::Notifier;
+use shutdown::{persist_pending_watering_test, restore_pending_watering_test, shutdown_signal};
+use snapshot::SnapshotStore;
+use state::{JsonStateManager, StateError};
+use tokio::{sync::oneshot, task::JoinSet};
+use toml_edit::DocumentMut;
+use validation::Problem;
 
-use crate::{
-    api_esp32::dequeue_jobs,
-    api_frontend::{get_plant, set_plant_amount_ml, test_watering},
-    watering_test::PendingWateringTest,
-};
+use crate::watering_test::PendingWateringTest;
 
+mod admin;
+mod api_audit;
+mod api_backup;
+mod api_calendar;
 mod api_esp32;
+mod api_export;
+mod api_firmware;
 mod api_frontend;
+mod api_health;
+mod api_lockouts;
+mod api_network;
+mod api_reservoir;
+mod api_snapshots;
+mod atomic_file;
+mod audit;
+mod backup;
+mod calendar;
+mod cli;
+mod client_ip;
 mod config;
+mod encoding;
+mod error;
+mod firmware;
+mod frontend;
+mod history;
+mod limits;
 mod model;
+mod network;
+mod notifier;
+mod openapi;
+mod reservoir;
+mod routes;
+mod schedule;
+mod shutdown;
+mod simulate;
+mod snapshot;
 mod state;
+mod tls;
+mod validation;
+mod watch;
 mod watering_test;
 
 pub const FRONTEND_ML_MAX: usize = 1000;
+// ESP32 app partitions are smaller than that.
+pub const FIRMWARE_MAX_BYTES: usize = 4 * 1024 * 1024;
+// Mostly firmware images, a few of them need to fit.
+pub const BACKUP_MAX_BYTES: usize = 64 * 1024 * 1024;
 
 #[derive(Clone)]
 pub struct GlobalState {
     pub config: ConfigManager,
+    pub state_dir: PathBuf,
     pub json_state: JsonStateManager,
     pub pending_watering_test: PendingWateringTest,
+    pub firmware: FirmwareStore,
+    pub audit: AuditLog,
+    pub history: HistoryStore,
+    pub snapshots: SnapshotStore,
+    pub limiter: Limiter,
+    pub notifier: Notifier,
 }
 
-async fn handler_404(uri: Uri) -> (StatusCode, &'static str) {
+async fn handler_404(uri: Uri) -> ApiError {
     info!("Got request with no matched endpoint: 404 - {}", uri);
-    (StatusCode::NOT_FOUND, "Path, query or body mismatch.")
+    ApiError::not_found("Path, query or body mismatch.")
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::fmt::init();
 
-    let statemanager = JsonStateManager::new();
+    let cli = Cli::parse();
+    let statemanager = JsonStateManager::new(&cli.state_dir);
+    let env = match EnvOverrides::from_env() {
+        Ok(env) => env,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let configmanager = ConfigManager::new(cli.config.clone(), cli.bind, env);
+    match cli.command.clone().unwrap_or(Command::Serve) {
+        Command::Serve => serve(&cli, configmanager, statemanager).await,
+        Command::Validate => validate(configmanager, statemanager),
+        Command::PrintConfig => print_config(configmanager),
+        Command::Simulate(args) => simulate::simulate(configmanager, statemanager, args),
+        Command::Openapi => print_openapi(configmanager),
+        Command::Backup(args) => backup::backup_command(configmanager, &cli.state_dir, args),
+        Command::Restore(args) => backup::restore_command(configmanager, &cli.state_dir, args),
+    }
+}
+
+fn validate(configmanager: ConfigManager, statemanager: JsonStateManager) -> ExitCode {
+    let mut valid = true;
+    match configmanager.validate() {
+        Ok(problems) if problems.is_empty() => {
+            println!("Config {} is valid.", configmanager.path().display())
+        }
+        Ok(problems) => {
+            println!("Config {} is invalid.", configmanager.path().display());
+            print_problems(&configmanager, &problems);
+            valid = false;
+        }
+        Err(err) => {
+            println!(
+                "Config {} is invalid.\n{}",
+                configmanager.path().display(),
+                err
+            );
+            valid = false;
+        }
+    }
+    match statemanager.get() {
+        Ok(_) => println!("State {} is valid.", statemanager.path().display()),
+        Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => println!(
+            "State {} does not exist yet, it will be created.",
+            statemanager.path().display()
+        ),
+        Err(err) => {
+            println!(
+                "State {} is invalid.\n{}",
+                statemanager.path().display(),
+                err
+            );
+            valid = false;
+        }
+    }
+    match valid {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+fn print_problems(configmanager: &ConfigManager, problems: &[Problem]) {
+    for problem in problems {
+        println!("{}:{}", configmanager.path().display(), problem);
+    }
+}
+
+/// The effective config as TOML, overridable values are commented with their source.
+fn effective_config_toml(configmanager: &ConfigManager) -> Result<String, String> {
+    let config = configmanager.get_effective().map_err(|e| e.to_string())?;
+    let sources = configmanager.get_sources().map_err(|e| e.to_string())?;
+    let mut document: DocumentMut = toml_edit::ser::to_string_pretty(&config.redacted())
+        .map_err(|e| e.to_string())?
+        .parse()
+        .map_err(|e: toml_edit::TomlError| e.to_string())?;
+    for (key, source) in sources {
+        if let Some(value) = document.get_mut(key).and_then(|item| item.as_value_mut()) {
+            value.decor_mut().set_suffix(format!(" # {}", source));
+        }
+    }
+    Ok(document.to_string())
+}
+
+fn print_config(configmanager: ConfigManager) -> ExitCode {
+    match effective_config_toml(&configmanager) {
+        Ok(config) => {
+            print!("{}", config);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Invalid config.\n{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn print_openapi(configmanager: ConfigManager) -> ExitCode {
+    let api_prefix = match configmanager.get_api_prefix() {
+        Ok(api_prefix) => api_prefix,
+        Err(err) => {
+            eprintln!("Invalid config.\n{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    match openapi::spec(&api_prefix).to_pretty_json() {
+        Ok(spec) => {
+            println!("{}", spec);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("Could not serialize the specification.\n{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve(
+    cli: &Cli,
+    configmanager: ConfigManager,
+    statemanager: JsonStateManager,
+) -> ExitCode {
+    if let Err(err) = fs::create_dir_all(&cli.state_dir) {
+        eprintln!("Could not create state directory.\n{}", err);
+        return ExitCode::FAILURE;
+    }
     if let Err(err) = statemanager.ensure_state() {
         eprintln!("Something is wrong with the state file.\n{}", err);
-        eprintln!("Try fixing or deleting state.json and restart the program.");
-        return;
+        eprintln!(
+            "Try fixing or deleting {} and restart the program.",
+            statemanager.path().display()
+        );
+        return ExitCode::FAILURE;
+    }
+    match configmanager.validate() {
+        Ok(problems) if problems.is_empty() => {}
+        Ok(problems) => {
+            eprintln!("Invalid config.");
+            print_problems(&configmanager, &problems);
+            eprintln!("Please fix the configuration and restart the program.");
+            return ExitCode::FAILURE;
+        }
+        Err(err) => {
+            eprintln!("Invalid config.\n{}", err);
+            eprintln!("Please fix the configuration and restart the program.");
+            return ExitCode::FAILURE;
+        }
     }
-    let configmanager = ConfigManager::new();
     let plants_result = configmanager.get_plant_config();
     if let Err(err) = plants_result {
         eprintln!("Invalid config.\n{}", err);
         eprintln!("Please fix the configuration and restart the program.");
-        return;
+        return ExitCode::FAILURE;
     } else {
         println!("{} plants are configured.", plants_result.unwrap().len());
     }
@@ -63,25 +258,145 @@ async fn main() {
     let port = configmanager.get_port().unwrap();
     println!("Listening on {}:{}", host, port);
 
+    if let Err(err) = configmanager.watch() {
+        eprintln!("Config changes need a restart.\n{}", err);
+    }
+    let pending_watering_test = PendingWateringTest::new();
+    pending_watering_test.cancel_on_config_reload(configmanager.clone());
+
+    let shutdown_timeout = configmanager.get_shutdown_timeout().unwrap();
+    let tls = configmanager.get_tls().unwrap();
+    let api_prefix = configmanager.get_api_prefix().unwrap();
+    let client_ip = configmanager.get_client_ip_config().unwrap();
+    let frontend = Frontend::new(configmanager.get_frontend_dir().unwrap());
+    match &frontend {
+        Some(Frontend::Dir(dir)) => println!("Serving frontend from {}", dir.display()),
+        #[cfg(feature = "embed-frontend")]
+        Some(Frontend::Embedded) => println!("Serving embedded frontend"),
+        None => {}
+    }
+    if !api_prefix.is_empty() {
+        println!("API is mounted at {}", api_prefix);
+    }
+    let rate_limits = configmanager.get_rate_limits().unwrap();
+    let limits_on = rate_limits.esp32_per_minute > 0
+        || rate_limits.frontend_per_minute > 0
+        || rate_limits.lockout_after_failures > 0;
+    if client_ip.source == ClientIpSource::Peer && limits_on {
+        eprintln!(
+            "client_ip_source is \"peer\". Behind a reverse proxy all clients share its \
+             address and its rate limits, set the source to the header the proxy sets."
+        );
+    }
+    let notifier = Notifier::new(configmanager.clone());
     let state = GlobalState {
         config: configmanager,
+        state_dir: cli.state_dir.clone(),
         json_state: statemanager,
-        pending_watering_test: PendingWateringTest::new(),
+        pending_watering_test,
+        firmware: FirmwareStore::new(&cli.state_dir),
+        audit: AuditLog::new(&cli.state_dir),
+        history: HistoryStore::new(&cli.state_dir),
+        snapshots: SnapshotStore::new(&cli.state_dir),
+        limiter: Limiter::new(client_ip.trusted_proxies.clone()),
+        notifier,
     };
-    let app = Router::new()
-        .route("/lastseen", get(last_seen))
-        .route("/plants", get(get_plant))
-        .route("/testwatering/:plantname", post(test_watering))
-        .route("/dequeue_jobs", post(dequeue_jobs))
-        .route("/updateml/:plantname", post(set_plant_amount_ml))
+    restore_pending_watering_test(&state).await;
+    let app = routes::api_routes()
+        .into_router()
         .fallback(handler_404)
-        // Using X-Real-IP, as done by Nginx
-        .layer(SecureClientIpSource::XRealIp.into_extension())
-        .with_state(state);
+        .layer(middleware::from_fn(error::request_id))
+        .with_state(state.clone());
+    let app = match api_prefix.as_str() {
+        "" => app,
+        prefix => Router::new().nest(prefix, app),
+    };
+    let app = match frontend {
+        Some(frontend) => app.fallback(move |method: Method, uri: Uri| async move {
+            frontend.serve(method, uri).await
+        }),
+        None => app.fallback(handler_404),
+    };
 
     let addr = SocketAddr::from((host, port));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+    let (stop_accepting, stop_accepting_rx) = oneshot::channel::<()>();
+    let http_server = match axum::Server::try_bind(&addr) {
+        Ok(server) => server
+            .serve(
+                app.clone()
+                    .layer(Extension(Arc::new(client_ip)))
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(async {
+                let _ = stop_accepting_rx.await;
+            }),
+        Err(err) => {
+            eprintln!("Could not listen on {}.\n{}", addr, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut servers = JoinSet::new();
+    servers.spawn(async move { http_server.await.map_err(|e| format!("HTTP: {}", e)) });
+
+    let https_handle = axum_server::Handle::new();
+    if let Some(tls) = tls {
+        let rustls_config = match tls::load_and_watch(&tls).await {
+            Ok(rustls_config) => rustls_config,
+            Err(err) => {
+                eprintln!("Could not load TLS certificate.\n{}", err);
+                return ExitCode::FAILURE;
+            }
+        };
+        let https_addr = SocketAddr::from((host, tls.port.unwrap()));
+        println!("Listening with TLS on {}", https_addr);
+        let https_server = axum_server::bind_rustls(https_addr, rustls_config)
+            .handle(https_handle.clone())
+            .serve(
+                // Clients connect directly, headers could be set by anyone
+                app.layer(Extension(Arc::new(ClientIpConfig {
+                    source: ClientIpSource::Peer,
+                    trusted_proxies: Vec::new(),
+                })))
+                .into_make_service_with_connect_info::<SocketAddr>(),
+            );
+        servers.spawn(async move { https_server.await.map_err(|e| format!("HTTPS: {}", e)) });
+    }
+
+    tokio::select! {
+        Some(result) = servers.join_next() => {
+            match result {
+                Ok(Err(err)) => eprintln!("Server failed.\n{}", err),
+                _ => eprintln!("Server stopped unexpectedly."),
+            }
+            return ExitCode::FAILURE;
+        },
+        _ = shutdown_signal() => {},
+    }
+
+    println!("Shutting down...");
+    let _ = stop_accepting.send(());
+    https_handle.graceful_shutdown(None);
+    // Also answers a waiting /testwatering, the servers wait for it
+    persist_pending_watering_test(&state).await;
+    let stopped = tokio::time::timeout(shutdown_timeout, async {
+        let mut exit_code = ExitCode::SUCCESS;
+        while let Some(result) = servers.join_next().await {
+            if let Ok(Err(err)) = result {
+                eprintln!("Server failed while shutting down.\n{}", err);
+                exit_code = ExitCode::FAILURE;
+            }
+        }
+        exit_code
+    })
+    .await;
+    match stopped {
+        Ok(exit_code) => exit_code,
+        Err(_) => {
+            eprintln!(
+                "Requests still running after {}s, exiting anyway.",
+                shutdown_timeout.as_secs()
+            );
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,6 +1,8 @@
+use protocol::{DequeueJobs, DequeueJobsV1};
 use serde::Serialize;
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct LastSeenResponse {
     pub last_seen_timestamp: i64,
@@ -8,16 +10,21 @@ pub struct LastSeenResponse {
     pub last_watering_date: String,
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct WateringJob {
-    pub plant_index: usize,
-    pub amount_ml: u32,
+/// Dequeue response in the format of the negotiated protocol version.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum DequeueResponse {
+    V1(DequeueJobsV1),
+    V2(DequeueJobs),
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct DequeueJobs {
-    pub watering_jobs: Vec<WateringJob>,
-    pub sleep_recommendation_seconds: u64,
+pub struct DeviceInfo {
+    pub device_id: String,
+    pub last_seen_timestamp: i64,
+    pub last_battery_percentage: f32,
+    pub last_ip: String,
+    pub firmware_version: Option<String>,
+    pub protocol_version: u32,
 }
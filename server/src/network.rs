@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use protocol::WakeFailure;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::history::CheckInRecord;
+
+pub const DEFAULT_DAYS: u32 = 30;
+pub const MAX_DAYS: u32 = 365;
+// Usual rules of thumb for 2.4 GHz, the ESP32 needs a bit more than a phone
+const GOOD_RSSI_DBM: i8 = -67;
+const FAIR_RSSI_DBM: i8 = -75;
+
+/// How well the device reaches the router, judged from the median RSSI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SignalQuality {
+    /// -67 dBm or better
+    Good,
+    /// Down to -75 dBm, occasional retries are expected
+    Fair,
+    /// Below -75 dBm, move the box closer to the router or add a repeater
+    Weak,
+    /// No check-in with diagnostics in the period, e.g. older firmware
+    Unknown,
+}
+
+impl SignalQuality {
+    fn from_rssi(rssi_dbm: i8) -> Self {
+        if rssi_dbm >= GOOD_RSSI_DBM {
+            SignalQuality::Good
+        } else if rssi_dbm >= FAIR_RSSI_DBM {
+            SignalQuality::Fair
+        } else {
+            SignalQuality::Weak
+        }
+    }
+}
+
+/// Network diagnostics of a single check-in.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkSample {
+    pub timestamp: i64,
+    pub wifi_connect_ms: Option<u32>,
+    pub dhcp_ms: Option<u32>,
+    pub rssi_dbm: Option<i8>,
+    pub bssid: Option<String>,
+    pub wifi_channel: Option<u8>,
+    /// Of the previous check-in, the device can't time the one it is sending
+    pub previous_round_trip_ms: Option<u32>,
+}
+
+/// Network diagnostics of a device aggregated over a day.
+/// Averages are `None` if no check-in of the day reported the value.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkDay {
+    pub date: NaiveDate,
+    pub check_ins: u32,
+    /// Wakes without check-in, counted on the day of the next successful one
+    pub failed_wakes: u32,
+    pub last_failure: Option<WakeFailure>,
+    pub avg_rssi_dbm: Option<i8>,
+    pub min_rssi_dbm: Option<i8>,
+    pub avg_wifi_connect_ms: Option<u32>,
+    pub max_wifi_connect_ms: Option<u32>,
+    pub avg_dhcp_ms: Option<u32>,
+    pub avg_round_trip_ms: Option<u32>,
+    /// Access points the device connected to, more than one hints at roaming
+    pub bssids: Vec<String>,
+    pub wifi_channels: Vec<u8>,
+}
+
+/// Network trend of a device, oldest day first.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkTrend {
+    pub device_id: String,
+    pub signal: SignalQuality,
+    pub median_rssi_dbm: Option<i8>,
+    pub latest: Option<NetworkSample>,
+    pub days: Vec<NetworkDay>,
+}
+
+#[derive(Default)]
+struct DayAccumulator {
+    check_ins: u32,
+    failed_wakes: u32,
+    last_failure: Option<WakeFailure>,
+    rssi_dbm: Vec<i8>,
+    wifi_connect_ms: Vec<u32>,
+    dhcp_ms: Vec<u32>,
+    round_trip_ms: Vec<u32>,
+    bssids: Vec<String>,
+    wifi_channels: Vec<u8>,
+}
+
+impl DayAccumulator {
+    fn add(&mut self, check_in: &CheckInRecord) {
+        self.check_ins += 1;
+        self.failed_wakes += check_in.failed_wakes.unwrap_or(0);
+        if check_in.last_failure.is_some() {
+            self.last_failure = check_in.last_failure;
+        }
+        self.rssi_dbm.extend(check_in.rssi_dbm);
+        self.wifi_connect_ms.extend(check_in.wifi_connect_ms);
+        self.dhcp_ms.extend(check_in.dhcp_ms);
+        self.round_trip_ms.extend(check_in.previous_round_trip_ms);
+        if let Some(bssid) = &check_in.bssid {
+            if !self.bssids.contains(bssid) {
+                self.bssids.push(bssid.clone());
+            }
+        }
+        if let Some(channel) = check_in.wifi_channel {
+            if !self.wifi_channels.contains(&channel) {
+                self.wifi_channels.push(channel);
+            }
+        }
+    }
+
+    fn finish(self, date: NaiveDate) -> NetworkDay {
+        let avg_rssi_dbm = average(self.rssi_dbm.iter().map(|&v| i64::from(v)))
+            .map(|avg| avg.clamp(i64::from(i8::MIN), 0) as i8);
+        NetworkDay {
+            date,
+            check_ins: self.check_ins,
+            failed_wakes: self.failed_wakes,
+            last_failure: self.last_failure,
+            avg_rssi_dbm,
+            min_rssi_dbm: self.rssi_dbm.iter().copied().min(),
+            avg_wifi_connect_ms: average_ms(&self.wifi_connect_ms),
+            max_wifi_connect_ms: self.wifi_connect_ms.iter().copied().max(),
+            avg_dhcp_ms: average_ms(&self.dhcp_ms),
+            avg_round_trip_ms: average_ms(&self.round_trip_ms),
+            bssids: self.bssids,
+            wifi_channels: self.wifi_channels,
+        }
+    }
+}
+
+fn average(values: impl ExactSizeIterator<Item = i64>) -> Option<i64> {
+    let count = values.len() as i64;
+    (count > 0).then(|| values.sum::<i64>() / count)
+}
+
+fn average_ms(values: &[u32]) -> Option<u32> {
+    average(values.iter().map(|&v| i64::from(v))).map(|avg| avg as u32)
+}
+
+/// Aggregates the check-ins of one device per day, they have to be in chronological order.
+pub fn trend(device_id: &str, check_ins: &[CheckInRecord]) -> NetworkTrend {
+    let check_ins: Vec<&CheckInRecord> = check_ins
+        .iter()
+        .filter(|c| c.device_id == device_id)
+        .collect();
+
+    let mut days: BTreeMap<NaiveDate, DayAccumulator> = BTreeMap::new();
+    for check_in in &check_ins {
+        days.entry(check_in.timestamp.date())
+            .or_default()
+            .add(check_in);
+    }
+
+    let mut rssi: Vec<i8> = check_ins.iter().filter_map(|c| c.rssi_dbm).collect();
+    rssi.sort_unstable();
+    let median_rssi_dbm = rssi.get(rssi.len() / 2).copied();
+
+    NetworkTrend {
+        device_id: device_id.to_string(),
+        signal: median_rssi_dbm.map_or(SignalQuality::Unknown, SignalQuality::from_rssi),
+        median_rssi_dbm,
+        latest: check_ins.last().map(|c| NetworkSample {
+            timestamp: c.timestamp.and_utc().timestamp(),
+            wifi_connect_ms: c.wifi_connect_ms,
+            dhcp_ms: c.dhcp_ms,
+            rssi_dbm: c.rssi_dbm,
+            bssid: c.bssid.clone(),
+            wifi_channel: c.wifi_channel,
+            previous_round_trip_ms: c.previous_round_trip_ms,
+        }),
+        days: days
+            .into_iter()
+            .map(|(date, day)| day.finish(date))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 5, day)
+            .unwrap()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+    }
+
+    fn check_in(timestamp: NaiveDateTime, rssi_dbm: Option<i8>) -> CheckInRecord {
+        CheckInRecord {
+            timestamp,
+            device_id: "default".into(),
+            battery_percentage: 80.0,
+            ip: "192.168.1.20".parse().unwrap(),
+            firmware_version: None,
+            protocol_version: protocol::PROTOCOL_VERSION,
+            wifi_connect_ms: None,
+            dhcp_ms: None,
+            rssi_dbm,
+            bssid: None,
+            wifi_channel: None,
+            previous_round_trip_ms: None,
+            failed_wakes: None,
+            last_failure: None,
+        }
+    }
+
+    #[test]
+    fn median_of_all_check_ins() {
+        let check_ins = [
+            check_in(at(1, 9), Some(-80)),
+            check_in(at(1, 12), None),
+            check_in(at(2, 9), Some(-60)),
+            check_in(at(3, 9), Some(-70)),
+            check_in(at(3, 12), Some(-72)),
+        ];
+        let result = trend("default", &check_ins);
+        // The upper one of the two in the middle
+        assert_eq!(result.median_rssi_dbm, Some(-70));
+        assert_eq!(result.signal, SignalQuality::Fair);
+        assert_eq!(result.latest.unwrap().rssi_dbm, Some(-72));
+    }
+
+    #[test]
+    fn no_diagnostics_is_unknown() {
+        let result = trend("default", &[check_in(at(1, 9), None)]);
+        assert_eq!(result.median_rssi_dbm, None);
+        assert_eq!(result.signal, SignalQuality::Unknown);
+        assert_eq!(result.days[0].avg_rssi_dbm, None);
+        assert_eq!(result.days[0].check_ins, 1);
+    }
+
+    #[test]
+    fn averages_per_day() {
+        let mut first = check_in(at(1, 9), Some(-70));
+        first.wifi_connect_ms = Some(1000);
+        first.previous_round_trip_ms = Some(300);
+        let mut second = check_in(at(1, 12), Some(-71));
+        second.wifi_connect_ms = Some(2001);
+        second.failed_wakes = Some(2);
+        second.last_failure = Some(WakeFailure::IpTimeout);
+        let third = check_in(at(2, 9), Some(-50));
+        let mut other_device = check_in(at(1, 10), Some(-90));
+        other_device.device_id = "balcony".into();
+
+        let result = trend("default", &[first, other_device, second, third]);
+        let dates: Vec<_> = result.days.iter().map(|d| d.date).collect();
+        assert_eq!(dates, [at(1, 0).date(), at(2, 0).date()]);
+        let day = &result.days[0];
+        assert_eq!(day.check_ins, 2);
+        assert_eq!(day.failed_wakes, 2);
+        assert_eq!(day.last_failure, Some(WakeFailure::IpTimeout));
+        // Rounded towards zero
+        assert_eq!(day.avg_rssi_dbm, Some(-70));
+        assert_eq!(day.min_rssi_dbm, Some(-71));
+        assert_eq!(day.avg_wifi_connect_ms, Some(1500));
+        assert_eq!(day.max_wifi_connect_ms, Some(2001));
+        assert_eq!(day.avg_round_trip_ms, Some(300));
+        assert_eq!(day.avg_dhcp_ms, None);
+        assert_eq!(result.days[1].avg_rssi_dbm, Some(-50));
+    }
+
+    #[test]
+    fn average_rssi_is_clamped() {
+        // Bogus positive readings don't make a day look better than 0 dBm
+        let check_ins = [
+            check_in(at(1, 9), Some(100)),
+            check_in(at(1, 12), Some(120)),
+        ];
+        let result = trend("default", &check_ins);
+        assert_eq!(result.days[0].avg_rssi_dbm, Some(0));
+        let check_ins = [
+            check_in(at(1, 9), Some(i8::MIN)),
+            check_in(at(1, 12), Some(i8::MIN)),
+        ];
+        let result = trend("default", &check_ins);
+        assert_eq!(result.days[0].avg_rssi_dbm, Some(i8::MIN));
+    }
+
+    #[test]
+    fn bssids_and_channels_are_listed_once() {
+        let check_ins: Vec<_> = [("aa:bb", 1), ("cc:dd", 6), ("aa:bb", 1)]
+            .into_iter()
+            .enumerate()
+            .map(|(hour, (bssid, channel))| {
+                let mut check_in = check_in(at(1, hour as u32), Some(-60));
+                check_in.bssid = Some(bssid.into());
+                check_in.wifi_channel = Some(channel);
+                check_in
+            })
+            .collect();
+        let result = trend("default", &check_ins);
+        assert_eq!(result.days[0].bssids, ["aa:bb", "cc:dd"]);
+        assert_eq!(result.days[0].wifi_channels, [1, 6]);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use chrono::{Local, NaiveDateTime};
+use hyper::{
+    client::HttpConnector,
+    header::{CONTENT_TYPE, USER_AGENT},
+    Body, Client, Method, Request, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::config::ConfigManager;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something a human has to take care of.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum Alert {
+    #[serde(rename_all = "camelCase")]
+    ReservoirLow {
+        device_id: String,
+        level_ml: u32,
+        capacity_ml: u32,
+    },
+}
+
+impl Alert {
+    pub fn message(&self) -> String {
+        match self {
+            Alert::ReservoirLow {
+                device_id,
+                level_ml,
+                capacity_ml,
+            } => format!(
+                "Reservoir of {} is low, about {}ml of {}ml left, refill it",
+                device_id, level_ml, capacity_ml
+            ),
+        }
+    }
+}
+
+/// JSON body of the webhook, the message is for chat-like receivers.
+#[derive(Serialize)]
+struct Payload<'a> {
+    message: String,
+    #[serde(flatten)]
+    alert: &'a Alert,
+}
+
+/// Outcome of the last alert posted to the webhook.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub at: NaiveDateTime,
+    pub error: Option<String>,
+}
+
+/// Posts alerts to the webhook of `[notifier]`, without one they are only logged.
+/// The URL is read on every alert, so config changes apply without a restart.
+#[derive(Clone)]
+pub struct Notifier {
+    config: ConfigManager,
+    client: Client<HttpsConnector<HttpConnector>>,
+    last_delivery: Arc<Mutex<Option<Delivery>>>,
+}
+
+impl Notifier {
+    pub fn new(config: ConfigManager) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            config,
+            client: Client::builder().build(connector),
+            last_delivery: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Posts the alert in the background, so it doesn't hold up the request raising it.
+    pub fn send(&self, alert: Alert) {
+        let url = match self.config.get_notifier() {
+            Ok(Some(notifier)) => notifier.webhook_url,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Could not send alert, the config can't be read: {}", e);
+                return;
+            }
+        };
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let error = notifier.post(&url, &alert).await.err();
+            match &error {
+                None => info!("Sent alert to the webhook: {}", alert.message()),
+                Some(e) => warn!("Could not send alert to the webhook: {}", e),
+            }
+            *notifier
+                .last_delivery
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(Delivery {
+                at: Local::now().naive_local(),
+                error,
+            });
+        });
+    }
+
+    async fn post(&self, url: &str, alert: &Alert) -> Result<(), String> {
+        let uri: Uri = url.parse().map_err(|e| format!("Invalid URL: {}", e))?;
+        let payload = Payload {
+            message: alert.message(),
+            alert,
+        };
+        let body = serde_json::to_vec(&payload).map_err(|e| e.to_string())?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .header(USER_AGENT, concat!("evergreen/", env!("CARGO_PKG_VERSION")))
+            .body(Body::from(body))
+            .map_err(|e| e.to_string())?;
+        let response = timeout(SEND_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| format!("No answer within {}s", SEND_TIMEOUT.as_secs()))?
+            .map_err(|e| e.to_string())?;
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("Webhook answered {}", response.status())),
+        }
+    }
+
+    pub fn last_delivery(&self) -> Option<Delivery> {
+        self.last_delivery
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
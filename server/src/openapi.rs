@@ -0,0 +1,279 @@
+use axum::{extract::State, response::Html, Json};
+use log::error;
+use utoipa::{
+    openapi::{
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        server::Server,
+    },
+    Modify, OpenApi,
+};
+
+use crate::{
+    api_audit, api_backup, api_calendar, api_esp32, api_export, api_firmware, api_frontend,
+    api_health, api_lockouts, api_network, api_reservoir, api_snapshots, audit, backup, config,
+    error, firmware, limits, model, network, reservoir, GlobalState,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Evergreen 5000",
+        description = "API of the Evergreen 5000 watering system, used by the frontend and the ESP32.",
+        license(name = "MIT")
+    ),
+    paths(
+        api_health::healthz,
+        api_health::readyz,
+        api_frontend::last_seen,
+        api_frontend::get_plant,
+        api_frontend::get_devices,
+        api_network::network_trend,
+        api_reservoir::list_reservoirs,
+        api_reservoir::refill_reservoir,
+        api_frontend::test_watering,
+        api_frontend::set_plant_amount_ml,
+        api_audit::list_audit,
+        api_audit::revert_change,
+        api_snapshots::list_snapshots,
+        api_snapshots::save_snapshot,
+        api_snapshots::diff_snapshot,
+        api_snapshots::apply_snapshot,
+        api_export::export,
+        api_calendar::calendar_feed,
+        api_esp32::dequeue_jobs,
+        api_esp32::report_watering,
+        api_firmware::download_firmware,
+        api_firmware::list_firmware,
+        api_firmware::upload_firmware,
+        api_firmware::rollback_firmware,
+        api_firmware::clear_rollback,
+        api_lockouts::list_lockouts,
+        api_lockouts::lift_lockout,
+        api_backup::download_backup,
+        api_backup::restore_backup,
+    ),
+    components(schemas(
+        error::ErrorBody,
+        error::ErrorCode,
+        api_health::Readiness,
+        api_health::ComponentHealth,
+        api_health::HealthStatus,
+        model::LastSeenResponse,
+        model::DeviceInfo,
+        network::NetworkTrend,
+        network::NetworkDay,
+        network::NetworkSample,
+        network::SignalQuality,
+        reservoir::ReservoirStatus,
+        model::DequeueResponse,
+        config::PlantConfig,
+        audit::AuditEntry,
+        audit::Change,
+        api_snapshots::SnapshotInfo,
+        api_snapshots::PlantDiff,
+        api_export::ExportKind,
+        firmware::FirmwareImage,
+        firmware::FirmwareManifest,
+        limits::Lockout,
+        backup::BackupManifest,
+        backup::BackupFile,
+        protocol::DequeueJobs,
+        protocol::DequeueJobsV1,
+        protocol::WateringJob,
+        protocol::DeviceSettings,
+        protocol::FirmwareUpdate,
+        protocol::WateringReport,
+        protocol::WateringOutcome,
+        protocol::WateringStatus,
+        protocol::WakeFailure,
+    )),
+    modifiers(&AdminSecurity),
+    tags(
+        (name = "frontend", description = "Used by the web frontend"),
+        (name = "esp32", description = "Used by the ESP32, authenticated with `api_secret`"),
+        (name = "admin", description = "Needs `Authorization: Bearer <admin_secret>`"),
+        (name = "health", description = "For monitoring and process supervisors"),
+    )
+)]
+pub struct ApiDoc;
+
+struct AdminSecurity;
+
+impl Modify for AdminSecurity {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_secret",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// The spec with the API prefix as server, so "try it out" hits the right URLs.
+pub fn spec(api_prefix: &str) -> utoipa::openapi::OpenApi {
+    let mut spec = ApiDoc::openapi();
+    if !api_prefix.is_empty() {
+        spec.servers = Some(vec![Server::new(api_prefix)]);
+    }
+    spec
+}
+
+pub async fn openapi_json(state: State<GlobalState>) -> Json<utoipa::openapi::OpenApi> {
+    let api_prefix = state.config.get_api_prefix().unwrap_or_else(|e| {
+        error!("Could not read api_prefix: {}", e);
+        String::new()
+    });
+    Json(spec(&api_prefix))
+}
+
+/// Swagger UI, loaded from a CDN to keep the binary small.
+pub async fn docs() -> Html<&'static str> {
+    Html(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8" />
+  <meta name="viewport" content="width=device-width, initial-scale=1" />
+  <title>Evergreen 5000 API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    // Relative, so it works under any api_prefix
+    window.ui = SwaggerUIBundle({ url: 'openapi.json', dom_id: '#swagger-ui' });
+  </script>
+</body>
+</html>
+"#,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use utoipa::openapi::{path::ParameterIn, PathItemType};
+
+    use super::*;
+    use crate::routes::api_routes;
+
+    fn method_name(item_type: &PathItemType) -> &'static str {
+        match item_type {
+            PathItemType::Get => "GET",
+            PathItemType::Post => "POST",
+            PathItemType::Put => "PUT",
+            PathItemType::Delete => "DELETE",
+            PathItemType::Options => "OPTIONS",
+            PathItemType::Head => "HEAD",
+            PathItemType::Patch => "PATCH",
+            PathItemType::Trace => "TRACE",
+            PathItemType::Connect => "CONNECT",
+        }
+    }
+
+    /// `/a/:b` to `/a/{b}`
+    fn to_openapi_path(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn routes_match_spec() {
+        let routed: BTreeSet<(String, String)> = api_routes()
+            .routes()
+            .iter()
+            .map(|(method, path)| (method.to_string(), to_openapi_path(path)))
+            .collect();
+        let documented: BTreeSet<(String, String)> = ApiDoc::openapi()
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                item.operations
+                    .keys()
+                    .map(|method| (method_name(method).to_string(), path.clone()))
+            })
+            .collect();
+
+        let undocumented: Vec<_> = routed.difference(&documented).collect();
+        let unrouted: Vec<_> = documented.difference(&routed).collect();
+        assert!(
+            undocumented.is_empty(),
+            "Routes missing in the spec: {:?}",
+            undocumented
+        );
+        assert!(unrouted.is_empty(), "Spec has no route for: {:?}", unrouted);
+    }
+
+    #[test]
+    fn path_parameters_match_spec() {
+        for (path, item) in ApiDoc::openapi().paths.paths {
+            let in_path: BTreeSet<&str> = path
+                .split('/')
+                .filter_map(|s| s.strip_prefix('{')?.strip_suffix('}'))
+                .collect();
+            for (method, operation) in &item.operations {
+                let declared: BTreeSet<&str> = operation
+                    .parameters
+                    .iter()
+                    .flatten()
+                    .filter(|p| p.parameter_in == ParameterIn::Path)
+                    .map(|p| p.name.as_str())
+                    .collect();
+                assert_eq!(
+                    in_path,
+                    declared,
+                    "Path parameters of {} {}",
+                    method_name(method),
+                    path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn schemas_are_complete() {
+        // Types missing in components() end up as dangling references
+        let spec = serde_json::to_value(spec("/api")).unwrap();
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        let json = spec.to_string();
+        for reference in json.split("\"$ref\":\"#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().unwrap();
+            assert!(schemas.contains_key(name), "Schema {} is missing", name);
+        }
+        assert_eq!(spec["servers"][0]["url"], "/api");
+    }
+
+    #[test]
+    fn errors_use_error_body() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            // Readiness reports failing components in its own body
+            if path == "/readyz" {
+                continue;
+            }
+            for (method, operation) in item.as_object().unwrap() {
+                for (status, response) in operation["responses"].as_object().unwrap() {
+                    if !status.starts_with('4') && !status.starts_with('5') {
+                        continue;
+                    }
+                    assert_eq!(
+                        response["content"]["application/json"]["schema"]["$ref"],
+                        "#/components/schemas/ErrorBody",
+                        "Response {} of {} {}",
+                        status,
+                        method,
+                        path
+                    );
+                }
+            }
+        }
+    }
+}
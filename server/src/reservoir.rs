@@ -0,0 +1,158 @@
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use protocol::WateringJob;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    config::ReservoirConfig,
+    notifier::Alert,
+    state::{JsonState, ReservoirState},
+};
+
+/// Estimated level of the reservoir of a device.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReservoirStatus {
+    pub device_id: String,
+    pub capacity_ml: u32,
+    pub level_ml: u32,
+    pub low_level_ml: u32,
+    pub low: bool,
+    /// Jobs are skipped while low
+    pub skip_jobs_when_low: bool,
+    /// `None` if never refilled, it is assumed full then
+    pub last_refill: Option<NaiveDateTime>,
+    pub low_since: Option<NaiveDateTime>,
+}
+
+/// The state of a reservoir, a full one if it was never refilled.
+/// Levels above a since reduced capacity are cut off.
+fn current(json_state: &JsonState, device_id: &str, config: &ReservoirConfig) -> ReservoirState {
+    let mut reservoir = json_state
+        .reservoirs
+        .get(device_id)
+        .cloned()
+        .unwrap_or(ReservoirState {
+            level_ml: config.capacity_ml,
+            last_refill: None,
+            low_since: None,
+        });
+    reservoir.level_ml = reservoir.level_ml.min(config.capacity_ml);
+    reservoir
+}
+
+fn is_low(level_ml: u32, config: &ReservoirConfig) -> bool {
+    level_ml <= config.low_level_ml()
+}
+
+/// Alerts once when the level drops to the low level, until it is above again.
+fn set_level(
+    json_state: &mut JsonState,
+    device_id: &str,
+    config: &ReservoirConfig,
+    mut reservoir: ReservoirState,
+    level_ml: u32,
+    now: NaiveDateTime,
+) -> Option<Alert> {
+    reservoir.level_ml = level_ml.min(config.capacity_ml);
+    let mut alert = None;
+    match (is_low(reservoir.level_ml, config), reservoir.low_since) {
+        (true, None) => {
+            warn!(
+                "Reservoir of {} is low, about {}ml of {}ml left, refill it",
+                device_id, reservoir.level_ml, config.capacity_ml
+            );
+            reservoir.low_since = Some(now);
+            alert = Some(Alert::ReservoirLow {
+                device_id: device_id.into(),
+                level_ml: reservoir.level_ml,
+                capacity_ml: config.capacity_ml,
+            });
+        }
+        (false, Some(_)) => reservoir.low_since = None,
+        _ => {}
+    }
+    json_state.reservoirs.insert(device_id.into(), reservoir);
+    alert
+}
+
+pub fn status(
+    json_state: &JsonState,
+    device_id: &str,
+    config: &ReservoirConfig,
+) -> ReservoirStatus {
+    let reservoir = current(json_state, device_id, config);
+    ReservoirStatus {
+        device_id: device_id.into(),
+        capacity_ml: config.capacity_ml,
+        level_ml: reservoir.level_ml,
+        low_level_ml: config.low_level_ml(),
+        low: is_low(reservoir.level_ml, config),
+        skip_jobs_when_low: config.skip_jobs_when_low,
+        last_refill: reservoir.last_refill,
+        low_since: reservoir.low_since,
+    }
+}
+
+/// Whether planned jobs must not be handed out, so the pumps don't run dry.
+pub fn skips_jobs(json_state: &JsonState, device_id: &str, config: &ReservoirConfig) -> bool {
+    config.skip_jobs_when_low && is_low(current(json_state, device_id, config).level_ml, config)
+}
+
+/// Subtracts the amounts of handed out jobs, reports correct it later.
+/// Returns an alert if the reservoir just became low.
+#[must_use]
+pub fn draw(
+    json_state: &mut JsonState,
+    device_id: &str,
+    config: &ReservoirConfig,
+    jobs: &[WateringJob],
+    now: NaiveDateTime,
+) -> Option<Alert> {
+    if jobs.is_empty() {
+        return None;
+    }
+    let reservoir = current(json_state, device_id, config);
+    let drawn_ml = jobs
+        .iter()
+        .map(|job| job.amount_ml)
+        .fold(0u32, u32::saturating_add);
+    let level_ml = reservoir.level_ml.saturating_sub(drawn_ml);
+    set_level(json_state, device_id, config, reservoir, level_ml, now)
+}
+
+/// Adds back what a device reported it didn't deliver of a handed out job.
+/// Returns an alert like [`draw`], in case the low level was raised.
+#[must_use]
+pub fn give_back(
+    json_state: &mut JsonState,
+    device_id: &str,
+    config: &ReservoirConfig,
+    undelivered_ml: u32,
+    now: NaiveDateTime,
+) -> Option<Alert> {
+    let reservoir = current(json_state, device_id, config);
+    let level_ml = reservoir.level_ml.saturating_add(undelivered_ml);
+    set_level(json_state, device_id, config, reservoir, level_ml, now)
+}
+
+/// Sets the level after the reservoir was filled up, returns the old level
+/// and an alert if it was only filled up to the low level.
+pub fn refill(
+    json_state: &mut JsonState,
+    device_id: &str,
+    config: &ReservoirConfig,
+    level_ml: u32,
+    now: NaiveDateTime,
+) -> (u32, Option<Alert>) {
+    let mut reservoir = current(json_state, device_id, config);
+    let old_ml = reservoir.level_ml;
+    reservoir.last_refill = Some(now);
+    info!(
+        "Reservoir of {} refilled from {}ml to {}ml",
+        device_id, old_ml, level_ml
+    );
+    let alert = set_level(json_state, device_id, config, reservoir, level_ml, now);
+    (old_ml, alert)
+}
@@ -0,0 +1,127 @@
+use axum::{
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    http::Method,
+    routing::{get, on, MethodFilter},
+    Router,
+};
+use log::debug;
+
+use crate::{
+    api_audit::{list_audit, revert_change},
+    api_backup::{download_backup, restore_backup},
+    api_calendar::calendar_feed,
+    api_esp32::{dequeue_jobs, report_watering},
+    api_export::export,
+    api_firmware::{
+        clear_rollback, download_firmware, list_firmware, rollback_firmware, upload_firmware,
+    },
+    api_frontend::{get_devices, get_plant, last_seen, set_plant_amount_ml, test_watering},
+    api_health::{healthz, readyz},
+    api_lockouts::{lift_lockout, list_lockouts},
+    api_network::network_trend,
+    api_reservoir::{list_reservoirs, refill_reservoir},
+    api_snapshots::{apply_snapshot, diff_snapshot, list_snapshots, save_snapshot},
+    openapi, GlobalState, BACKUP_MAX_BYTES, FIRMWARE_MAX_BYTES,
+};
+
+/// Router which remembers its routes, so they can be compared with the OpenAPI spec.
+pub struct ApiRouter {
+    router: Router<GlobalState>,
+    routes: Vec<(Method, &'static str)>,
+}
+
+impl ApiRouter {
+    fn new() -> Self {
+        Self {
+            router: Router::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    fn route<H, T>(mut self, method: Method, path: &'static str, handler: H) -> Self
+    where
+        H: Handler<T, GlobalState>,
+        T: 'static,
+    {
+        let filter = MethodFilter::try_from(method.clone()).expect("Unsupported method");
+        self.router = self.router.route(path, on(filter, handler));
+        self.routes.push((method, path));
+        self
+    }
+
+    /// Method and path of every documented route, paths in axum syntax.
+    #[cfg(test)]
+    pub fn routes(&self) -> &[(Method, &'static str)] {
+        &self.routes
+    }
+
+    /// The router including the spec and docs, which aren't part of the spec.
+    pub fn into_router(self) -> Router<GlobalState> {
+        for (method, path) in &self.routes {
+            debug!("Route {} {}", method, path);
+        }
+        self.router
+            .route("/openapi.json", get(openapi::openapi_json))
+            .route("/docs", get(openapi::docs))
+    }
+}
+
+/// All API endpoints, relative to `api_prefix`.
+/// Every route needs a `#[utoipa::path]` and an entry in [`openapi::ApiDoc`].
+pub fn api_routes() -> ApiRouter {
+    ApiRouter::new()
+        .route(Method::GET, "/healthz", healthz)
+        .route(Method::GET, "/readyz", readyz)
+        .route(Method::GET, "/lastseen", last_seen)
+        .route(Method::GET, "/plants", get_plant)
+        .route(Method::GET, "/devices", get_devices)
+        .route(Method::GET, "/devices/:device_id/network", network_trend)
+        .route(Method::GET, "/reservoirs", list_reservoirs)
+        .route(
+            Method::POST,
+            "/devices/:device_id/reservoir/refill",
+            refill_reservoir,
+        )
+        .route(Method::POST, "/testwatering/:plantname", test_watering)
+        .route(Method::POST, "/dequeue_jobs", dequeue_jobs)
+        .route(Method::POST, "/report", report_watering)
+        .route(Method::POST, "/updateml/:plantname", set_plant_amount_ml)
+        .route(Method::GET, "/audit", list_audit)
+        .route(Method::POST, "/audit/:id/revert", revert_change)
+        .route(Method::GET, "/snapshots", list_snapshots)
+        .route(Method::POST, "/snapshots/:name", save_snapshot)
+        .route(Method::GET, "/snapshots/:name/diff", diff_snapshot)
+        .route(Method::POST, "/snapshots/:name/apply", apply_snapshot)
+        .route(Method::GET, "/export/:kind", export)
+        .route(Method::GET, "/calendar.ics", calendar_feed)
+        .route(
+            Method::GET,
+            "/firmware/:device_id/:version",
+            download_firmware,
+        )
+        .route(Method::GET, "/admin/firmware", list_firmware)
+        .route(
+            Method::POST,
+            "/admin/firmware/:device_id/:version",
+            upload_firmware.layer(DefaultBodyLimit::max(FIRMWARE_MAX_BYTES)),
+        )
+        .route(
+            Method::POST,
+            "/admin/firmware/:device_id/rollback/:version",
+            rollback_firmware,
+        )
+        .route(
+            Method::DELETE,
+            "/admin/firmware/:device_id/rollback",
+            clear_rollback,
+        )
+        .route(Method::GET, "/admin/lockouts", list_lockouts)
+        .route(Method::DELETE, "/admin/lockouts/:ip", lift_lockout)
+        .route(Method::GET, "/admin/backup", download_backup)
+        .route(
+            Method::POST,
+            "/admin/restore",
+            restore_backup.layer(DefaultBodyLimit::max(BACKUP_MAX_BYTES)),
+        )
+}
@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use chrono::{Duration as ChronoDuration, NaiveDate, NaiveDateTime};
+use protocol::WateringJob;
+
+use crate::{config::PlantConfig, state::JsonState};
+
+/// Jobs and sleep recommendation of a single check-in.
+pub struct CheckInPlan {
+    pub watering_jobs: Vec<WateringJob>,
+    pub sleep_recommendation_seconds: u64,
+}
+
+fn next_watering_sleep_time(last_date: NaiveDate, now: NaiveDateTime) -> ChronoDuration {
+    // ChronoDuration can be negative
+    let last_watering = last_date.and_hms_opt(9, 0, 0).expect("Invalid hms");
+    let h24 = ChronoDuration::from_std(Duration::from_secs(24 * 60 * 60)).unwrap();
+    let next_watering = last_watering + h24;
+
+    // 9h - 10h on same day => -1h to "wait"
+    next_watering - now
+}
+
+/// Plans a check-in at `now` and marks planned waterings in the state.
+/// Used by the dequeue endpoint and the simulation alike.
+pub fn plan_check_in(
+    json_state: &mut JsonState,
+    plant_config: &[PlantConfig],
+    now: NaiveDateTime,
+) -> CheckInPlan {
+    let watering_sleep_time_sec: i64 =
+        next_watering_sleep_time(json_state.last_planned_watering, now).num_seconds();
+    let watering_jobs: Vec<WateringJob> = match watering_sleep_time_sec.is_negative() {
+        true => {
+            json_state.last_planned_watering = now.date();
+            plant_config
+                .iter()
+                .enumerate()
+                .map(|(index, conf)| WateringJob {
+                    plant_index: index,
+                    amount_ml: conf.amount_ml,
+                })
+                .collect()
+        }
+        false => Vec::new(),
+    };
+
+    let sleep_recommendation = next_watering_sleep_time(json_state.last_planned_watering, now);
+    let sleep_recommendation_seconds = if sleep_recommendation.num_seconds().is_negative() {
+        0
+    } else {
+        sleep_recommendation.num_seconds() as u64
+    };
+    CheckInPlan {
+        watering_jobs,
+        sleep_recommendation_seconds,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plants() -> Vec<PlantConfig> {
+        vec![
+            PlantConfig {
+                amount_ml: 100,
+                name: "Karsten".into(),
+            },
+            PlantConfig {
+                amount_ml: 500,
+                name: "Bazil".into(),
+            },
+        ]
+    }
+
+    fn at(date: &str, time: &str) -> NaiveDateTime {
+        format!("{}T{}", date, time).parse().unwrap()
+    }
+
+    fn state_watered_on(date: &str) -> JsonState {
+        JsonState {
+            last_planned_watering: date.parse().unwrap(),
+            ..JsonState::default()
+        }
+    }
+
+    fn amounts(plan: &CheckInPlan) -> Vec<(usize, u32)> {
+        plan.watering_jobs
+            .iter()
+            .map(|job| (job.plant_index, job.amount_ml))
+            .collect()
+    }
+
+    #[test]
+    fn before_nine_sleeps_until_nine() {
+        let mut state = state_watered_on("2024-05-01");
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-02", "08:00:00"));
+        assert_eq!(amounts(&plan), vec![]);
+        assert_eq!(plan.sleep_recommendation_seconds, 60 * 60);
+        assert_eq!(state.last_planned_watering.to_string(), "2024-05-01");
+    }
+
+    #[test]
+    fn exactly_nine_is_not_due_yet() {
+        let mut state = state_watered_on("2024-05-01");
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-02", "09:00:00"));
+        assert_eq!(amounts(&plan), vec![]);
+        assert_eq!(plan.sleep_recommendation_seconds, 0);
+    }
+
+    #[test]
+    fn after_nine_waters_once_per_day() {
+        let mut state = state_watered_on("2024-05-01");
+        let now = at("2024-05-02", "09:00:01");
+        let plan = plan_check_in(&mut state, &plants(), now);
+        assert_eq!(amounts(&plan), vec![(0, 100), (1, 500)]);
+        assert_eq!(state.last_planned_watering.to_string(), "2024-05-02");
+        // Until 09:00 of the next day
+        assert_eq!(plan.sleep_recommendation_seconds, 24 * 60 * 60 - 1);
+
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-02", "18:00:00"));
+        assert_eq!(amounts(&plan), vec![]);
+        assert_eq!(plan.sleep_recommendation_seconds, 15 * 60 * 60);
+    }
+
+    #[test]
+    fn missed_days_are_not_made_up() {
+        let mut state = state_watered_on("2024-05-01");
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-05", "12:00:00"));
+        assert_eq!(amounts(&plan), vec![(0, 100), (1, 500)]);
+        assert_eq!(state.last_planned_watering.to_string(), "2024-05-05");
+
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-05", "12:00:05"));
+        assert_eq!(amounts(&plan), vec![]);
+        assert_eq!(plan.sleep_recommendation_seconds, 21 * 60 * 60 - 5);
+    }
+
+    #[test]
+    fn missed_days_water_before_nine() {
+        // Overdue since yesterday 09:00, so it doesn't wait for today's
+        let mut state = state_watered_on("2024-05-01");
+        let plan = plan_check_in(&mut state, &plants(), at("2024-05-03", "07:00:00"));
+        assert_eq!(amounts(&plan), vec![(0, 100), (1, 500)]);
+        assert_eq!(state.last_planned_watering.to_string(), "2024-05-03");
+        assert_eq!(plan.sleep_recommendation_seconds, 26 * 60 * 60);
+    }
+}
@@ -0,0 +1,108 @@
+use log::{error, info, warn};
+use protocol::WateringJob;
+
+use crate::GlobalState;
+
+/// Resolves on Ctrl+C or SIGTERM, as sent by systemd.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Could not listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Answers the waiting frontend and stores the queued watering test,
+/// so the ESP32 still gets it after the restart. Call it once no new
+/// requests are accepted, later tests are refused.
+pub async fn persist_pending_watering_test(state: &GlobalState) {
+    let Some(task) = state.pending_watering_test.close().await else {
+        return;
+    };
+    let job = task.destruct_on_shutdown();
+    let plant_name = match state.config.get_plant_config() {
+        Ok(plants) => match plants.get(job.plant_index) {
+            Some(plant) => plant.name.clone(),
+            None => {
+                warn!(
+                    "Dropping queued watering test of unknown plant {}",
+                    job.plant_index
+                );
+                return;
+            }
+        },
+        Err(e) => {
+            error!(
+                "Dropping queued watering test, could not read config: {}",
+                e
+            );
+            return;
+        }
+    };
+    let result = state.json_state.update(|json_state| {
+        json_state.pending_watering_test = Some(plant_name.clone());
+    });
+    match result {
+        Ok(()) => info!("Stored queued watering test of {}", plant_name),
+        Err(e) => error!("Dropping queued watering test, could not store it: {}", e),
+    }
+}
+
+/// Queues a watering test stored during the last shutdown again.
+/// Nobody waits for it anymore, it is just handed to the ESP32.
+pub async fn restore_pending_watering_test(state: &GlobalState) {
+    let plant_name = state
+        .json_state
+        .update(|json_state| json_state.pending_watering_test.take());
+    let plant_name = match plant_name {
+        Ok(Some(plant_name)) => plant_name,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Could not restore a queued watering test: {}", e);
+            return;
+        }
+    };
+    let plants = state.config.get_plant_config().unwrap_or_default();
+    match plants
+        .iter()
+        .enumerate()
+        .find(|(_, p)| p.name == plant_name)
+    {
+        Some((plant_index, plant)) => {
+            let job = WateringJob {
+                plant_index,
+                amount_ml: plant.amount_ml,
+            };
+            let pending = state
+                .pending_watering_test
+                .set_pending_job(plant_name.clone(), job);
+            drop(pending.await);
+            info!("Restored queued watering test of {}", plant_name);
+        }
+        None => warn!(
+            "Dropping queued watering test, plant {} no longer exists",
+            plant_name
+        ),
+    }
+}
@@ -0,0 +1,166 @@
+use std::{io::ErrorKind, process::ExitCode};
+
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use serde::Serialize;
+
+use crate::{
+    cli::{OutputFormat, SimulateArgs},
+    config::{ConfigManager, PlantConfig},
+    schedule::{plan_check_in, CheckInPlan},
+    state::{JsonState, JsonStateManager, StateError},
+};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedJob {
+    pub plant_index: usize,
+    pub plant_name: String,
+    pub amount_ml: u32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulatedCheckIn {
+    pub time: NaiveDateTime,
+    pub watering_jobs: Vec<SimulatedJob>,
+    pub sleep_recommendation_seconds: u64,
+}
+
+/// Check-ins of a device which always sleeps as long as recommended.
+/// Also used for the planned waterings of the calendar feed.
+pub fn simulate_check_ins(
+    mut json_state: JsonState,
+    plants: &[PlantConfig],
+    start: NaiveDateTime,
+    days: u32,
+) -> Vec<SimulatedCheckIn> {
+    let end = start + ChronoDuration::days(days.into());
+    let mut now = start;
+    let mut check_ins = Vec::new();
+    while now < end {
+        let CheckInPlan {
+            watering_jobs,
+            sleep_recommendation_seconds,
+        } = plan_check_in(&mut json_state, plants, now);
+        check_ins.push(SimulatedCheckIn {
+            time: now,
+            watering_jobs: watering_jobs
+                .into_iter()
+                .map(|job| SimulatedJob {
+                    plant_index: job.plant_index,
+                    plant_name: plants[job.plant_index].name.clone(),
+                    amount_ml: job.amount_ml,
+                })
+                .collect(),
+            sleep_recommendation_seconds,
+        });
+        // A device told to sleep 0s checks in again right away, the next plan differs.
+        now += ChronoDuration::seconds(sleep_recommendation_seconds.max(1) as i64);
+    }
+    check_ins
+}
+
+fn format_duration(seconds: u64) -> String {
+    format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+fn print_table(check_ins: &[SimulatedCheckIn]) {
+    println!(
+        "{:<19}  {:<20}  {:>8}  {:>9}",
+        "Check-in", "Plant", "Amount", "Sleep"
+    );
+    for check_in in check_ins {
+        let time = check_in.time.format("%Y-%m-%d %H:%M:%S").to_string();
+        let sleep = format_duration(check_in.sleep_recommendation_seconds);
+        match check_in.watering_jobs.split_first() {
+            None => println!("{:<19}  {:<20}  {:>8}  {:>9}", time, "-", "-", sleep),
+            Some((first, rest)) => {
+                println!(
+                    "{:<19}  {:<20}  {:>8}  {:>9}",
+                    time,
+                    first.plant_name,
+                    format!("{}ml", first.amount_ml),
+                    sleep
+                );
+                for job in rest {
+                    println!(
+                        "{:<19}  {:<20}  {:>8}",
+                        "",
+                        job.plant_name,
+                        format!("{}ml", job.amount_ml)
+                    );
+                }
+            }
+        }
+    }
+}
+
+pub fn simulate(
+    configmanager: ConfigManager,
+    statemanager: JsonStateManager,
+    args: SimulateArgs,
+) -> ExitCode {
+    let plants = match configmanager.get_plant_config() {
+        Ok(plants) => plants,
+        Err(err) => {
+            eprintln!("Invalid config.\n{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    // Never written, the simulation must not change what the device gets next.
+    let json_state = match statemanager.get() {
+        Ok(json_state) => json_state,
+        Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => JsonState::default(),
+        Err(err) => {
+            eprintln!("Something is wrong with the state file.\n{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let start = args.start.unwrap_or_else(|| Local::now().naive_local());
+    let check_ins = simulate_check_ins(json_state, &plants, start, args.days);
+    match args.format {
+        OutputFormat::Table => print_table(&check_ins),
+        OutputFormat::Json => match serde_json::to_string_pretty(&check_ins) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                eprintln!("Could not serialize the simulation.\n{}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn waters_once_a_day_after_missed_days() {
+        let plants = vec![PlantConfig {
+            amount_ml: 100,
+            name: "Karsten".into(),
+        }];
+        let json_state = JsonState {
+            last_planned_watering: "2024-05-01".parse().unwrap(),
+            ..JsonState::default()
+        };
+        let start = "2024-05-04T08:00:00".parse().unwrap();
+        let check_ins: Vec<_> = simulate_check_ins(json_state, &plants, start, 3)
+            .into_iter()
+            .map(|c| (c.time.to_string(), c.watering_jobs.len()))
+            .collect();
+        assert_eq!(
+            check_ins,
+            vec![
+                ("2024-05-04 08:00:00".into(), 1),
+                // Told to sleep 0s at 09:00 sharp, waters on the retry
+                ("2024-05-05 09:00:00".into(), 0),
+                ("2024-05-05 09:00:01".into(), 1),
+                ("2024-05-06 09:00:00".into(), 0),
+                ("2024-05-06 09:00:01".into(), 1),
+            ]
+        );
+    }
+}
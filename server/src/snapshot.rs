@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use chrono::{Local, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{atomic_file, config::PlantConfig};
+
+pub const SNAPSHOTS_FILENAME: &str = "snapshots.json";
+const MAX_NAME_CHARS: usize = 64;
+
+/// A named copy of the `plants` section of the config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub name: String,
+    pub created: NaiveDateTime,
+    /// TOML with the comments of the config at the time, written back on apply.
+    pub plants_toml: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct PlantsSection {
+    #[serde(default)]
+    plants: Vec<PlantConfig>,
+}
+
+impl Snapshot {
+    pub fn plants(&self) -> Result<Vec<PlantConfig>, SnapshotError> {
+        Ok(toml_edit::de::from_str::<PlantsSection>(&self.plants_toml)?.plants)
+    }
+}
+
+/// TOML of a `plants` section, for restoring plants without a snapshot.
+pub fn plants_toml(plants: &[PlantConfig]) -> Result<String, SnapshotError> {
+    Ok(toml_edit::ser::to_string_pretty(&PlantsSection {
+        plants: plants.to_vec(),
+    })?)
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parsing error: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Plants of snapshot don't parse: {0}")]
+    Plants(#[from] toml_edit::de::Error),
+    #[error("Serializing plants failed: {0}")]
+    SerializePlants(#[from] toml_edit::ser::Error),
+    #[error("Invalid snapshot name {0:?}, use 1 to 64 characters without control characters")]
+    InvalidName(String),
+}
+
+/// Snapshots in `snapshots.json` of the state directory.
+#[derive(Clone)]
+pub struct SnapshotStore {
+    path: PathBuf,
+    mutex: Arc<Mutex<()>>,
+}
+
+impl SnapshotStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            path: state_dir.join(SNAPSHOTS_FILENAME),
+            mutex: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Held while a backup or restore handles snapshots.json.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read(&self) -> Result<Vec<Snapshot>, SnapshotError> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer)?;
+        Ok(serde_json::from_str(&buffer)?)
+    }
+
+    pub fn list(&self) -> Result<Vec<Snapshot>, SnapshotError> {
+        let _guard = self.mutex.lock();
+        self.read()
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Snapshot>, SnapshotError> {
+        Ok(self.list()?.into_iter().find(|s| s.name == name))
+    }
+
+    /// Saves the plants under `name`, replacing an older snapshot of that name.
+    pub fn save(&self, name: &str, plants_toml: String) -> Result<Snapshot, SnapshotError> {
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_CHARS
+            || name.chars().any(char::is_control)
+        {
+            return Err(SnapshotError::InvalidName(name.into()));
+        }
+        let snapshot = Snapshot {
+            name: name.into(),
+            created: Local::now().naive_local(),
+            plants_toml,
+        };
+        // Fail before saving something that can't be applied later
+        snapshot.plants()?;
+
+        let _guard = self.mutex.lock();
+        let mut snapshots = self.read()?;
+        match snapshots.iter_mut().find(|s| s.name == name) {
+            Some(existing) => *existing = snapshot.clone(),
+            None => snapshots.push(snapshot.clone()),
+        }
+        atomic_file::write(
+            &self.path,
+            serde_json::to_string_pretty(&snapshots)?.as_bytes(),
+        )?;
+        Ok(snapshot)
+    }
+}
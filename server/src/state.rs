@@ -1,14 +1,18 @@
 use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{File, OpenOptions},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
     io::{ErrorKind, Read, Write},
     net::{IpAddr, Ipv4Addr},
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
 };
 use thiserror::Error;
 
-const STATE_FILENAME: &str = "state.json";
+use crate::watch;
+
+pub const STATE_FILENAME: &str = "state.json";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JsonState {
@@ -16,10 +20,57 @@ pub struct JsonState {
     pub last_seen: chrono::NaiveDateTime,
     pub last_accu_percentage: f32,
     pub last_ip: IpAddr,
+    // Missing in state files written before devices were tracked
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceState>,
+    // Plant of a watering test queued during shutdown, run after the restart
+    #[serde(default)]
+    pub pending_watering_test: Option<String>,
+    // Estimated levels of configured reservoirs, by device ID
+    #[serde(default)]
+    pub reservoirs: HashMap<String, ReservoirState>,
+}
+
+/// What a device reported on its last check-in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceState {
+    pub last_seen: chrono::NaiveDateTime,
+    pub last_ip: IpAddr,
+    pub last_accu_percentage: f32,
+    pub firmware_version: Option<String>,
+    pub protocol_version: u32,
+}
+
+/// Estimated level of a reservoir, from the refills and the deliveries since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReservoirState {
+    pub level_ml: u32,
+    pub last_refill: Option<chrono::NaiveDateTime>,
+    // Set when the level dropped to the low level, so the alert is sent once
+    pub low_since: Option<chrono::NaiveDateTime>,
+}
+
+/// State of a server which has never seen a device.
+impl Default for JsonState {
+    fn default() -> Self {
+        Self {
+            last_seen: DateTime::from_timestamp(0, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+                .naive_utc(),
+            last_accu_percentage: 0.0,
+            last_planned_watering: NaiveDate::from_yo_opt(1970, 1).unwrap(),
+            last_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            devices: HashMap::new(),
+            pending_watering_test: None,
+            reservoirs: HashMap::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct JsonStateManager {
+    path: PathBuf,
     mutex: Arc<Mutex<()>>,
 }
 
@@ -32,35 +83,76 @@ pub enum StateError {
 }
 
 impl JsonStateManager {
-    pub fn new() -> Self {
+    pub fn new(state_dir: &Path) -> Self {
         Self {
+            path: state_dir.join(STATE_FILENAME),
             mutex: Arc::new(Mutex::new(())),
         }
     }
 
+    /// Held while a backup reads or a restore replaces state.json.
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn get(&self) -> Result<JsonState, StateError> {
         let _guard = self.mutex.lock();
-        let mut file = File::open(STATE_FILENAME)?;
+        self.read_file()
+    }
+
+    pub fn set(&self, state: JsonState) -> Result<(), StateError> {
+        let _guard = self.mutex.lock();
+        self.write_file(&state)
+    }
+
+    /// Reads, changes and writes the state under the lock, so a concurrent
+    /// change isn't overwritten. Starts from the default state if there is none.
+    /// `change` must not use the manager itself, it would deadlock.
+    pub fn update<T>(&self, change: impl FnOnce(&mut JsonState) -> T) -> Result<T, StateError> {
+        let _guard = self.lock();
+        let mut state = match self.read_file() {
+            Ok(state) => state,
+            Err(StateError::Io(e)) if e.kind() == ErrorKind::NotFound => JsonState::default(),
+            Err(e) => return Err(e),
+        };
+        let result = change(&mut state);
+        self.write_file(&state)?;
+        Ok(result)
+    }
+
+    /// Callers hold the mutex.
+    fn read_file(&self) -> Result<JsonState, StateError> {
+        let mut file = File::open(&self.path)?;
         let mut buffer = String::new();
         file.read_to_string(&mut buffer)?;
         Ok(serde_json::from_str(buffer.as_str())?)
     }
 
-    pub fn set(&self, state: JsonState) -> Result<(), StateError> {
-        let _guard = self.mutex.lock();
+    /// Callers hold the mutex.
+    fn write_file(&self, state: &JsonState) -> Result<(), StateError> {
         let mut file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(STATE_FILENAME)?;
-        let buf = serde_json::to_string(&state)?;
+            .open(&self.path)?;
+        let buf = serde_json::to_string(state)?;
         file.write_all(buf.as_bytes())?;
         file.sync_all()?;
-        drop(file);
-        drop(_guard);
         Ok(())
     }
 
+    /// Writes and deletes a file next to the state, which stays untouched.
+    pub fn check_writable(&self) -> std::io::Result<()> {
+        let probe = watch::parent_dir(&self.path).join(".state.json.probe");
+        let _guard = self.mutex.lock();
+        fs::write(&probe, b"probe")?;
+        fs::remove_file(&probe)
+    }
+
     pub fn ensure_state(&self) -> Result<JsonState, StateError> {
         let mut state = match self.get() {
             Ok(s) => Ok(Some(s)),
@@ -69,15 +161,7 @@ impl JsonStateManager {
         }?;
 
         if state.is_none() {
-            let default_state = JsonState {
-                last_seen: DateTime::from_timestamp(0, 0)
-                    .unwrap()
-                    .with_timezone(&Utc)
-                    .naive_utc(),
-                last_accu_percentage: 0.0,
-                last_planned_watering: NaiveDate::from_yo_opt(1970, 1).unwrap(),
-                last_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            };
+            let default_state = JsonState::default();
             self.set(default_state.clone())?;
             state = Some(default_state);
         }
@@ -0,0 +1,30 @@
+use axum_server::tls_rustls::RustlsConfig;
+use log::{info, warn};
+
+use crate::{config::TlsConfig, watch};
+
+/// Loads certificate and key, then reloads them whenever the files change,
+/// e.g. after a renewal. A broken renewal keeps the old certificate.
+pub async fn load_and_watch(tls: &TlsConfig) -> std::io::Result<RustlsConfig> {
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await?;
+
+    let (cert_path, key_path) = (tls.cert_path.clone(), tls.key_path.clone());
+    let reload_config = rustls_config.clone();
+    let watched = watch::on_file_change(&[cert_path.clone(), key_path.clone()], move || {
+        let rustls_config = reload_config.clone();
+        let (cert_path, key_path) = (cert_path.clone(), key_path.clone());
+        async move {
+            match rustls_config
+                .reload_from_pem_file(&cert_path, &key_path)
+                .await
+            {
+                Ok(()) => info!("Reloaded TLS certificate {}", cert_path.display()),
+                Err(e) => warn!("Keeping the old TLS certificate, reload failed: {}", e),
+            }
+        }
+    });
+    if let Err(e) = watched {
+        warn!("Certificate changes need a restart: {}", e);
+    }
+    Ok(rustls_config)
+}
@@ -0,0 +1,491 @@
+use std::{fmt::Display, fs::File, ops::Range, path::Path};
+
+use hyper::Uri;
+use thiserror::Error;
+use toml_edit::{ImDocument, Item, Table, TableLike};
+
+use crate::{
+    config::{read_secret_file, Config, EnvOverrides},
+    FRONTEND_ML_MAX,
+};
+
+/// Secrets shipped in evergreen.toml end with this.
+const PLACEHOLDER_SUFFIX: &str = "replace-me";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Invalid TOML: {0}")]
+    Syntax(String),
+    #[error("Invalid value: {0}")]
+    Schema(String),
+    #[error("No plants configured")]
+    NoPlants,
+    #[error("Plant name is empty")]
+    EmptyName,
+    #[error("Plant name {0:?} is used more than once, names must be unique")]
+    DuplicateName(String),
+    // The frontend puts the name into URLs without encoding it
+    #[error("Plant name {0:?} may only contain letters, digits, '-', '_', '.' and '~'")]
+    UrlUnsafeName(String),
+    #[error("{amount_ml}ml are more than the allowed {max}ml")]
+    AmountTooHigh { amount_ml: i64, max: usize },
+    #[error("api_secret is missing")]
+    MissingApiSecret,
+    #[error("api_prefix {0:?} must be empty or start with '/' and not end with '/'")]
+    InvalidApiPrefix(String),
+    #[error("Could not read {key} {path}: {reason}")]
+    UnreadableFile {
+        key: &'static str,
+        path: String,
+        reason: String,
+    },
+    #[error("{key} is still the placeholder {value:?}")]
+    PlaceholderSecret { key: &'static str, value: String },
+    #[error("Reservoir capacity must be more than 0ml")]
+    EmptyReservoir,
+    #[error("Low level of {low_level_ml}ml is above the capacity of {capacity_ml}ml")]
+    LowLevelAboveCapacity { low_level_ml: i64, capacity_ml: i64 },
+    // The URL isn't repeated, it usually carries a token
+    #[error("webhookUrl must be an http:// or https:// URL with a host")]
+    InvalidWebhookUrl,
+}
+
+/// A validation error and where it is in the config file, both 1-based.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub line: usize,
+    pub column: usize,
+    pub error: ValidationError,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.error)
+    }
+}
+
+impl Problem {
+    fn new(raw: &str, span: Option<Range<usize>>, error: ValidationError) -> Self {
+        let offset = span.map(|s| s.start).unwrap_or(0).min(raw.len());
+        let before = &raw[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .map(|l| l.chars().count())
+            .unwrap_or(0)
+            + 1;
+        Self {
+            line,
+            column,
+            error,
+        }
+    }
+}
+
+/// Checks the raw config for mistakes the parser accepts,
+/// returns an empty list if there are none.
+/// Relative secret files are looked up in `config_dir`.
+pub fn validate(raw: &str, config_dir: &Path, env: &EnvOverrides) -> Vec<Problem> {
+    let document = match ImDocument::parse(raw) {
+        Ok(document) => document,
+        Err(e) => {
+            return vec![Problem::new(
+                raw,
+                e.span(),
+                ValidationError::Syntax(e.message().to_string()),
+            )]
+        }
+    };
+    let root = document.as_table();
+    let mut problems = Vec::new();
+    validate_secrets(raw, root, config_dir, env, &mut problems);
+    validate_plants(raw, root, &mut problems);
+    validate_reservoirs(raw, root, &mut problems);
+    validate_tls(raw, root, config_dir, &mut problems);
+    validate_notifier(raw, root, &mut problems);
+    validate_frontend(raw, root, config_dir, &mut problems);
+    if let Err(e) = toml_edit::de::from_str::<Config>(raw) {
+        problems.push(Problem::new(
+            raw,
+            e.span(),
+            ValidationError::Schema(e.message().to_string()),
+        ));
+    }
+    problems.sort_by_key(|p| (p.line, p.column));
+    problems
+}
+
+fn validate_secrets(
+    raw: &str,
+    root: &Table,
+    config_dir: &Path,
+    env: &EnvOverrides,
+    problems: &mut Vec<Problem>,
+) {
+    // Same precedence as ConfigManager: environment, secret file, api_secret
+    let secret_file = root
+        .get("api_secret_file")
+        .or_else(|| root.get("apiSecretFile"));
+    match secret_file {
+        _ if env.has_api_secret() => {}
+        Some(item) => {
+            let file = item.as_str().unwrap_or_default();
+            match read_secret_file(&config_dir.join(file)) {
+                Ok(secret) if secret.is_empty() => problems.push(Problem::new(
+                    raw,
+                    item.span(),
+                    ValidationError::MissingApiSecret,
+                )),
+                Ok(_) => {}
+                Err(e) => problems.push(Problem::new(
+                    raw,
+                    item.span(),
+                    ValidationError::UnreadableFile {
+                        key: "api_secret_file",
+                        path: file.to_string(),
+                        reason: e.to_string(),
+                    },
+                )),
+            }
+        }
+        None => validate_inline_api_secret(raw, root, problems),
+    }
+
+    for key in ["admin_secret", "calendar_secret"] {
+        if let Some(item) = root.get(key) {
+            validate_not_placeholder(raw, key, item, problems);
+        }
+    }
+}
+
+fn validate_inline_api_secret(raw: &str, root: &Table, problems: &mut Vec<Problem>) {
+    match root.get("api_secret") {
+        Some(item) if item.as_str().is_some_and(|s| s.trim().is_empty()) => problems.push(
+            Problem::new(raw, item.span(), ValidationError::MissingApiSecret),
+        ),
+        None => problems.push(Problem::new(raw, None, ValidationError::MissingApiSecret)),
+        Some(item) => validate_not_placeholder(raw, "api_secret", item, problems),
+    }
+}
+
+fn validate_not_placeholder(
+    raw: &str,
+    key: &'static str,
+    item: &Item,
+    problems: &mut Vec<Problem>,
+) {
+    if let Some(secret) = item.as_str().filter(|s| s.ends_with(PLACEHOLDER_SUFFIX)) {
+        problems.push(Problem::new(
+            raw,
+            item.span(),
+            ValidationError::PlaceholderSecret {
+                key,
+                value: secret.to_string(),
+            },
+        ));
+    }
+}
+
+fn validate_plants(raw: &str, root: &Table, problems: &mut Vec<Problem>) {
+    let item = root.get("plants");
+    // [[plants]] tables or an inline array
+    let plants: Vec<&dyn TableLike> = match item {
+        Some(Item::ArrayOfTables(plants)) => plants.iter().map(|t| t as &dyn TableLike).collect(),
+        Some(Item::Value(value)) => value
+            .as_array()
+            .into_iter()
+            .flat_map(|a| a.iter())
+            .filter_map(|v| v.as_inline_table())
+            .map(|t| t as &dyn TableLike)
+            .collect(),
+        _ => Vec::new(),
+    };
+    if plants.is_empty() {
+        problems.push(Problem::new(
+            raw,
+            item.and_then(Item::span),
+            ValidationError::NoPlants,
+        ));
+        return;
+    }
+
+    let mut names: Vec<&str> = Vec::new();
+    for plant in plants {
+        if let Some(item) = plant.get("name") {
+            let name = item.as_str().unwrap_or_default();
+            let error = if name.is_empty() {
+                Some(ValidationError::EmptyName)
+            } else if names.contains(&name) {
+                Some(ValidationError::DuplicateName(name.to_string()))
+            } else if !name.chars().all(is_url_safe) {
+                Some(ValidationError::UrlUnsafeName(name.to_string()))
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                problems.push(Problem::new(raw, item.span(), error));
+            }
+            names.push(name);
+        }
+
+        if let Some(item) = plant.get("amountMl") {
+            let amount_ml = item.as_integer().unwrap_or_default();
+            if amount_ml > FRONTEND_ML_MAX as i64 {
+                problems.push(Problem::new(
+                    raw,
+                    item.span(),
+                    ValidationError::AmountTooHigh {
+                        amount_ml,
+                        max: FRONTEND_ML_MAX,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+fn validate_reservoirs(raw: &str, root: &Table, problems: &mut Vec<Problem>) {
+    let Some(Item::ArrayOfTables(devices)) = root.get("devices") else {
+        return;
+    };
+    let reservoirs = devices
+        .iter()
+        .filter_map(|d| d.get("reservoir").and_then(Item::as_table_like));
+    for reservoir in reservoirs {
+        let Some(capacity) = reservoir.get("capacityMl") else {
+            continue;
+        };
+        let capacity_ml = capacity.as_integer().unwrap_or_default();
+        if capacity_ml <= 0 {
+            problems.push(Problem::new(
+                raw,
+                capacity.span(),
+                ValidationError::EmptyReservoir,
+            ));
+            continue;
+        }
+        if let Some(low_level) = reservoir.get("lowLevelMl") {
+            let low_level_ml = low_level.as_integer().unwrap_or_default();
+            if low_level_ml > capacity_ml {
+                problems.push(Problem::new(
+                    raw,
+                    low_level.span(),
+                    ValidationError::LowLevelAboveCapacity {
+                        low_level_ml,
+                        capacity_ml,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+fn validate_tls(raw: &str, root: &Table, config_dir: &Path, problems: &mut Vec<Problem>) {
+    let Some(tls) = root.get("tls").and_then(Item::as_table_like) else {
+        return;
+    };
+    for key in ["certPath", "keyPath"] {
+        let Some(item) = tls.get(key) else {
+            continue;
+        };
+        let file = item.as_str().unwrap_or_default();
+        if let Err(e) = File::open(config_dir.join(file)) {
+            problems.push(Problem::new(
+                raw,
+                item.span(),
+                ValidationError::UnreadableFile {
+                    key,
+                    path: file.to_string(),
+                    reason: e.to_string(),
+                },
+            ));
+        }
+    }
+}
+
+fn validate_notifier(raw: &str, root: &Table, problems: &mut Vec<Problem>) {
+    let Some(item) = root
+        .get("notifier")
+        .and_then(Item::as_table_like)
+        .and_then(|notifier| notifier.get("webhookUrl"))
+    else {
+        return;
+    };
+    let valid = item
+        .as_str()
+        .and_then(|url| url.parse::<Uri>().ok())
+        .is_some_and(|uri| {
+            matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some()
+        });
+    if !valid {
+        problems.push(Problem::new(
+            raw,
+            item.span(),
+            ValidationError::InvalidWebhookUrl,
+        ));
+    }
+}
+
+fn validate_frontend(raw: &str, root: &Table, config_dir: &Path, problems: &mut Vec<Problem>) {
+    if let Some(item) = root.get("api_prefix") {
+        let prefix = item.as_str().unwrap_or_default();
+        let valid = prefix.is_empty()
+            || (prefix.starts_with('/')
+                && !prefix.ends_with('/')
+                && prefix.chars().all(|c| c == '/' || is_url_safe(c)));
+        if !valid {
+            problems.push(Problem::new(
+                raw,
+                item.span(),
+                ValidationError::InvalidApiPrefix(prefix.to_string()),
+            ));
+        }
+    }
+    if let Some(item) = root.get("frontend_dir") {
+        let dir = item.as_str().unwrap_or_default();
+        let index = Path::new(dir).join("index.html");
+        if let Err(e) = File::open(config_dir.join(&index)) {
+            problems.push(Problem::new(
+                raw,
+                item.span(),
+                ValidationError::UnreadableFile {
+                    key: "frontend_dir",
+                    path: index.display().to_string(),
+                    reason: e.to_string(),
+                },
+            ));
+        }
+    }
+}
+
+/// Unreserved characters of RFC 3986, which never need encoding.
+fn is_url_safe(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const VALID: &str = r#"api_secret = "secret"
+
+[[plants]]
+name = "Karsten"
+amountMl = 100
+
+[[plants]]
+name = "Bazil"
+amountMl = 500
+"#;
+
+    fn problems(raw: &str) -> Vec<(usize, usize, ValidationError)> {
+        validate(raw, Path::new("."), &EnvOverrides::default())
+            .into_iter()
+            .map(|p| (p.line, p.column, p.error))
+            .collect()
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        assert_eq!(problems(VALID), vec![]);
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let raw = VALID.replace("\"Bazil\"", "\"Karsten\"");
+        assert_eq!(
+            problems(&raw),
+            vec![(8, 8, ValidationError::DuplicateName("Karsten".into()))]
+        );
+    }
+
+    #[test]
+    fn url_unsafe_name() {
+        let raw = VALID.replace("\"Bazil\"", "\"Bazil/Basil\"");
+        assert_eq!(
+            problems(&raw),
+            vec![(8, 8, ValidationError::UrlUnsafeName("Bazil/Basil".into()))]
+        );
+        let raw = VALID.replace("\"Karsten\"", "\"\"");
+        assert_eq!(problems(&raw), vec![(4, 8, ValidationError::EmptyName)]);
+    }
+
+    #[test]
+    fn amount_bounds() {
+        let raw = VALID.replace("500", &FRONTEND_ML_MAX.to_string());
+        assert_eq!(problems(&raw), vec![]);
+
+        let raw = VALID.replace("500", &(FRONTEND_ML_MAX + 1).to_string());
+        assert_eq!(
+            problems(&raw),
+            vec![(
+                9,
+                12,
+                ValidationError::AmountTooHigh {
+                    amount_ml: FRONTEND_ML_MAX as i64 + 1,
+                    max: FRONTEND_ML_MAX
+                }
+            )]
+        );
+
+        // Negative amounts don't fit the u32 of PlantConfig
+        let raw = VALID.replace("100", "-100");
+        let problems = problems(&raw);
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(problems[0], (5, 12, ValidationError::Schema(_))));
+    }
+
+    #[test]
+    fn empty_plants() {
+        let raw = "api_secret = \"secret\"\nplants = []\n";
+        assert_eq!(problems(raw), vec![(2, 10, ValidationError::NoPlants)]);
+
+        // The schema error about the missing field comes second
+        let raw = "api_secret = \"secret\"\n";
+        let problems = problems(raw);
+        assert_eq!(problems[0], (1, 1, ValidationError::NoPlants));
+    }
+
+    #[test]
+    fn placeholder_api_secret() {
+        let raw = VALID.replace("\"secret\"", "\"esp32-secret-replace-me\"");
+        assert_eq!(
+            problems(&raw),
+            vec![(
+                1,
+                14,
+                ValidationError::PlaceholderSecret {
+                    key: "api_secret",
+                    value: "esp32-secret-replace-me".into()
+                }
+            )]
+        );
+
+        let raw = VALID.replace("api_secret = \"secret\"", "api_secret = \" \"");
+        assert_eq!(
+            problems(&raw),
+            vec![(1, 14, ValidationError::MissingApiSecret)]
+        );
+    }
+
+    #[test]
+    fn webhook_url() {
+        let with_url = |url: &str| format!("{}\n[notifier]\nwebhookUrl = \"{}\"\n", VALID, url);
+        assert_eq!(problems(&with_url("https://example.com/hooks/abc")), vec![]);
+        assert_eq!(problems(&with_url("http://192.168.1.5:8123/hook")), vec![]);
+        for url in [
+            "example.com/hooks/abc",
+            "ftp://example.com/",
+            "https://",
+            "",
+        ] {
+            assert_eq!(
+                problems(&with_url(url)),
+                vec![(12, 14, ValidationError::InvalidWebhookUrl)],
+                "{:?}",
+                url
+            );
+        }
+    }
+}
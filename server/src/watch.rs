@@ -0,0 +1,61 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::warn;
+use notify::{RecursiveMode, Watcher};
+
+/// Runs `on_change` whenever one of the files changed.
+pub fn on_file_change<F, Fut>(files: &[PathBuf], on_change: F) -> notify::Result<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let names: HashSet<OsString> = files
+        .iter()
+        .filter_map(|f| f.file_name().map(|n| n.to_owned()))
+        .collect();
+    let mut watcher =
+        notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) if event.kind.is_access() => {}
+            Ok(event) => {
+                let watched = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name().is_some_and(|n| names.contains(n)));
+                if watched {
+                    let _ = tx.send(());
+                }
+            }
+            Err(e) => warn!("Error watching files: {}", e),
+        })?;
+    // Editors and certbot replace files instead of writing them, so watch the directories.
+    let dirs: HashSet<&Path> = files.iter().map(|f| parent_dir(f)).collect();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // A save consists of several events, handle them at once.
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            while rx.try_recv().is_ok() {}
+            on_change().await;
+        }
+    });
+    Ok(())
+}
+
+/// Directory of a file, `.` for bare file names.
+pub fn parent_dir(file: &Path) -> &Path {
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
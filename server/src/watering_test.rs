@@ -1,50 +1,134 @@
 use std::sync::Arc;
 
+use log::info;
 use tokio::sync::{
+    broadcast::error::RecvError,
     oneshot::{channel, Receiver, Sender},
     Mutex,
 };
 
-use crate::model::WateringJob;
+use protocol::WateringJob;
+
+use crate::config::{ConfigEvent, ConfigManager};
+
+/// What happened to a task, a dropped task means it was replaced or cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    Done,
+    ShuttingDown,
+    /// The server was already shutting down, the task wasn't queued.
+    Refused,
+}
 
 pub struct Task<T> {
     value: T,
-    ack: Sender<()>,
+    ack: Sender<Ack>,
 }
 
 impl<T> Task<T> {
-    pub fn new(value: T) -> (Self, Receiver<()>) {
+    pub fn new(value: T) -> (Self, Receiver<Ack>) {
         let (ack, response) = channel();
         (Self { value, ack }, response)
     }
 
     pub fn destruct_and_ack(self) -> T {
-        let _ = self.ack.send(());
+        let _ = self.ack.send(Ack::Done);
+        self.value
+    }
+
+    /// Tells the waiter the server stops before the task was picked up.
+    pub fn destruct_on_shutdown(self) -> T {
+        let _ = self.ack.send(Ack::ShuttingDown);
         self.value
     }
 }
 
+struct PendingJob {
+    // The job refers to the plant by index, which a new config may change
+    plant: String,
+    task: Task<WateringJob>,
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: Option<PendingJob>,
+    // Set on shutdown, after the pending job was stored
+    closed: bool,
+}
+
 #[derive(Clone)]
 pub struct PendingWateringTest {
-    inner: Arc<Mutex<Option<Task<WateringJob>>>>,
+    inner: Arc<Mutex<Inner>>,
 }
 
 impl PendingWateringTest {
     pub fn new() -> Self {
         Self {
-            inner: Arc::new(Mutex::new(None)),
+            inner: Arc::new(Mutex::new(Inner::default())),
         }
     }
 
-    pub async fn set_pending_job(&self, plant_id: WateringJob) -> Receiver<()> {
-        let (task, response) = Task::new(plant_id);
+    pub async fn set_pending_job(&self, plant: String, job: WateringJob) -> Receiver<Ack> {
+        let (task, response) = Task::new(job);
         let mut inner = self.inner.lock().await;
-        let _ = inner.insert(task);
+        match inner.closed {
+            true => {
+                let _ = task.ack.send(Ack::Refused);
+            }
+            false => inner.pending = Some(PendingJob { plant, task }),
+        }
         response
     }
 
     pub async fn pop_pending_task(&self) -> Option<Task<WateringJob>> {
         let mut inner = self.inner.lock().await;
-        inner.take()
+        inner.pending.take().map(|pending| pending.task)
+    }
+
+    /// Takes the pending task for good, later ones are refused since
+    /// nothing would store them anymore.
+    pub async fn close(&self) -> Option<Task<WateringJob>> {
+        let mut inner = self.inner.lock().await;
+        inner.closed = true;
+        inner.pending.take().map(|pending| pending.task)
+    }
+
+    /// Cancels the pending job if its index no longer points to its plant,
+    /// so the ESP32 doesn't water another one. Other changes keep it.
+    pub async fn cancel_if_plant_moved(&self, config: &ConfigManager) {
+        let mut inner = self.inner.lock().await;
+        let Some(pending) = inner.pending.as_ref() else {
+            return;
+        };
+        let moved = match config.get_plant_config() {
+            Ok(plants) => {
+                plants.get(pending.task.value.plant_index).map(|p| &p.name) != Some(&pending.plant)
+            }
+            Err(_) => true,
+        };
+        if moved {
+            info!(
+                "Plant {} moved or was removed, cancelled pending watering test",
+                pending.plant
+            );
+            inner.pending = None;
+        }
+    }
+
+    /// Checks the pending job against every new config, including the server's own changes.
+    pub fn cancel_on_config_reload(&self, config: ConfigManager) {
+        let pending = self.clone();
+        let mut events = config.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(ConfigEvent::Reloaded) | Err(RecvError::Lagged(_)) => {
+                        pending.cancel_if_plant_moved(&config).await;
+                    }
+                    Ok(ConfigEvent::Rejected(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
     }
 }